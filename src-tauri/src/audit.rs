@@ -0,0 +1,235 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::encryption::{constant_time_eq, get_config_dir, ORG_SECRET};
+use crate::lock::FileLock;
+use crate::machine::{cached_mac, get_raw_hostname};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const AUDIT_LOG_FILE: &str = "audit.log";
+// Entry count and last HMAC, so dropping entries from the end is detectable
+const AUDIT_HEAD_FILE: &str = "audit.head";
+// The log is moved here once it passes MAX_AUDIT_LOG_SIZE, replacing the
+// previous one, and a new log continues the chain
+const AUDIT_ROTATED_FILE: &str = "audit.log.1";
+const MAX_AUDIT_LOG_SIZE: u64 = 1024 * 1024;
+const AUDIT_KEY_LABEL: &[u8] = b"btic-config-audit-v1";
+
+// One line of the audit log. Each hmac covers the entry and the previous
+// entry's hmac, so editing or removing any line breaks the chain after it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    timestamp: String,
+    user: String,
+    action: String,
+    file_path: String,
+    content_sha256: String,
+    // The machine that wrote the file; entries from before these were
+    // recorded have neither
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    mac: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    host: String,
+    hmac: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditVerification {
+    valid: bool,
+    entries: usize,
+    message: String,
+}
+
+// Function to append a record of a config write to the audit log
+pub fn append_audit_record(action: &str, file_path: &str, content: &[u8]) -> Result<(), String> {
+    let log_path = audit_path(AUDIT_LOG_FILE);
+    let _lock = FileLock::acquire(&log_path)?;
+
+    let (mut count, prev_hmac, mut base_hmac) = read_head()?;
+    let log_size = fs::metadata(&log_path).map(|m| m.len()).unwrap_or(0);
+    if log_size > MAX_AUDIT_LOG_SIZE {
+        fs::rename(&log_path, audit_path(AUDIT_ROTATED_FILE))
+            .map_err(|e| format!("Failed to rotate audit log: {}", e))?;
+        println!("Rotated audit log after {} entries", count);
+        count = 0;
+        base_hmac = prev_hmac.clone();
+    }
+
+    let mut entry = AuditEntry {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        user: current_username(),
+        action: action.to_string(),
+        file_path: file_path.to_string(),
+        content_sha256: hex::encode(Sha256::digest(content)),
+        // Only the MAC detection already found, an audit record isn't worth
+        // enumerating adapters for
+        mac: cached_mac().unwrap_or_default(),
+        host: get_raw_hostname(),
+        hmac: String::new(),
+    };
+    entry.hmac = entry_hmac(&prev_hmac, &entry);
+
+    let line = serde_json::to_string(&entry)
+        .map_err(|e| format!("Failed to serialize audit entry: {}", e))?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .map_err(|e| format!("Failed to open audit log: {}", e))?;
+    writeln!(file, "{}", line).map_err(|e| format!("Failed to write audit log: {}", e))?;
+
+    write_head(count + 1, &entry.hmac, &base_hmac)
+}
+
+// Command to read every entry of the audit log
+#[tauri::command]
+pub fn get_audit_log() -> Result<Vec<AuditEntry>, String> {
+    read_entries()
+}
+
+// Command to check the audit log for edits, reordering and truncation. After
+// a rotation the chain starts from the last entry of the rotated log.
+#[tauri::command]
+pub fn verify_audit_log() -> Result<AuditVerification, String> {
+    let log_path = audit_path(AUDIT_LOG_FILE);
+    let _lock = FileLock::acquire(&log_path)?;
+
+    let entries = read_entries()?;
+    let (count, head_hmac, base_hmac) = read_head()?;
+    let mut prev_hmac = base_hmac;
+    for (i, entry) in entries.iter().enumerate() {
+        let expected = entry_hmac(&prev_hmac, entry);
+        if !constant_time_eq(expected.as_bytes(), entry.hmac.as_bytes()) {
+            return Ok(AuditVerification {
+                valid: false,
+                entries: entries.len(),
+                message: format!("Audit log entry {} was modified or reordered", i + 1),
+            });
+        }
+        prev_hmac = entry.hmac.clone();
+    }
+
+    if count != entries.len() || head_hmac != prev_hmac {
+        return Ok(AuditVerification {
+            valid: false,
+            entries: entries.len(),
+            message: format!(
+                "Audit log has {} entries but {} were recorded: it was truncated or extended",
+                entries.len(),
+                count
+            ),
+        });
+    }
+
+    Ok(AuditVerification {
+        valid: true,
+        entries: entries.len(),
+        message: "Audit log is intact".to_string(),
+    })
+}
+
+// Function to compute the chained HMAC of an entry
+fn entry_hmac(prev_hmac: &str, entry: &AuditEntry) -> String {
+    let mut mac = HmacSha256::new_from_slice(&audit_key()).expect("HMAC accepts any key length");
+    for field in [
+        prev_hmac,
+        entry.timestamp.as_str(),
+        entry.user.as_str(),
+        entry.action.as_str(),
+        entry.file_path.as_str(),
+        entry.content_sha256.as_str(),
+    ] {
+        // Length-prefix each field so values can't be shifted between fields
+        mac.update(&(field.len() as u32).to_le_bytes());
+        mac.update(field.as_bytes());
+    }
+    // Covered only when present, so entries written before keep verifying
+    if !entry.mac.is_empty() || !entry.host.is_empty() {
+        for field in [entry.mac.as_str(), entry.host.as_str()] {
+            mac.update(&(field.len() as u32).to_le_bytes());
+            mac.update(field.as_bytes());
+        }
+    }
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// The HMAC key is only as secret as its inputs: with the organization secret
+// the chain also resists deliberate edits, without it it still catches
+// accidental corruption and naive tampering
+fn audit_key() -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(AUDIT_KEY_LABEL);
+    hasher.update(ORG_SECRET.unwrap_or("").as_bytes());
+    // The raw name, as before hostnames were normalized, so existing logs
+    // keep verifying
+    hasher.update(get_raw_hostname().as_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn read_entries() -> Result<Vec<AuditEntry>, String> {
+    let log_path = audit_path(AUDIT_LOG_FILE);
+    if !log_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents =
+        fs::read_to_string(&log_path).map_err(|e| format!("Failed to read audit log: {}", e))?;
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| format!("Audit log entry {} is corrupt: {}", i + 1, e))
+        })
+        .collect()
+}
+
+// Function to read the entry count, last HMAC and the HMAC the current log
+// continues from (empty until the first rotation)
+fn read_head() -> Result<(usize, String, String), String> {
+    let head_path = audit_path(AUDIT_HEAD_FILE);
+    if !head_path.exists() {
+        return Ok((0, String::new(), String::new()));
+    }
+
+    let contents =
+        fs::read_to_string(&head_path).map_err(|e| format!("Failed to read audit head: {}", e))?;
+    let mut parts = contents.trim().split(';');
+    let (Some(count), Some(hmac)) = (parts.next(), parts.next()) else {
+        return Err("Audit head is corrupt".to_string());
+    };
+    let count = count
+        .parse::<usize>()
+        .map_err(|_| "Audit head is corrupt".to_string())?;
+    let base_hmac = parts.next().unwrap_or("");
+    Ok((count, hmac.to_string(), base_hmac.to_string()))
+}
+
+fn write_head(count: usize, hmac: &str, base_hmac: &str) -> Result<(), String> {
+    let head = if base_hmac.is_empty() {
+        format!("{};{}", count, hmac)
+    } else {
+        format!("{};{};{}", count, hmac, base_hmac)
+    };
+    fs::write(audit_path(AUDIT_HEAD_FILE), head)
+        .map_err(|e| format!("Failed to write audit head: {}", e))
+}
+
+fn audit_path(file_name: &str) -> PathBuf {
+    let mut path = get_config_dir();
+    path.push(file_name);
+    path
+}
+
+// Function to get the Windows user running the configurator
+fn current_username() -> String {
+    std::env::var("USERNAME")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
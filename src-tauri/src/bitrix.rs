@@ -0,0 +1,170 @@
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::config::parse_json;
+
+// A webhook that hasn't answered by now won't work for the connector either
+const BITRIX_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BitrixConnectionResult {
+    success: bool,
+    // HTTP status of the response, when one was received
+    status: Option<u16>,
+    // Name of the user the webhook acts as
+    user_name: Option<String>,
+    error: Option<String>,
+}
+
+// Error body of the Bitrix REST API
+#[derive(Debug, Deserialize)]
+struct BitrixError {
+    error: String,
+    error_description: Option<String>,
+}
+
+// Command to check the Bitrix24 webhook of a config before it is encrypted,
+// by calling the lightweight "profile" method with it. Nothing is encrypted
+// or written; this is the configurator's only call to the Bitrix tenant.
+#[tauri::command]
+pub async fn test_bitrix_connection(config_json: String) -> Result<BitrixConnectionResult, String> {
+    let config = parse_json(&config_json)?;
+    let webhook_url = match webhook_url(&config) {
+        Ok(url) => url,
+        Err(e) => return Ok(failure(None, e)),
+    };
+
+    let client = Client::builder()
+        .timeout(BITRIX_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+
+    // The webhook URL carries its token, so only the host is logged
+    println!(
+        "Testing Bitrix24 webhook on {}",
+        reqwest::Url::parse(&webhook_url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_string()))
+            .unwrap_or_default()
+    );
+
+    let response = match client
+        .get(format!("{}profile.json", webhook_url))
+        .send()
+        .await
+    {
+        Ok(resp) => resp,
+        Err(e) if e.is_timeout() => {
+            return Ok(failure(None, "Bitrix24 didn't answer in time".to_string()))
+        }
+        Err(e) => {
+            return Ok(failure(
+                None,
+                format!("Connection error: {}", e.without_url()),
+            ))
+        }
+    };
+
+    let status = response.status().as_u16();
+    let body: Value = response.json().await.unwrap_or(Value::Null);
+
+    // The API reports bad tokens and missing scopes in the body
+    if let Ok(api_error) = serde_json::from_value::<BitrixError>(body.clone()) {
+        let error_message = match api_error.error.as_str() {
+            "INVALID_CREDENTIALS" | "invalid_token" => {
+                "Webhook token is not valid or was revoked".to_string()
+            }
+            "expired_token" => "Webhook token expired".to_string(),
+            "insufficient_scope" => "Webhook lacks the permission to read the profile".to_string(),
+            "NO_AUTH_FOUND" => "Webhook URL is not a valid webhook".to_string(),
+            other => format!(
+                "Bitrix24 error {}: {}",
+                other,
+                api_error.error_description.unwrap_or_default()
+            ),
+        };
+        return Ok(failure(Some(status), error_message));
+    }
+
+    if !(200..300).contains(&status) {
+        let error_message = match status {
+            401 => "Webhook token is not valid".to_string(),
+            403 => "Access to the Bitrix24 tenant is forbidden".to_string(),
+            404 => "Webhook URL not found, check the tenant address".to_string(),
+            500..=599 => "Bitrix24 server error. Please try again later".to_string(),
+            _ => format!("Bitrix24 answered with status: {}", status),
+        };
+        return Ok(failure(Some(status), error_message));
+    }
+
+    let Some(profile) = body.get("result") else {
+        return Ok(failure(
+            Some(status),
+            "Response is not from the Bitrix24 REST API".to_string(),
+        ));
+    };
+    let user_name = [profile.get("NAME"), profile.get("LAST_NAME")]
+        .into_iter()
+        .flatten()
+        .filter_map(|part| part.as_str())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ");
+
+    Ok(BitrixConnectionResult {
+        success: true,
+        status: Some(status),
+        user_name: (!user_name.is_empty()).then_some(user_name),
+        error: None,
+    })
+}
+
+// Function to get the webhook URL ("https://<tenant>/rest/<user>/<token>/")
+// from the Bitrix24 section of a config, ending in a slash
+fn webhook_url(config: &Value) -> Result<String, String> {
+    let url = config
+        .pointer("/Bitrix24/API_Tenant")
+        .and_then(|url| url.as_str())
+        .map(|url| url.trim())
+        .filter(|url| !url.is_empty())
+        .ok_or_else(|| "Config has no Bitrix24 API_Tenant webhook URL".to_string())?;
+
+    let parsed =
+        reqwest::Url::parse(url).map_err(|_| "API_Tenant is not a valid URL".to_string())?;
+    if parsed.scheme() != "https" {
+        return Err("API_Tenant must be an https:// URL".to_string());
+    }
+    let segments: Vec<String> = parsed
+        .path_segments()
+        .map(|segments| {
+            segments
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    if segments.len() < 3 || segments[0] != "rest" {
+        return Err(
+            "API_Tenant must be a webhook URL of the form https://<tenant>/rest/<user>/<token>/"
+                .to_string(),
+        );
+    }
+
+    // Anything after the token (a method name, a query) is dropped
+    let mut base = parsed;
+    base.set_path(&format!("/rest/{}/{}/", segments[1], segments[2]));
+    base.set_query(None);
+    base.set_fragment(None);
+    Ok(base.to_string())
+}
+
+fn failure(status: Option<u16>, error: String) -> BitrixConnectionResult {
+    BitrixConnectionResult {
+        success: false,
+        status,
+        user_name: None,
+        error: Some(error),
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{Read, Write};
+
+use crate::encryption::{decrypt_blob, encrypt_to_blob, BlobOptions};
+
+const USAGE: &str = "\
+usage: sage-bitrix-configurador encrypt|decrypt [options] < input > output
+
+  encrypt                   read plaintext JSON from stdin, write the encrypted file to stdout
+  decrypt                   read an encrypted file from stdin, write its JSON to stdout
+
+options:
+  --key-char <c>            key char, as char_key
+  --binding <mode>          machine, passphrase or both, as binding_mode
+  --binding-source <src>    mac, machine_guid, machine_id or mac_machine_guid
+  --passphrase-env <name>   read the passphrase from this environment variable
+  --no-canonicalize         encrypt the JSON exactly as given";
+
+// Function to run the command-line interface when the first argument is one
+// of its subcommands, returning the exit code. Returns None for any other
+// arguments so the app starts as usual.
+pub fn run(args: Vec<String>) -> Option<i32> {
+    let encrypt = match args.first().map(String::as_str) {
+        Some("encrypt") => true,
+        Some("decrypt") => false,
+        _ => return None,
+    };
+
+    let result = parse_options(&args[1..]).and_then(|options| {
+        // The library logs with println!, which would corrupt the output
+        let mut output = take_stdout()?;
+        let data = read_stdin()?;
+        let result = if encrypt {
+            let json_data = String::from_utf8(data)
+                .map_err(|_| "Input is not UTF-8 text".to_string())?
+                .trim_start_matches('\u{feff}')
+                .to_string();
+            tauri::async_runtime::block_on(encrypt_to_blob(json_data, options))?
+        } else {
+            decrypt_blob(&data, options.char_key, options.passphrase)?.into_bytes()
+        };
+        output
+            .write_all(&result)
+            .and_then(|_| output.flush())
+            .map_err(|e| format!("Failed to write output: {}", e))
+    });
+
+    match result {
+        Ok(()) => Some(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            Some(1)
+        }
+    }
+}
+
+fn parse_options(args: &[String]) -> Result<BlobOptions, String> {
+    let mut options = BlobOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--key-char" => options.char_key = Some(value()?),
+            "--binding" => options.binding_mode = Some(value()?),
+            "--binding-source" => options.binding_source = Some(value()?),
+            // Not a plain flag, so the passphrase doesn't show in the process list
+            "--passphrase-env" => {
+                let name = value()?;
+                let passphrase = std::env::var(&name)
+                    .map_err(|_| format!("Environment variable {} is not set", name))?;
+                options.passphrase = Some(passphrase);
+            }
+            "--no-canonicalize" => options.canonicalize = Some(false),
+            other => return Err(format!("Unknown option: {}\n\n{}", other, USAGE)),
+        }
+    }
+    Ok(options)
+}
+
+fn read_stdin() -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Failed to read stdin: {}", e))?;
+    Ok(data)
+}
+
+// Function to keep the real stdout for the output and point the standard
+// output descriptor at stderr, where the library's logging then goes
+#[cfg(unix)]
+fn take_stdout() -> Result<File, String> {
+    use std::os::fd::AsFd;
+
+    let _ = std::io::stdout().flush();
+    let saved = std::io::stdout()
+        .as_fd()
+        .try_clone_to_owned()
+        .map_err(|e| format!("Failed to duplicate stdout: {}", e))?;
+    // SAFETY: dup2 only replaces this process's own standard output descriptor
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } == -1 {
+        return Err(format!(
+            "Failed to redirect logging to stderr: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(File::from(saved))
+}
+
+// On Windows Rust looks the standard handles up on every write, so swapping
+// the handle is enough. Release builds use the GUI subsystem and only have
+// a stdout when it is redirected to a file or pipe.
+#[cfg(windows)]
+fn take_stdout() -> Result<File, String> {
+    use std::os::windows::io::FromRawHandle;
+    use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+    use windows_sys::Win32::System::Console::{
+        GetStdHandle, SetStdHandle, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE,
+    };
+
+    let _ = std::io::stdout().flush();
+    // SAFETY: the handles are this process's standard handles; stdout is
+    // owned by the returned File from here on
+    unsafe {
+        let stdout = GetStdHandle(STD_OUTPUT_HANDLE);
+        if stdout == 0 || stdout == INVALID_HANDLE_VALUE {
+            return Err("No standard output: redirect it to a file or pipe".to_string());
+        }
+        SetStdHandle(STD_OUTPUT_HANDLE, GetStdHandle(STD_ERROR_HANDLE));
+        Ok(File::from_raw_handle(stdout as _))
+    }
+}
@@ -0,0 +1,64 @@
+use std::io::Read;
+use std::process::{Command, Output, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How long machine detection waits for an external tool. ipconfig and
+// PowerShell take well under a second; anything longer is stuck.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Function to run an external tool and collect its output, killing it if it
+// hasn't exited within the timeout. A missing binary (containers, trimmed
+// installs) is an ordinary error for the caller to fall back from.
+pub fn run_command(program: &str, args: &[&str], timeout: Duration) -> Result<Output, String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+
+    // Drain both pipes while waiting, a tool blocked on a full pipe never exits
+    let stdout = child.stdout.take().map(read_pipe);
+    let stderr = child.stderr.take().map(read_pipe);
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() < timeout => thread::sleep(POLL_INTERVAL),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "{} did not finish within {} seconds",
+                    program,
+                    timeout.as_secs()
+                ));
+            }
+            Err(e) => return Err(format!("Failed to wait for {}: {}", program, e)),
+        }
+    };
+
+    Ok(Output {
+        status,
+        stdout: join_pipe(stdout),
+        stderr: join_pipe(stderr),
+    })
+}
+
+fn read_pipe<R: Read + Send + 'static>(mut pipe: R) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = pipe.read_to_end(&mut buffer);
+        buffer
+    })
+}
+
+fn join_pipe(handle: Option<JoinHandle<Vec<u8>>>) -> Vec<u8> {
+    handle
+        .and_then(|handle| handle.join().ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,255 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// Configuration read by the ConnectorSageBitrix service. Field names match the
+// JSON the Dashboard builds before calling encrypt_json.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConnectorConfig {
+    #[serde(rename = "CodigoCliente")]
+    pub codigo_cliente: String,
+    #[serde(rename = "DB")]
+    pub db: DatabaseConfig,
+    #[serde(rename = "Bitrix24", skip_serializing_if = "Option::is_none")]
+    pub bitrix24: Option<Bitrix24Config>,
+    #[serde(rename = "Empresas")]
+    pub empresas: Vec<CompanyMapping>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DatabaseConfig {
+    #[serde(rename = "DB_Host")]
+    pub host: String,
+    #[serde(rename = "DB_Host_Sage")]
+    pub host_sage: String,
+    #[serde(rename = "DB_Port")]
+    pub port: String,
+    #[serde(rename = "DB_Database")]
+    pub database: String,
+    #[serde(rename = "DB_Username")]
+    pub username: String,
+    #[serde(rename = "DB_Password")]
+    pub password: String,
+    #[serde(rename = "IdLlicencia")]
+    pub license: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bitrix24Config {
+    #[serde(rename = "API_Tenant")]
+    pub api_tenant: String,
+    pub pack_empresa: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompanyMapping {
+    #[serde(rename = "EmpresaBitrix")]
+    pub empresa_bitrix: String,
+    #[serde(rename = "EmpresaSage")]
+    pub empresa_sage: String,
+}
+
+// The default config is the template shown to new operators: every field is
+// present and placeholders describe what goes in it
+impl Default for ConnectorConfig {
+    fn default() -> Self {
+        ConnectorConfig {
+            codigo_cliente: "<client code>".to_string(),
+            db: DatabaseConfig {
+                host: "<SQL Server host\\instance>".to_string(),
+                host_sage: "<Sage server host>".to_string(),
+                port: "1433".to_string(),
+                database: "<Sage database name>".to_string(),
+                username: "<database user>".to_string(),
+                password: "<database password>".to_string(),
+                license: "<license id>".to_string(),
+            },
+            bitrix24: Some(Bitrix24Config {
+                api_tenant: "<https://your-tenant.bitrix24.es/rest/...>".to_string(),
+                pack_empresa: false,
+            }),
+            empresas: vec![CompanyMapping {
+                empresa_bitrix: "<Bitrix company id>".to_string(),
+                empresa_sage: "<Sage company code>".to_string(),
+            }],
+        }
+    }
+}
+
+// Command to get a pretty-printed JSON skeleton of the connector config
+#[tauri::command]
+pub fn generate_config_template() -> Result<String, String> {
+    serde_json::to_string_pretty(&ConnectorConfig::default())
+        .map_err(|e| format!("Failed to serialize config template: {}", e))
+}
+
+// Shown in place of a masked value
+const MASKED_VALUE: &str = "***";
+
+// Key-name patterns masked when the caller gives none. API_Tenant is the
+// Bitrix webhook URL, which embeds the webhook token.
+const DEFAULT_MASK_PATTERNS: &[&str] = &["password", "token", "secret", "api_tenant"];
+
+// Function to replace secret values with *** for display. Rules starting with
+// '/' are JSON pointers, any other rule is a case-insensitive substring of a
+// key name; nulls are left alone so a missing value still reads as missing.
+pub fn mask_secrets(json_data: &str, rules: Option<&[String]>) -> Result<String, String> {
+    let mut value = parse_json(json_data)?;
+    let rules: Vec<String> = match rules {
+        Some(rules) => rules.to_vec(),
+        None => DEFAULT_MASK_PATTERNS
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+    };
+    let (pointers, patterns): (Vec<String>, Vec<String>) =
+        rules.into_iter().partition(|rule| rule.starts_with('/'));
+
+    for pointer in &pointers {
+        if let Some(target) = value.pointer_mut(pointer) {
+            mask_value(target);
+        }
+    }
+    let patterns: Vec<String> = patterns.iter().map(|p| p.to_lowercase()).collect();
+    mask_keys(&mut value, &patterns);
+
+    serde_json::to_string(&value).map_err(|e| format!("Failed to serialize JSON: {}", e))
+}
+
+fn mask_keys(value: &mut Value, patterns: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                let key_lower = key.to_lowercase();
+                if patterns.iter().any(|pattern| key_lower.contains(pattern)) {
+                    mask_value(child);
+                } else {
+                    mask_keys(child, patterns);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| mask_keys(item, patterns)),
+        _ => {}
+    }
+}
+
+fn mask_value(value: &mut Value) {
+    if !value.is_null() {
+        *value = Value::String(MASKED_VALUE.to_string());
+    }
+}
+
+// Function to parse config JSON, reporting where it is malformed
+pub fn parse_json(json_data: &str) -> Result<Value, String> {
+    // serde_json errors already end with "at line X column Y"
+    serde_json::from_str(json_data).map_err(|e| format!("Invalid JSON: {}", e))
+}
+
+// Function to check config JSON has the shape the connector service reads:
+// every required section and field present with the right type
+pub fn validate_connector_config(json_data: &str) -> Result<(), String> {
+    serde_json::from_str::<ConnectorConfig>(json_data)
+        .map(|_| ())
+        .map_err(|e| format!("Config doesn't match the connector schema: {}", e))
+}
+
+// Function to re-serialize JSON in canonical form: object keys sorted at every
+// level and no insignificant whitespace, so equal configs give equal bytes
+pub fn canonicalize_json(json_data: &str) -> Result<String, String> {
+    let value = sort_keys(parse_json(json_data)?);
+    serde_json::to_string(&value).map_err(|e| format!("Failed to serialize JSON: {}", e))
+}
+
+// Sorting is done explicitly rather than relying on serde_json's map type,
+// which keeps insertion order when its preserve_order feature is enabled
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, sort_keys(value)))
+                    .collect::<Map<String, Value>>(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        other => other,
+    }
+}
+
+// One difference between two JSON documents, located by JSON pointer
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonDiffEntry {
+    op: String,
+    pointer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new: Option<Value>,
+}
+
+// Function to list added, removed and changed values between two documents.
+// Objects are compared key by key and arrays index by index; anything else
+// that differs is reported as changed at its pointer.
+pub fn diff_json(old: &Value, new: &Value) -> Vec<JsonDiffEntry> {
+    let mut entries = Vec::new();
+    diff_value("", old, new, &mut entries);
+    entries
+}
+
+fn diff_value(pointer: &str, old: &Value, new: &Value, entries: &mut Vec<JsonDiffEntry>) {
+    match (old, new) {
+        (Value::Object(old_map), Value::Object(new_map)) => {
+            let mut keys: Vec<&String> = old_map.keys().chain(new_map.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let child = format!("{}/{}", pointer, escape_pointer(key));
+                diff_child(&child, old_map.get(key), new_map.get(key), entries);
+            }
+        }
+        (Value::Array(old_items), Value::Array(new_items)) => {
+            for i in 0..old_items.len().max(new_items.len()) {
+                let child = format!("{}/{}", pointer, i);
+                diff_child(&child, old_items.get(i), new_items.get(i), entries);
+            }
+        }
+        _ if old != new => entries.push(JsonDiffEntry {
+            op: "changed".to_string(),
+            pointer: pointer.to_string(),
+            old: Some(old.clone()),
+            new: Some(new.clone()),
+        }),
+        _ => {}
+    }
+}
+
+fn diff_child(
+    pointer: &str,
+    old: Option<&Value>,
+    new: Option<&Value>,
+    entries: &mut Vec<JsonDiffEntry>,
+) {
+    match (old, new) {
+        (Some(old), Some(new)) => diff_value(pointer, old, new, entries),
+        (Some(old), None) => entries.push(JsonDiffEntry {
+            op: "removed".to_string(),
+            pointer: pointer.to_string(),
+            old: Some(old.clone()),
+            new: None,
+        }),
+        (None, Some(new)) => entries.push(JsonDiffEntry {
+            op: "added".to_string(),
+            pointer: pointer.to_string(),
+            old: None,
+            new: Some(new.clone()),
+        }),
+        (None, None) => {}
+    }
+}
+
+// RFC 6901 escaping of a key inside a JSON pointer
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
@@ -1,483 +1,5357 @@
 use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
-use cipher::BlockDecryptMut;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use cipher::{BlockDecryptMut, StreamCipher};
 use hex;
+use hkdf::Hkdf;
+use rand_chacha::ChaCha20Rng;
+use rand_core::{CryptoRng, OsRng, RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fs;
+use std::future::Future;
 use std::path::Path;
-use std::process::Command;
-use tauri::AppHandle;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use tauri::{AppHandle, Emitter};
+
+use crate::audit::append_audit_record;
+use crate::config::{
+    canonicalize_json, diff_json, mask_secrets, parse_json, validate_connector_config,
+    ConnectorConfig, JsonDiffEntry,
+};
+use crate::escrow::{
+    escrow_key_id, escrow_unwrap, escrow_wrap, parse_private_key, parse_public_key,
+};
+use crate::lock::FileLock;
+use crate::machine::{
+    choose_interface, detect_mac, detect_virtual_machine, eligible_binding_macs, get_computer_info,
+    get_hostname_for_metadata, get_machine_id_mac, match_present_interface, read_bios_uuid,
+    read_machine_domain, read_machine_sid, remember_first_selection, same_hostname, same_mac,
+    virtual_mac_vendor, MacSelection, MachineIdSource, NetworkInterface, EXTERNAL_VSWITCH_DECISION,
+    FALLBACK_MAC, SHARED_FALLBACK_DECISION,
+};
+use crate::protection::{dpapi_protect, dpapi_unprotect, tpm_unwrap, tpm_wrap, TPM_KEY_NAME};
 
 // Define the AES-CBC cipher with PKCS7 padding
+type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+type Aes192CbcEnc = cbc::Encryptor<aes::Aes192>;
+type Aes192CbcDec = cbc::Decryptor<aes::Aes192>;
 type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
 type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+// AES-CTR with the header IV as the initial 128-bit big-endian counter block
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+type Aes192Ctr = ctr::Ctr128BE<aes::Aes192>;
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct EncryptionResult {
-    success: bool,
-    message: String,
-    file_path: String,
-}
+// Organization secret baked into BTic distribution builds.
+//
+// The MAC and hostname used for key derivation are stored in clear in the
+// file's own metadata, so without this anyone with the public source can
+// re-derive the key. Official builds set BTIC_ORG_SECRET at compile time;
+// open-source builds leave it unset and keep producing (and reading) files
+// without it, flagged by the absence of ORG=1 in the metadata.
+pub(crate) const ORG_SECRET: Option<&str> = option_env!("BTIC_ORG_SECRET");
 
-// Command to encrypt JSON data
-#[tauri::command]
-pub async fn encrypt_json(
-    _app_handle: AppHandle,
-    json_data: String,
-    output_path: Option<String>,
-    char_key: Option<String>,
-) -> Result<EncryptionResult, String> {
-    // Parse char_key or use default "T"
-    let char_key = char_key.unwrap_or_else(|| "T".to_string());
-    let char_key_char = char_key.chars().next().unwrap_or('T');
+// Warning code returned when the binding fell back to the shared MAC
+const WARNING_MAC_FALLBACK: &str = "mac_detection_failed_using_fallback";
+// Warning code returned when the binding uses a randomized Wi-Fi MAC
+const WARNING_MAC_RANDOMIZED: &str = "mac_randomized_wifi_address";
+// Warning code returned when a VM's config is bound to its virtual NIC
+const WARNING_VIRTUAL_MACHINE: &str = "virtual_machine_nic_binding";
+// Warning code returned when a host with only virtual adapters is bound to
+// one whose MAC is regenerated with its virtual switch
+const WARNING_VIRTUAL_ADAPTER: &str = "virtual_adapter_binding";
+// Warning code returned when the binding adapter is a USB or dock NIC
+const WARNING_REMOVABLE_ADAPTER: &str = "removable_adapter_binding";
+// Warning code returned when canonicalization was asked for but the input
+// isn't JSON, so it was encrypted as is
+const WARNING_NOT_CANONICALIZED: &str = "input_not_json_not_canonicalized";
 
-    // Get computer info for key generation
-    let computer_info = get_computer_info();
-    println!("Computer info for key generation: {}", computer_info);
+// Environment variable holding the key char for headless deployments
+const KEY_CHAR_ENV: &str = "BTIC_KEY_CHAR";
 
-    // Get MAC and hostname for metadata
-    let mac_address = get_mac_for_metadata();
-    let hostname = get_hostname_for_metadata();
+// Keys that can open the metadata of a file, used to find it when the
+// length field can't be trusted
+const METADATA_START_KEYS: &[&str] = &["MAC=", "PROTECTION=", "BINDING=", "HOST=", "KDF="];
 
-    // Create metadata string
-    let metadata = format!(
-        "MAC={};HOST={};KEY_CHAR={};",
-        mac_address, hostname, char_key
-    );
-    let metadata_bytes = metadata.as_bytes();
-    let metadata_len = metadata_bytes.len() as u32;
-    let metadata_len_bytes = metadata_len.to_le_bytes();
+// Longest deployment tag accepted in the metadata, in characters
+const MAX_TAG_LEN: usize = 128;
 
-    println!(
-        "Metadata: {} (size: {} bytes)",
-        metadata,
-        metadata_bytes.len()
-    );
+// Upper bound for the metadata length field of an encrypted file
+const MAX_METADATA_LEN: usize = 64 * 1024;
 
-    // Generate key and IV
-    let key = get_key(32, &computer_info, char_key_char);
-    let iv = get_key(16, &computer_info, char_key_char);
+// Largest JSON encrypt_json accepts by default. A connector config is a few
+// KB; the input is copied several times while encrypting, so a runaway
+// frontend call is rejected before any of that. Deployments with unusual
+// configs raise it with the max_plaintext_bytes option.
+const DEFAULT_MAX_PLAINTEXT_LEN: usize = 16 * 1024 * 1024;
 
-    // Show key info for debugging
-    let key_string = pad_with_char(&computer_info, 32, char_key_char);
-    let iv_string = pad_with_char(&computer_info, 16, char_key_char);
-    println!(
-        "Full key string (with '{}' padding): {} (length: {})",
-        char_key_char,
-        key_string,
-        key_string.len()
-    );
-    println!(
-        "Full IV string (with '{}' padding): {} (length: {})",
-        char_key_char,
-        iv_string,
-        iv_string.len()
-    );
-    println!("Generated key (hex): {:?}", hex::encode(&key));
-    println!("Generated IV (hex): {:?}", hex::encode(&iv));
+// PBKDF2 parameters for passphrase-bound files. The iteration count is stored
+// in the header; files written before it was recorded used the default.
+// The same bounds apply to what a file may ask for, so a crafted header can't
+// pin the CPU for minutes.
+const PBKDF2_DEFAULT_ITERATIONS: u32 = 100_000;
+const PBKDF2_MIN_ITERATIONS: u32 = 10_000;
+const PBKDF2_MAX_ITERATIONS: u32 = 2_000_000;
+const PBKDF2_CALIBRATION_TARGET_MS: u128 = 250;
+const SALT_LEN: usize = 16;
+// Every file gets its own random CBC IV, stored in the header
+const IV_LEN: usize = 16;
 
-    // Encrypt the data
-    let data_to_encrypt = json_data.as_bytes();
-    let encrypted_data = match encrypt_data(data_to_encrypt, &key, &iv) {
-        Ok(data) => data,
-        Err(e) => return Err(format!("Encryption error: {}", e)),
-    };
+// Two-part recovery: the escrowed secret is key (32) + IV (16), the check
+// value lets recovery tell a wrong or corrupt share from a bad payload
+const RECOVERY_SECRET_LEN: usize = 48;
+const RECOVERY_ID_LEN: usize = 8;
+const RECOVERY_CHECK_LEN: usize = 8;
+const RECOVERY_CHECK_LABEL: &[u8] = b"btic-config-recovery-check-v1";
+const RECOVERY_SHARE_HEADER: &str = "BTIC-RECOVERY-SHARE-V1";
+// Escrowed files encrypt the payload under a random AES-256 data key
+const ESCROW_DATA_KEY_LEN: usize = 32;
 
-    println!("Encrypted data size: {} bytes", encrypted_data.len());
+// HKDF info labels, one per output so key and IV are independent
+const HKDF_KEY_INFO: &[u8] = b"btic-config-key-v2";
+const HKDF_IV_INFO: &[u8] = b"btic-config-iv-v2";
+// Separates the support fingerprint from any use of the key itself
+const FINGERPRINT_LABEL: &[u8] = b"btic-config-fingerprint-v1";
 
-    // Combine metadata length, metadata, and encrypted data
-    let mut final_data = Vec::with_capacity(4 + metadata_bytes.len() + encrypted_data.len());
-    final_data.extend_from_slice(&metadata_len_bytes);
-    final_data.extend_from_slice(metadata_bytes);
-    final_data.extend_from_slice(&encrypted_data);
+// Any-MAC protection: the data key is wrapped once per adapter and each copy
+// is found by a short salted hash of its MAC, so the header doesn't list the
+// machine's adapters in clear. Bounded so a crafted header stays cheap.
+const MAC_KEY_LABEL: &[u8] = b"btic-config-mac-key-v1";
+const MAC_KEY_ID_LEN: usize = 8;
+const MAX_MAC_KEYS: usize = 16;
 
-    println!("Final data size with metadata: {} bytes", final_data.len());
+// Self-test sample and its AES-256-CBC ciphertext under key 00..1f and IV
+// 00..0f, computed independently with `openssl enc -aes-256-cbc`
+const SELF_TEST_JSON: &str = r#"{"CodigoCliente":"SELFTEST","DB":{"DB_Port":"1433"}}"#;
+const SELF_TEST_CIPHERTEXT: &str = "fb4c36f3faa117c7d8150b85cc7b583c4a9248da18ec214c31e3b50ced6ad464\
+                                    525c3f84c44a67be0e362cee50031dbc6e88fbfddc0c428f7f036269dde5d232";
+// The same sample under AES-256-CTR, from `openssl enc -aes-256-ctr`
+const SELF_TEST_CTR_CIPHERTEXT: &str =
+    "214c47386c9216f9b3423c586cb7c3b05ad143908fc30c180642d97d50a162ed\
+     261e551f789a9ccbc995299fc720f3a1a5e97a0f";
+const SELF_TEST_PASSPHRASE: &str = "btic-self-test";
+// The whole file the sample gives on a fixed machine, with the salt and IV
+// drawn from a ChaCha20 RNG seeded with GOLDEN_SEED. Any change to the header
+// layout or the key derivation shows up as a mismatch; when the format
+// changes on purpose the fixture is regenerated.
+const GOLDEN_FILE: &[u8] = include_bytes!("../fixtures/golden_config.bin");
+const GOLDEN_SEED: u64 = 333;
+const GOLDEN_MAC: &str = "00:11:22:33:44:55";
+const GOLDEN_HOSTNAME: &str = "GOLDEN-HOST";
 
-    // Determine output path
-    let output_path = match output_path {
-        Some(path) => {
-            // Check if the path is absolute or just a filename
-            let path_obj = Path::new(&path);
-            if path_obj.is_absolute() {
-                path
-            } else {
-                // If relative, use the ProgramData directory as the base
-                let mut config_path = get_config_dir();
-                config_path.push(path);
-                // Create directory if it doesn't exist
-                if let Some(parent) = config_path.parent() {
-                    fs::create_dir_all(parent)
-                        .map_err(|e| format!("Failed to create directory: {}", e))?;
-                }
-                config_path.to_string_lossy().to_string()
-            }
-        }
-        None => {
-            // Default path - use ProgramData directory
-            let mut config_path = get_config_dir();
-            config_path.push("config");
-            config_path.to_string_lossy().to_string()
+// Progress events for large payloads. Small configs finish before the UI
+// could draw a progress bar, so they emit nothing.
+const PROGRESS_EVENT: &str = "encryption://progress";
+const PROGRESS_THRESHOLD: usize = 1024 * 1024;
+static NEXT_OPERATION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Antivirus scanners briefly open new files exclusively; writes hitting
+// that are retried with the delay doubling after each attempt
+const WRITE_ATTEMPTS: u32 = 3;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// How the key of a config file is bound
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Binding {
+    // Key derived from the MAC address and hostname of the machine
+    #[default]
+    Machine,
+    // Key derived from a user-supplied passphrase, portable across machines
+    Passphrase,
+    // Key derived from both, so the file needs the right machine and a person
+    // who knows the passphrase
+    Both,
+}
+
+impl Binding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Binding::Machine => "machine",
+            Binding::Passphrase => "passphrase",
+            Binding::Both => "both",
         }
-    };
+    }
 
-    // Save encrypted data to file
-    match save_encrypted_data(&final_data, &output_path) {
-        Ok(_) => {
-            println!("Encrypted data saved to: {}", output_path);
-            Ok(EncryptionResult {
-                success: true,
-                message: format!("Encryption successful. File saved to: {}", output_path),
-                file_path: output_path,
-            })
+    fn uses_machine(&self) -> bool {
+        matches!(self, Binding::Machine | Binding::Both)
+    }
+
+    fn uses_passphrase(&self) -> bool {
+        matches!(self, Binding::Passphrase | Binding::Both)
+    }
+
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "machine" => Ok(Binding::Machine),
+            "passphrase" => Ok(Binding::Passphrase),
+            "both" => Ok(Binding::Both),
+            other => Err(format!("Unknown binding mode: {}", other)),
         }
-        Err(e) => Err(format!("Failed to save file: {}", e)),
     }
 }
 
-// Function to get MAC address for metadata
-fn get_mac_for_metadata() -> String {
-    // We'll collect all available MAC addresses with their interface names
-    let mut selected_mac = String::new();
-
-    // Use ipconfig to get detailed network interface information on Windows
-    if let Ok(output) = Command::new("ipconfig").arg("/all").output() {
-        if let Ok(output_str) = String::from_utf8(output.stdout) {
-            let mut interfaces = Vec::new();
-            let mut current_interface: Option<(String, String)> = None;
+// Key derivation used for a file, recorded as KDF= in the metadata
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Kdf {
+    // Key and IV padded from the same string (files without a KDF entry)
+    #[default]
+    Legacy,
+    // Independent key and IV expanded with HKDF-SHA256
+    HkdfSha256,
+}
 
-            // Parse ipconfig output line by line
-            for line in output_str.lines() {
-                let line = line.trim();
+impl Kdf {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kdf::Legacy => "legacy",
+            Kdf::HkdfSha256 => "hkdf-sha256",
+        }
+    }
 
-                // New interface section starts with a description
-                if line.contains("adapter") && line.ends_with(":") {
-                    // Save previous interface if we found one
-                    if let Some((name, mac)) = current_interface.take() {
-                        if !mac.is_empty() {
-                            interfaces.push((name, mac));
-                        }
-                    }
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "legacy" => Ok(Kdf::Legacy),
+            "hkdf-sha256" => Ok(Kdf::HkdfSha256),
+            other => Err(format!("Unknown key derivation in metadata: {}", other)),
+        }
+    }
+}
 
-                    // Start a new interface
-                    let name = line.trim_end_matches(":");
-                    current_interface = Some((name.to_string(), String::new()));
-                }
+// AES key size of a file, recorded as KEY_BITS= in the metadata. Key
+// material is always derived at 32 bytes and the cipher uses its prefix, so
+// recovery shares and wrapped keys don't depend on the size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum KeySize {
+    Aes128,
+    Aes192,
+    // Files without a KEY_BITS entry
+    #[default]
+    Aes256,
+}
 
-                // Look for Physical Address (MAC)
-                if line.contains("Physical Address") {
-                    if let Some(mac_part) = line.split(":").nth(1) {
-                        if let Some((_name, mac)) = &mut current_interface {
-                            *mac = mac_part.trim().replace("-", "").replace(":", "");
-                        }
-                    }
-                }
-            }
+impl KeySize {
+    fn bits(&self) -> u16 {
+        match self {
+            KeySize::Aes128 => 128,
+            KeySize::Aes192 => 192,
+            KeySize::Aes256 => 256,
+        }
+    }
 
-            // Add the last interface
-            if let Some((name, mac)) = current_interface {
-                if !mac.is_empty() {
-                    interfaces.push((name, mac));
-                }
-            }
+    fn key_len(&self) -> usize {
+        self.bits() as usize / 8
+    }
 
-            // Debug output of all found interfaces
-            println!("Found {} network interfaces:", interfaces.len());
-            for (i, (name, mac)) in interfaces.iter().enumerate() {
-                println!("  [{}] {} -> {}", i, name, mac);
-            }
+    fn from_bits(bits: u16) -> Result<Self, String> {
+        match bits {
+            128 => Ok(KeySize::Aes128),
+            192 => Ok(KeySize::Aes192),
+            256 => Ok(KeySize::Aes256),
+            other => Err(format!(
+                "Unsupported AES key size: {} bits (use 128, 192 or 256)",
+                other
+            )),
+        }
+    }
+}
 
-            // Now apply the same selection logic as in the Go app
-            // First try with preferred interfaces
-            for (name, mac) in &interfaces {
-                let name_lower = name.to_lowercase();
-                if !name_lower.contains("virtual")
-                    && !name_lower.contains("vpn")
-                    && !name_lower.contains("vethernet")
-                    && !name_lower.contains("loopback")
-                    && ((name_lower.contains("ethernet") && !name_lower.contains("vethernet"))
-                        || name_lower.contains("wi-fi")
-                        || name_lower.contains("wlan"))
-                {
-                    selected_mac = mac.clone();
-                    println!("Selected interface: {} with MAC: {}", name, mac);
-                    break;
-                }
-            }
+// Block cipher mode of the payload, recorded as MODE= in the metadata
+//
+// CTR needs no padding, so the ciphertext is exactly as long as the JSON and
+// a reader can decrypt any byte range by seeking the counter. Like CBC it
+// provides no integrity: a flipped ciphertext bit flips the same plaintext
+// bit, so pair it with the hash sidecar (check_tamper) where that matters.
+// The IV is the initial counter and must never repeat under the same key,
+// which the random per-file IV guarantees.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum CipherMode {
+    // Files without a MODE entry
+    #[default]
+    Cbc,
+    Ctr,
+}
 
-            // Fallback to less strict criteria
-            if selected_mac.is_empty() {
-                for (name, mac) in &interfaces {
-                    let name_lower = name.to_lowercase();
-                    if !name_lower.contains("loopback") {
-                        selected_mac = mac.clone();
-                        println!("Fallback interface: {} with MAC: {}", name, mac);
-                        break;
-                    }
-                }
-            }
+impl CipherMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CipherMode::Cbc => "cbc",
+            CipherMode::Ctr => "ctr",
         }
     }
 
-    // If automatic detection failed, fall back to the hardcoded MAC
-    if selected_mac.is_empty() {
-        selected_mac = "902E168B9AC1".to_string();
-        println!("Using hardcoded fallback MAC address: {}", selected_mac);
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "cbc" => Ok(CipherMode::Cbc),
+            "ctr" => Ok(CipherMode::Ctr),
+            other => Err(format!("Unknown cipher mode: {} (use cbc or ctr)", other)),
+        }
     }
+}
 
-    selected_mac
+// Case folding applied to the hostname of a new file, recorded as
+// HOST_CASE= in the metadata. Keys are derived from the HOST= value as
+// stored, so the mode only documents how that value was produced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum HostnameCase {
+    // The name as the OS reports it (files without a HOST_CASE entry)
+    #[default]
+    Preserve,
+    Lower,
+    Upper,
 }
 
-// Function to get hostname for metadata
-fn get_hostname_for_metadata() -> String {
-    match hostname::get() {
-        Ok(name) => name.to_string_lossy().into_owned(),
-        Err(_) => "unknown".to_string(),
+impl HostnameCase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HostnameCase::Preserve => "preserve",
+            HostnameCase::Lower => "lower",
+            HostnameCase::Upper => "upper",
+        }
     }
-}
 
-// Function to get computer info with hardcoded MAC address
-fn get_computer_info() -> String {
-    let mac = get_mac_for_metadata();
-    let hostname = get_hostname_for_metadata();
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "preserve" => Ok(HostnameCase::Preserve),
+            "lower" => Ok(HostnameCase::Lower),
+            "upper" => Ok(HostnameCase::Upper),
+            other => Err(format!("Unknown hostname case: {}", other)),
+        }
+    }
 
-    // Combine MAC and hostname
-    let result = format!("{}{}", mac, hostname);
-    println!("Raw computer info (before padding): {}", result);
-    result
+    fn apply(&self, hostname: String) -> String {
+        match self {
+            HostnameCase::Preserve => hostname,
+            HostnameCase::Lower => hostname.to_lowercase(),
+            HostnameCase::Upper => hostname.to_uppercase(),
+        }
+    }
 }
 
-// Function to pad a string with a specific character to reach the specified length
-fn pad_with_char(input: &str, length: usize, pad_char: char) -> String {
-    let mut result = input.to_string();
-    if result.len() > length {
-        result.truncate(length);
-    } else {
-        while result.len() < length {
-            result.push(pad_char);
+// Platform protection of a random data key, recorded as PROTECTION= in the
+// metadata. Files without it derive their key from binding material.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Protection {
+    #[default]
+    None,
+    // Data key sealed with Windows DPAPI in machine scope
+    Dpapi,
+    // Data key wrapped by a persisted TPM key (Platform Crypto Provider)
+    Tpm,
+    // Data key wrapped under each physical adapter's MAC, so whichever of
+    // them is present later opens the file
+    AnyMac,
+}
+
+impl Protection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Protection::None => "none",
+            Protection::Dpapi => "dpapi",
+            Protection::Tpm => "tpm",
+            Protection::AnyMac => "any_mac",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "none" => Ok(Protection::None),
+            "dpapi" => Ok(Protection::Dpapi),
+            "tpm" => Ok(Protection::Tpm),
+            "any_mac" => Ok(Protection::AnyMac),
+            other => Err(format!("Unknown key protection: {}", other)),
         }
     }
-    result
 }
 
-// Function to create a key of specified length based on computer info
-fn get_key(key_length: usize, computer_info: &str, pad_char: char) -> Vec<u8> {
-    let padded_info = pad_with_char(computer_info, key_length, pad_char);
-    padded_info.into_bytes()
+// Plaintext metadata stored in front of the encrypted payload as
+// "KEY=VALUE;" pairs. Files without a BINDING entry are machine-bound and
+// files without a KDF entry use the legacy padded derivation.
+#[derive(Debug, Default)]
+struct FileMetadata {
+    mac: String,
+    mac_source: MachineIdSource,
+    bios_uuid: String,
+    // Windows machine SID, empty when not part of the binding
+    machine_sid: String,
+    hostname: String,
+    // DNS/AD domain or WORKGROUP_DOMAIN, empty when not part of the binding
+    domain: String,
+    hostname_case: HostnameCase,
+    key_char: Option<char>,
+    org_secret_applied: bool,
+    binding: Binding,
+    kdf: Kdf,
+    key_size: KeySize,
+    cipher_mode: CipherMode,
+    salt: Vec<u8>,
+    iv: Vec<u8>,
+    iterations: Option<u32>,
+    protection: Protection,
+    wrapped_key: Vec<u8>,
+    key_name: String,
+    // Any-MAC protection: one wrapped data key per adapter
+    mac_keys: Vec<MacKey>,
+    recovery_id: Vec<u8>,
+    recovery_share: Vec<u8>,
+    recovery_check: Vec<u8>,
+    // Escrow: the payload's random data key, wrapped under the derived key
+    // and under the recovery public key identified by escrow_id. Files
+    // without WRAPPED_DEK use the derived key directly.
+    wrapped_dek: Vec<u8>,
+    escrow_id: Vec<u8>,
+    escrow_key: Vec<u8>,
+    canonical: bool,
+    // Deployment id or version stamped by the operator; not key material
+    tag: Option<String>,
 }
 
-// Function to encrypt data using AES-CBC with PKCS7 padding
-fn encrypt_data(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
-    // Print debug info
-    println!("Data length: {} bytes", data.len());
-    println!("Key length: {} bytes", key.len());
-    println!("IV length: {} bytes", iv.len());
+impl FileMetadata {
+    fn parse(metadata_str: &str) -> Result<Self, String> {
+        let mut metadata = FileMetadata::default();
 
-    // Create AES-CBC cipher
-    let cipher = match Aes256CbcEnc::new_from_slices(key, iv) {
-        Ok(c) => c,
-        Err(e) => return Err(format!("Error creating cipher: {}", e)),
-    };
+        for part in metadata_str.split(';') {
+            if let Some(mac_val) = part.strip_prefix("MAC=") {
+                metadata.mac = mac_val.to_string();
+            } else if let Some(source_val) = part.strip_prefix("MAC_SOURCE=") {
+                metadata.mac_source = MachineIdSource::parse(source_val)?;
+            } else if let Some(uuid_val) = part.strip_prefix("BIOSUUID=") {
+                metadata.bios_uuid = uuid_val.to_string();
+            } else if let Some(sid_val) = part.strip_prefix("SID=") {
+                metadata.machine_sid = sid_val.to_string();
+            } else if let Some(domain_val) = part.strip_prefix("DOMAIN=") {
+                metadata.domain = domain_val.to_string();
+            } else if let Some(host_val) = part.strip_prefix("HOST=") {
+                metadata.hostname = host_val.to_string();
+            } else if let Some(case_val) = part.strip_prefix("HOST_CASE=") {
+                metadata.hostname_case = HostnameCase::parse(case_val)?;
+            } else if let Some(key_val) = part.strip_prefix("KEY_CHAR=") {
+                metadata.key_char = key_val.chars().next();
+            } else if let Some(org_val) = part.strip_prefix("ORG=") {
+                metadata.org_secret_applied = org_val == "1";
+            } else if let Some(binding_val) = part.strip_prefix("BINDING=") {
+                metadata.binding = Binding::parse(binding_val)?;
+            } else if let Some(kdf_val) = part.strip_prefix("KDF=") {
+                metadata.kdf = Kdf::parse(kdf_val)?;
+            } else if let Some(bits_val) = part.strip_prefix("KEY_BITS=") {
+                metadata.key_size = KeySize::from_bits(
+                    bits_val
+                        .parse()
+                        .map_err(|_| "Invalid key size in metadata".to_string())?,
+                )?;
+            } else if let Some(mode_val) = part.strip_prefix("MODE=") {
+                metadata.cipher_mode = CipherMode::parse(mode_val)?;
+            } else if let Some(salt_val) = part.strip_prefix("SALT=") {
+                metadata.salt =
+                    hex::decode(salt_val).map_err(|_| "Invalid salt in metadata".to_string())?;
+            } else if let Some(iv_val) = part.strip_prefix("IV=") {
+                metadata.iv =
+                    hex::decode(iv_val).map_err(|_| "Invalid IV in metadata".to_string())?;
+                if metadata.iv.len() != IV_LEN {
+                    return Err("Invalid IV length in metadata".to_string());
+                }
+            } else if let Some(iter_val) = part.strip_prefix("ITER=") {
+                metadata.iterations = Some(
+                    iter_val
+                        .parse()
+                        .map_err(|_| "Invalid iteration count in metadata".to_string())?,
+                );
+            } else if let Some(protection_val) = part.strip_prefix("PROTECTION=") {
+                metadata.protection = Protection::parse(protection_val)?;
+            } else if let Some(wrapped_val) = part.strip_prefix("WRAPPED_KEY=") {
+                metadata.wrapped_key = hex::decode(wrapped_val)
+                    .map_err(|_| "Invalid wrapped key in metadata".to_string())?;
+            } else if let Some(key_name_val) = part.strip_prefix("KEY_NAME=") {
+                metadata.key_name = key_name_val.to_string();
+            } else if let Some(mac_keys_val) = part.strip_prefix("MAC_KEYS=") {
+                metadata.mac_keys = parse_mac_keys(mac_keys_val)?;
+            } else if let Some(canonical_val) = part.strip_prefix("CANONICAL=") {
+                metadata.canonical = canonical_val == "1";
+            } else if let Some(tag_val) = part.strip_prefix("TAG=") {
+                metadata.tag = Some(unescape_metadata_value(tag_val)?);
+            } else if let Some(id_val) = part.strip_prefix("RECOVERY_ID=") {
+                metadata.recovery_id = hex::decode(id_val)
+                    .map_err(|_| "Invalid recovery id in metadata".to_string())?;
+            } else if let Some(share_val) = part.strip_prefix("RECOVERY_SHARE=") {
+                metadata.recovery_share = hex::decode(share_val)
+                    .map_err(|_| "Invalid recovery share in metadata".to_string())?;
+            } else if let Some(dek_val) = part.strip_prefix("WRAPPED_DEK=") {
+                metadata.wrapped_dek = hex::decode(dek_val)
+                    .map_err(|_| "Invalid wrapped data key in metadata".to_string())?;
+            } else if let Some(id_val) = part.strip_prefix("ESCROW_ID=") {
+                metadata.escrow_id =
+                    hex::decode(id_val).map_err(|_| "Invalid escrow id in metadata".to_string())?;
+            } else if let Some(escrow_val) = part.strip_prefix("ESCROW_KEY=") {
+                metadata.escrow_key = hex::decode(escrow_val)
+                    .map_err(|_| "Invalid escrowed key in metadata".to_string())?;
+            } else if let Some(check_val) = part.strip_prefix("RECOVERY_CHECK=") {
+                metadata.recovery_check = hex::decode(check_val)
+                    .map_err(|_| "Invalid recovery check in metadata".to_string())?;
+            }
+        }
 
-    // Calculate needed buffer size (data length + padding)
-    let block_size = 16; // AES block size is always 16 bytes
-    let padding_len = block_size - (data.len() % block_size);
-    let buffer_len = data.len() + padding_len;
+        Ok(metadata)
+    }
 
-    println!(
-        "Buffer size calculated: {} bytes (with {} padding)",
-        buffer_len, padding_len
-    );
+    fn encode(&self) -> String {
+        let mut metadata = String::new();
+        if self.protection == Protection::AnyMac {
+            let mac_keys: Vec<String> = self
+                .mac_keys
+                .iter()
+                .map(|mac_key| {
+                    format!(
+                        "{}:{}",
+                        hex::encode(&mac_key.id),
+                        hex::encode(&mac_key.wrapped)
+                    )
+                })
+                .collect();
+            metadata.push_str(&format!(
+                "PROTECTION={};HOST={};MAC_KEYS={};",
+                self.protection.as_str(),
+                self.hostname,
+                mac_keys.join(",")
+            ));
+            if let Some(key_char) = self.key_char {
+                metadata.push_str(&format!("KEY_CHAR={};", key_char));
+            }
+        } else if self.protection != Protection::None {
+            metadata.push_str(&format!(
+                "PROTECTION={};WRAPPED_KEY={};",
+                self.protection.as_str(),
+                hex::encode(&self.wrapped_key)
+            ));
+            if !self.key_name.is_empty() {
+                metadata.push_str(&format!("KEY_NAME={};", self.key_name));
+            }
+        } else if self.binding.uses_machine() {
+            metadata.push_str(&format!("MAC={};HOST={};", self.mac, self.hostname));
+            if self.mac_source != MachineIdSource::Mac {
+                metadata.push_str(&format!("MAC_SOURCE={};", self.mac_source.as_str()));
+            }
+            if !self.bios_uuid.is_empty() {
+                metadata.push_str(&format!("BIOSUUID={};", self.bios_uuid));
+            }
+            if !self.machine_sid.is_empty() {
+                metadata.push_str(&format!("SID={};", self.machine_sid));
+            }
+            if !self.domain.is_empty() {
+                metadata.push_str(&format!("DOMAIN={};", self.domain));
+            }
+            if self.hostname_case != HostnameCase::Preserve {
+                metadata.push_str(&format!("HOST_CASE={};", self.hostname_case.as_str()));
+            }
+            if let Some(key_char) = self.key_char {
+                metadata.push_str(&format!("KEY_CHAR={};", key_char));
+            }
+        }
+        if self.binding != Binding::Machine {
+            metadata.push_str(&format!("BINDING={};", self.binding.as_str()));
+        }
+        if !self.salt.is_empty() {
+            metadata.push_str(&format!("SALT={};", hex::encode(&self.salt)));
+        }
+        if self.binding.uses_passphrase() {
+            if let Some(iterations) = self.iterations {
+                metadata.push_str(&format!("ITER={};", iterations));
+            }
+        }
+        if self.kdf != Kdf::Legacy {
+            metadata.push_str(&format!("KDF={};", self.kdf.as_str()));
+        }
+        if self.key_size != KeySize::Aes256 {
+            metadata.push_str(&format!("KEY_BITS={};", self.key_size.bits()));
+        }
+        if self.cipher_mode != CipherMode::Cbc {
+            metadata.push_str(&format!("MODE={};", self.cipher_mode.as_str()));
+        }
+        if !self.iv.is_empty() {
+            metadata.push_str(&format!("IV={};", hex::encode(&self.iv)));
+        }
+        if self.org_secret_applied {
+            metadata.push_str("ORG=1;");
+        }
+        if self.canonical {
+            metadata.push_str("CANONICAL=1;");
+        }
+        if let Some(tag) = &self.tag {
+            metadata.push_str(&format!("TAG={};", escape_metadata_value(tag)));
+        }
+        if !self.recovery_id.is_empty() {
+            metadata.push_str(&format!(
+                "RECOVERY_ID={};RECOVERY_SHARE={};RECOVERY_CHECK={};",
+                hex::encode(&self.recovery_id),
+                hex::encode(&self.recovery_share),
+                hex::encode(&self.recovery_check)
+            ));
+        }
+        if !self.wrapped_dek.is_empty() {
+            metadata.push_str(&format!(
+                "WRAPPED_DEK={};ESCROW_ID={};ESCROW_KEY={};",
+                hex::encode(&self.wrapped_dek),
+                hex::encode(&self.escrow_id),
+                hex::encode(&self.escrow_key)
+            ));
+        }
+        metadata
+    }
+}
 
-    // Create properly sized buffer
-    let mut buffer = vec![0u8; buffer_len];
-    buffer[..data.len()].copy_from_slice(data);
+// The data key of an any-MAC file wrapped for one adapter
+#[derive(Debug)]
+struct MacKey {
+    // Identifies the adapter's MAC without storing it, see mac_key_id
+    id: Vec<u8>,
+    wrapped: Vec<u8>,
+}
 
-    // Encrypt with PKCS7 padding
-    match cipher.encrypt_padded_mut::<Pkcs7>(&mut buffer, data.len()) {
-        Ok(encrypted) => {
-            println!(
-                "Encryption successful, output length: {} bytes",
-                encrypted.len()
-            );
-            Ok(encrypted.to_vec())
+// Function to parse the "id:wrapped,..." list of an any-MAC file
+fn parse_mac_keys(value: &str) -> Result<Vec<MacKey>, String> {
+    let entries: Vec<&str> = value.split(',').filter(|entry| !entry.is_empty()).collect();
+    if entries.len() > MAX_MAC_KEYS {
+        return Err(format!(
+            "Metadata lists {} adapter keys, more than the {} allowed",
+            entries.len(),
+            MAX_MAC_KEYS
+        ));
+    }
+    entries
+        .into_iter()
+        .map(|entry| {
+            let (id, wrapped) = entry
+                .split_once(':')
+                .ok_or_else(|| "Invalid adapter key in metadata".to_string())?;
+            let id = hex::decode(id).map_err(|_| "Invalid adapter key in metadata".to_string())?;
+            let wrapped =
+                hex::decode(wrapped).map_err(|_| "Invalid adapter key in metadata".to_string())?;
+            Ok(MacKey { id, wrapped })
+        })
+        .collect()
+}
+
+// Function to percent-encode the separators of a free-text metadata value,
+// so it can't end its entry early or pass for another key
+fn escape_metadata_value(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace(';', "%3B")
+        .replace('=', "%3D")
+}
+
+fn unescape_metadata_value(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| "Invalid escape in metadata value".to_string())?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
         }
-        Err(e) => Err(format!("Error during encryption: {}", e)),
     }
+    String::from_utf8(decoded).map_err(|_| "Invalid escape in metadata value".to_string())
 }
 
-// Function to decrypt data using AES-CBC with PKCS7 padding
-fn decrypt_data(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, String> {
-    // Print debug info
-    println!("Encrypted data length: {} bytes", encrypted_data.len());
-    println!("Key length: {} bytes", key.len());
-    println!("IV length: {} bytes", iv.len());
+// Function to check a deployment tag before it goes into the metadata
+fn validate_tag(tag: &str) -> Result<(), String> {
+    if tag.is_empty() {
+        return Err("Tag must not be empty".to_string());
+    }
+    if tag.chars().count() > MAX_TAG_LEN {
+        return Err(format!("Tag must be at most {} characters", MAX_TAG_LEN));
+    }
+    if tag.chars().any(char::is_control) {
+        return Err("Tag must not contain control characters".to_string());
+    }
+    Ok(())
+}
 
-    // Create buffer for decrypted output (same size as input)
-    let mut buffer = encrypted_data.to_vec();
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptionResult {
+    success: bool,
+    message: String,
+    file_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_share_path: Option<String>,
+    // Header and ciphertext, only set when return_blob was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_base64: Option<String>,
+    // Per-path outcome when writing to several output paths
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    writes: Vec<WriteResult>,
+    // Where the time went, only set when timings were requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<EncryptionTimings>,
+    // Set when the machine binding may not be stable, e.g. only virtual
+    // adapters were found
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+    // Machine-readable warning codes, e.g. mac_detection_failed_using_fallback
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    // Which rule picked the bound MAC, for machine-bound files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mac_decision: Option<String>,
+    // Adapters considered for machine binding
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    interfaces: Vec<NetworkInterface>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EncryptionTimings {
+    // MAC (ipconfig) and hostname detection, zero for passphrase binding
+    mac_detect_ms: u64,
+    derive_ms: u64,
+    encrypt_ms: u64,
+    write_ms: u64,
+}
 
-    // Create AES-CBC cipher for decryption
-    let cipher = Aes256CbcDec::new_from_slices(key, iv)
-        .map_err(|e| format!("Error creating cipher: {}", e))?;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteResult {
+    path: String,
+    success: bool,
+    error: Option<String>,
+}
 
-    // Decrypt with PKCS7 unpadding
-    let decrypted = cipher
-        .decrypt_padded_mut::<Pkcs7>(&mut buffer)
-        .map_err(|e| format!("Error during decryption: {}", e))?;
+// Payload of an encryption://progress event. The operation id tells apart
+// concurrent operations; bytes refer to the current stage.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressEvent {
+    operation_id: u64,
+    operation: String,
+    stage: String,
+    bytes_processed: u64,
+    total_bytes: u64,
+}
 
-    Ok(decrypted.to_vec())
+// Emits progress for one encrypt or decrypt call, or nothing when the
+// payload is under the threshold
+struct ProgressReporter {
+    app_handle: Option<AppHandle>,
+    operation_id: u64,
+    operation: &'static str,
 }
 
-// Function to save encrypted data to a file
-fn save_encrypted_data(data: &[u8], file_path: &str) -> Result<(), String> {
-    // Create parent directories if they don't exist
-    if let Some(parent) = Path::new(file_path).parent() {
-        fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+impl ProgressReporter {
+    fn new(
+        app_handle: Option<&AppHandle>,
+        operation: &'static str,
+        size: usize,
+    ) -> ProgressReporter {
+        ProgressReporter {
+            app_handle: app_handle.filter(|_| size >= PROGRESS_THRESHOLD).cloned(),
+            operation_id: NEXT_OPERATION_ID.fetch_add(1, Ordering::Relaxed),
+            operation,
+        }
     }
 
-    // Write data to file
-    fs::write(file_path, data).map_err(|e| format!("Failed to write file: {}", e))
+    fn report(&self, stage: &str, bytes_processed: usize, total_bytes: usize) {
+        let Some(app_handle) = &self.app_handle else {
+            return;
+        };
+        let event = ProgressEvent {
+            operation_id: self.operation_id,
+            operation: self.operation.to_string(),
+            stage: stage.to_string(),
+            bytes_processed: bytes_processed as u64,
+            total_bytes: total_bytes as u64,
+        };
+        // Progress is informational; a closed window must not fail the operation
+        if let Err(e) = app_handle.emit(PROGRESS_EVENT, event) {
+            println!("Failed to emit progress event: {}", e);
+        }
+    }
 }
 
-// Helper function to get the standard configuration directory path
-fn get_config_dir() -> std::path::PathBuf {
-    // Direct hardcoded path to C:\ProgramData\Btic\ConfigConnectorBitrix
-    std::path::PathBuf::from("C:\\ProgramData\\Btic\\ConfigConnectorBitrix")
+// Everything encrypt_json can be asked to do besides the JSON itself. All
+// fields are optional and named, so callers set only what they need and
+// leave the rest to Default.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptOptions {
+    pub output_path: Option<String>,
+    pub output_paths: Option<Vec<String>>,
+    pub char_key: Option<String>,
+    pub passphrase: Option<String>,
+    pub binding_mode: Option<String>,
+    pub recovery_share_path: Option<String>,
+    pub return_blob: Option<bool>,
+    pub canonicalize: Option<bool>,
+    pub pbkdf2_iterations: Option<u32>,
+    pub timings: Option<bool>,
+    pub protection: Option<String>,
+    pub interface_mac: Option<String>,
+    pub binding_source: Option<String>,
+    pub profile: Option<String>,
+    pub include_bios_uuid: Option<bool>,
+    pub require_real_mac: Option<bool>,
+    pub hash_sidecar: Option<bool>,
+    pub key_bits: Option<u16>,
+    pub tag: Option<String>,
+    pub include_machine_sid: Option<bool>,
+    pub hostname_case: Option<String>,
+    pub cipher_mode: Option<String>,
+    pub include_domain: Option<bool>,
+    pub recovery_public_key: Option<String>,
+    pub max_plaintext_bytes: Option<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DecryptionResult {
-    success: bool,
-    message: String,
+// Command to encrypt JSON data. output_path and char_key stay top-level
+// arguments, as the Dashboard passes them; everything else is in options.
+#[tauri::command]
+pub async fn encrypt_json(
+    app_handle: AppHandle,
     json_data: String,
+    output_path: Option<String>,
+    char_key: Option<String>,
+    options: Option<EncryptOptions>,
+) -> Result<EncryptionResult, String> {
+    let mut options = options.unwrap_or_default();
+    options.output_path = output_path.or(options.output_path);
+    options.char_key = char_key.or(options.char_key);
+    encrypt_json_inner(Some(app_handle), json_data, options, &mut OsRng).await
 }
 
+// Command to encrypt a typed connector config. The frontend sends the model
+// instead of a JSON string, so a config missing a section or field is
+// rejected before anything is written.
 #[tauri::command]
-pub async fn decrypt_json(
-    _app_handle: AppHandle,
-    file_path: Option<String>,
+pub async fn encrypt_config(
+    app_handle: AppHandle,
+    config: ConnectorConfig,
+    output_path: Option<String>,
     char_key: Option<String>,
-    _username: Option<String>,
-) -> Result<DecryptionResult, String> {
-    // Determine input path
-    let input_path = match file_path {
-        Some(path) => path,
-        None => {
-            // Use the standard ProgramData directory
-            let mut config_path = get_config_dir();
-            config_path.push("config");
-            config_path.to_string_lossy().to_string()
-        }
-    };
+    passphrase: Option<String>,
+    binding_mode: Option<String>,
+    profile: Option<String>,
+) -> Result<EncryptionResult, String> {
+    let json_data =
+        serde_json::to_string(&config).map_err(|e| format!("Failed to serialize config: {}", e))?;
+    encrypt_json_inner(
+        Some(app_handle),
+        json_data,
+        EncryptOptions {
+            output_path,
+            char_key,
+            passphrase,
+            binding_mode,
+            profile,
+            ..Default::default()
+        },
+        &mut OsRng,
+    )
+    .await
+}
 
-    println!("Attempting to decrypt file: {}", input_path);
+// Options of encrypt_json exposed to callers outside the app (the CLI)
+#[derive(Debug, Default)]
+pub struct BlobOptions {
+    pub char_key: Option<String>,
+    pub passphrase: Option<String>,
+    pub binding_mode: Option<String>,
+    pub binding_source: Option<String>,
+    pub canonicalize: Option<bool>,
+}
 
-    // Read the encrypted file
-    let encrypted_data = match fs::read(&input_path) {
-        Ok(data) => data,
-        Err(e) => return Err(format!("Failed to read file: {}", e)),
-    };
+// Function to encrypt JSON into a complete encrypted file held in memory,
+// the same bytes encrypt_json would write
+pub async fn encrypt_to_blob(json_data: String, options: BlobOptions) -> Result<Vec<u8>, String> {
+    let result = encrypt_json_inner(
+        None,
+        json_data,
+        EncryptOptions {
+            char_key: options.char_key,
+            passphrase: options.passphrase,
+            binding_mode: options.binding_mode,
+            return_blob: Some(true),
+            canonicalize: options.canonicalize,
+            binding_source: options.binding_source,
+            ..Default::default()
+        },
+        &mut OsRng,
+    )
+    .await?;
+    BASE64
+        .decode(result.data_base64.unwrap_or_default())
+        .map_err(|e| format!("Invalid encrypted data: {}", e))
+}
 
-    println!("Read {} bytes from file", encrypted_data.len());
+// Function to decrypt a complete encrypted file held in memory to its JSON
+pub fn decrypt_blob(
+    encrypted_data: &[u8],
+    char_key: Option<String>,
+    passphrase: Option<String>,
+) -> Result<String, String> {
+    decrypt_file_contents(encrypted_data, char_key, passphrase, false)
+        .map(|result| result.json_data)
+}
 
-    // File must be at least 4 bytes (for metadata length)
-    if encrypted_data.len() < 4 {
-        return Err("File is too small to contain valid data".to_string());
-    }
+// Function behind encrypt_json; without an app handle no progress is emitted.
+// Every salt, IV and data key it generates comes from rng, so a seeded RNG
+// gives byte-exact output. Production callers must pass OsRng.
+async fn encrypt_json_inner(
+    app_handle: Option<AppHandle>,
+    json_data: String,
+    options: EncryptOptions,
+    rng: &mut (impl RngCore + CryptoRng + Send),
+) -> Result<EncryptionResult, String> {
+    let EncryptOptions {
+        output_path,
+        output_paths,
+        char_key,
+        passphrase,
+        binding_mode,
+        recovery_share_path,
+        return_blob,
+        canonicalize,
+        pbkdf2_iterations,
+        timings,
+        protection,
+        interface_mac,
+        binding_source,
+        profile,
+        include_bios_uuid,
+        require_real_mac,
+        hash_sidecar,
+        key_bits,
+        tag,
+        include_machine_sid,
+        hostname_case,
+        cipher_mode,
+        include_domain,
+        recovery_public_key,
+        max_plaintext_bytes,
+    } = options;
+    let mut timing = EncryptionTimings::default();
 
-    // Extract metadata length (first 4 bytes)
-    let metadata_len = u32::from_le_bytes([
-        encrypted_data[0],
-        encrypted_data[1],
-        encrypted_data[2],
-        encrypted_data[3],
-    ]) as usize;
+    let max_plaintext_len = max_plaintext_bytes.unwrap_or(DEFAULT_MAX_PLAINTEXT_LEN);
+    if json_data.len() > max_plaintext_len {
+        return Err(format!(
+            "PayloadTooLarge: JSON data is {} bytes, the limit is {} bytes",
+            json_data.len(),
+            max_plaintext_len
+        ));
+    }
 
-    println!("Metadata length: {} bytes", metadata_len);
+    // PKCS7 happily pads nothing to a full block, and the empty result would
+    // decrypt back to an empty "config". An empty object ({}) is allowed.
+    if json_data.trim().is_empty() {
+        return Err("Empty input: there is no JSON data to encrypt".to_string());
+    }
 
-    // Validate metadata length
-    if encrypted_data.len() < 4 + metadata_len {
-        return Err("File is too small to contain complete metadata".to_string());
+    if let Some(tag) = &tag {
+        validate_tag(tag)?;
     }
 
-    // Extract metadata
-    let metadata_str = match String::from_utf8(encrypted_data[4..4 + metadata_len].to_vec()) {
-        Ok(s) => s,
-        Err(_) => return Err("Invalid metadata encoding".to_string()),
-    };
+    // A profile names the default file; explicit paths would contradict it
+    let config_name = config_file_name(profile.as_deref())?;
+    if profile.is_some() && (output_path.is_some() || output_paths.is_some()) {
+        return Err("A profile can't be combined with explicit output paths".to_string());
+    }
 
-    println!("Metadata: {}", metadata_str);
+    // Determine output paths: the single output_path first, then any extras
+    let mut requested_paths: Vec<String> = output_path.into_iter().collect();
+    requested_paths.extend(output_paths.unwrap_or_default());
+    if requested_paths.is_empty() {
+        requested_paths.push(config_name);
+    }
+    let requested_paths: Vec<String> = requested_paths
+        .into_iter()
+        .map(resolve_output_path)
+        .collect();
 
-    // Parse metadata to extract MAC address, hostname, and key char
-    let mut mac = String::new();
-    let mut hostname = String::new();
-    let mut key_char = char_key
-        .unwrap_or_else(|| "T".to_string())
-        .chars()
-        .next()
-        .unwrap_or('T');
-
-    for part in metadata_str.split(';') {
-        if let Some(mac_val) = part.strip_prefix("MAC=") {
-            mac = mac_val.to_string();
-        } else if let Some(host_val) = part.strip_prefix("HOST=") {
-            hostname = host_val.to_string();
-        } else if let Some(key_val) = part.strip_prefix("KEY_CHAR=") {
-            if !key_val.is_empty() {
-                key_char = key_val.chars().next().unwrap_or('T');
+    // Fail before detection and encryption when nothing can be written; a
+    // single unwritable path among several is reported in its WriteResult
+    if !return_blob.unwrap_or(false) {
+        let mut probe_errors = Vec::new();
+        for path in &requested_paths {
+            if let Err(e) = check_writable(path).await {
+                probe_errors.push(e);
             }
         }
+        if probe_errors.len() == requested_paths.len() {
+            return Err(probe_errors.join("; "));
+        }
     }
 
-    println!("Extracted MAC: {}", mac);
-    println!("Extracted hostname: {}", hostname);
-    println!("Using key_char: {}", key_char);
+    // Unless the caller needs the exact bytes, store JSON in canonical form so
+    // re-saving an unchanged config is a no-op. Input that isn't JSON is
+    // encrypted as is with a warning, whatever canonicalize says.
+    let mut warnings = Vec::new();
+    let canonical_requested = canonicalize.unwrap_or(true);
+    let (json_data, canonical, is_json) = match canonicalize_json(&json_data) {
+        Ok(canonical_json) if canonical_requested => (canonical_json, true, true),
+        Ok(_) => (json_data, false, true),
+        Err(e) => {
+            println!("WARNING: Input not canonicalized: {}", e);
+            warnings.push(WARNING_NOT_CANONICALIZED.to_string());
+            (json_data, false, false)
+        }
+    };
 
-    // Recreate the computer_info string that was used for encryption
-    let computer_info = format!("{}{}", mac, hostname);
-    println!("Using computer info for decryption: {}", computer_info);
+    let char_key_char = resolve_key_char(char_key);
 
-    // Generate the same key and IV
-    let key = get_key(32, &computer_info, key_char);
-    let iv = get_key(16, &computer_info, key_char);
-    println!(
-        "Generated key length: {}, IV length: {}",
-        key.len(),
-        iv.len()
-    );
+    // Resolve the binding: an explicit mode wins, otherwise a passphrase
+    // alone selects passphrase binding
+    let binding = match binding_mode.as_deref() {
+        Some(mode) => Binding::parse(mode)?,
+        None if passphrase.is_some() => Binding::Passphrase,
+        None => Binding::Machine,
+    };
+    match passphrase.as_deref() {
+        None if binding.uses_passphrase() => {
+            return Err(format!(
+                "A passphrase is required for '{}' binding",
+                binding.as_str()
+            ))
+        }
+        Some(_) if !binding.uses_passphrase() => {
+            return Err("A passphrase was given but the binding mode is 'machine'".to_string())
+        }
+        Some("") => return Err("Passphrase must not be empty".to_string()),
+        _ => {}
+    }
 
-    // Get just the encrypted portion (after metadata)
-    let actual_encrypted_data = &encrypted_data[4 + metadata_len..];
-    println!(
-        "Actual encrypted data size: {} bytes",
-        actual_encrypted_data.len()
+    // Platform protection replaces the machine-derived key with a random one
+    let protection = match protection.as_deref() {
+        Some(value) => Protection::parse(value)?,
+        None => Protection::None,
+    };
+
+    // Escrow adds a random data key of its own, which protection already has
+    let recovery_public_key = recovery_public_key
+        .as_deref()
+        .map(parse_public_key)
+        .transpose()?;
+    if recovery_public_key.is_some() && protection != Protection::None {
+        return Err(format!(
+            "Recovery key escrow can't be combined with '{}' protection",
+            protection.as_str()
+        ));
+    }
+    if protection != Protection::None && binding != Binding::Machine {
+        return Err(format!(
+            "'{}' protection can't be combined with '{}' binding",
+            protection.as_str(),
+            binding.as_str()
+        ));
+    }
+
+    // What identifies the machine: an adapter MAC unless the operator picks
+    // an OS identifier that survives NIC swaps
+    let binding_source = match binding_source.as_deref() {
+        Some(value) => MachineIdSource::parse(value)?,
+        None => MachineIdSource::Mac,
+    };
+    let uses_mac = matches!(
+        binding_source,
+        MachineIdSource::Mac | MachineIdSource::MacAndMachineGuid
     );
+    if !uses_mac && interface_mac.is_some() {
+        return Err(format!(
+            "An interface MAC can't be chosen with the '{}' binding source",
+            binding_source.as_str()
+        ));
+    }
+    // Any-MAC protection binds to every adapter rather than a chosen one
+    if protection == Protection::AnyMac
+        && (binding_source != MachineIdSource::Mac || interface_mac.is_some())
+    {
+        return Err(
+            "'any_mac' protection binds to every adapter: don't choose an interface or binding source"
+                .to_string(),
+        );
+    }
 
-    // Decrypt the data
-    let decrypted_data = match decrypt_data(actual_encrypted_data, &key, &iv) {
+    // Folding the case keeps the HOST= value stable when a rename or domain
+    // join changes only the case the OS reports
+    let hostname_case = match hostname_case.as_deref() {
+        Some(value) => HostnameCase::parse(value)?,
+        None => HostnameCase::Preserve,
+    };
+
+    // AES-256 unless a partner integration needs a smaller key
+    let key_size = match key_bits {
+        Some(bits) => KeySize::from_bits(bits)?,
+        None => KeySize::Aes256,
+    };
+
+    // CBC unless a reader needs random access to the payload
+    let cipher_mode = match cipher_mode.as_deref() {
+        Some(value) => CipherMode::parse(value)?,
+        None => CipherMode::Cbc,
+    };
+    // A CTR file must decrypt to JSON, since nothing else catches a wrong key
+    if !is_json && cipher_mode == CipherMode::Ctr {
+        return Err("Input that isn't valid JSON can't be encrypted in ctr mode".to_string());
+    }
+
+    // Build the metadata for the requested binding
+    let mut metadata = FileMetadata {
+        binding,
+        kdf: Kdf::HkdfSha256,
+        key_size,
+        cipher_mode,
+        tag,
+        // A random protected key gains nothing from the organization secret
+        org_secret_applied: ORG_SECRET.is_some() && protection == Protection::None,
+        canonical,
+        protection,
+        ..Default::default()
+    };
+    let mut computer_info = String::new();
+    let mut mac_selection = None;
+    let mut binding_warnings = Vec::new();
+    if binding.uses_machine() && protection == Protection::None {
+        let started = Instant::now();
+
+        // Get MAC and hostname for metadata
+        if uses_mac {
+            let selection = match interface_mac.as_deref() {
+                Some(mac) => choose_interface(detect_mac_blocking().await?, mac)?,
+                None => detect_mac_blocking().await?,
+            };
+            // The shared fallback MAC gives every such machine the same key
+            if selection.decision == SHARED_FALLBACK_DECISION {
+                if require_real_mac.unwrap_or(false) {
+                    return Err(
+                        "No network interface or machine identifier was detected and a real MAC is required"
+                            .to_string(),
+                    );
+                }
+                warnings.push(WARNING_MAC_FALLBACK.to_string());
+            }
+            if selection.randomized {
+                warnings.push(WARNING_MAC_RANDOMIZED.to_string());
+            }
+            let selected_virtual = selection
+                .interfaces
+                .iter()
+                .any(|interface| interface.mac == selection.mac && interface.is_virtual);
+            if selected_virtual && selection.decision != EXTERNAL_VSWITCH_DECISION {
+                warnings.push(WARNING_VIRTUAL_ADAPTER.to_string());
+            }
+            // Selection only lands here when there is no internal adapter
+            // or the operator chose it
+            if let Some(interface) = selection
+                .interfaces
+                .iter()
+                .find(|interface| interface.mac == selection.mac && interface.removable)
+            {
+                let message = format!(
+                    "Bound to {}, a USB or docking-station adapter: the config only opens while it is connected - choose an internal adapter or use passphrase binding",
+                    interface.name
+                );
+                println!("WARNING: {}", message);
+                warnings.push(WARNING_REMOVABLE_ADAPTER.to_string());
+                binding_warnings.push(message);
+            }
+            // A VM gets a new NIC address when it is migrated or its adapter
+            // is recreated, which silently breaks the binding
+            if let Some(vendor) = virtual_mac_vendor(&selection.mac) {
+                let vm = tokio::task::spawn_blocking(detect_virtual_machine)
+                    .await
+                    .ok()
+                    .flatten();
+                if let Some(vm) = vm {
+                    let message = format!(
+                        "Running in a virtual machine ({}) and bound to a {} virtual NIC: use the {} binding source or passphrase binding so the config survives a VM migration",
+                        vm,
+                        vendor,
+                        MachineIdSource::MachineGuid.as_str()
+                    );
+                    println!("WARNING: {}", message);
+                    warnings.push(WARNING_VIRTUAL_MACHINE.to_string());
+                    binding_warnings.push(message);
+                }
+            }
+            metadata.mac = selection.mac.clone();
+            metadata.mac_source = selection.source;
+            if binding_source == MachineIdSource::MacAndMachineGuid {
+                metadata
+                    .mac
+                    .push_str(&get_machine_id_mac(MachineIdSource::MachineGuid)?);
+                metadata.mac_source = binding_source;
+            }
+            mac_selection = Some(selection);
+        } else {
+            metadata.mac = get_machine_id_mac(binding_source)?;
+            metadata.mac_source = binding_source;
+            println!("Binding to the {} identifier", binding_source.as_str());
+        }
+        metadata.hostname = hostname_case.apply(get_hostname_for_metadata());
+        metadata.hostname_case = hostname_case;
+        metadata.key_char = Some(char_key_char);
+
+        // An unusable BIOS UUID leaves the binding as it would be without it
+        if include_bios_uuid.unwrap_or(false) {
+            match read_bios_uuid() {
+                Ok(uuid) => metadata.bios_uuid = uuid,
+                Err(e) => {
+                    let message = format!("BIOS UUID not included in the binding: {}", e);
+                    println!("WARNING: {}", message);
+                    binding_warnings.push(message);
+                }
+            }
+        }
+
+        // Without the rights to look it up the binding stays as before
+        if include_machine_sid.unwrap_or(false) {
+            match read_machine_sid() {
+                Ok(sid) => metadata.machine_sid = sid,
+                Err(e) => {
+                    let message = format!("Machine SID not included in the binding: {}", e);
+                    println!("WARNING: {}", message);
+                    binding_warnings.push(message);
+                }
+            }
+        }
+
+        // A restored image in another AD domain stops opening the file even
+        // with the hostname and MAC cloned
+        if include_domain.unwrap_or(false) {
+            match read_machine_domain() {
+                Ok(domain) => metadata.domain = domain,
+                Err(e) => {
+                    let message = format!("Domain not included in the binding: {}", e);
+                    println!("WARNING: {}", message);
+                    binding_warnings.push(message);
+                }
+            }
+        }
+
+        // Get computer info for key generation
+        computer_info = get_computer_info(
+            &metadata.mac,
+            &metadata.bios_uuid,
+            &metadata.machine_sid,
+            &metadata.hostname,
+            &metadata.domain,
+        );
+
+        // Computer info is guessable; a per-file salt means a table of keys
+        // for likely MAC/hostname pairs has to be rebuilt for every file.
+        // Combined binding already salts through PBKDF2.
+        if binding == Binding::Machine {
+            metadata.salt = generate_salt(rng)?;
+        }
+
+        timing.mac_detect_ms = elapsed_ms(started);
+    }
+    let mut binding_macs = Vec::new();
+    if protection == Protection::AnyMac {
+        let started = Instant::now();
+        let selection = detect_mac_blocking().await?;
+        binding_macs = eligible_binding_macs(&selection);
+        if binding_macs.is_empty() {
+            return Err("No physical network adapter was detected to bind the key to".to_string());
+        }
+        println!("Binding to {} adapters", binding_macs.len());
+        metadata.hostname = hostname_case.apply(get_hostname_for_metadata());
+        metadata.hostname_case = hostname_case;
+        metadata.key_char = Some(char_key_char);
+        metadata.salt = generate_salt(rng)?;
+        mac_selection = Some(selection);
+        timing.mac_detect_ms = elapsed_ms(started);
+    }
+    if binding.uses_passphrase() {
+        let iterations = pbkdf2_iterations.unwrap_or(PBKDF2_DEFAULT_ITERATIONS);
+        if !(PBKDF2_MIN_ITERATIONS..=PBKDF2_MAX_ITERATIONS).contains(&iterations) {
+            return Err(format!(
+                "PBKDF2 iterations must be between {} and {}",
+                PBKDF2_MIN_ITERATIONS, PBKDF2_MAX_ITERATIONS
+            ));
+        }
+        metadata.iterations = Some(iterations);
+        metadata.salt = generate_salt(rng)?;
+        println!(
+            "Using {} binding, passphrase stretched with PBKDF2",
+            binding.as_str()
+        );
+    }
+
+    // Generate key and IV
+    let started = Instant::now();
+    let mut protected_key = None;
+    if protection != Protection::None {
+        let mut data_key = vec![0u8; 48];
+        rng.try_fill_bytes(&mut data_key)
+            .map_err(|e| format!("Failed to generate data key: {}", e))?;
+        if protection == Protection::Tpm {
+            metadata.key_name = TPM_KEY_NAME.to_string();
+        }
+        if protection == Protection::AnyMac {
+            metadata.mac_keys = wrap_mac_keys(&metadata, &binding_macs, &data_key)?;
+        } else {
+            metadata.wrapped_key = wrap_data_key(&metadata, &data_key)?;
+        }
+        println!("Data key protected with {}", protection.as_str());
+        protected_key = Some(data_key[..32].to_vec());
+    }
+    // A derived IV would repeat for every file on the machine
+    metadata.iv = vec![0u8; IV_LEN];
+    rng.try_fill_bytes(&mut metadata.iv)
+        .map_err(|e| format!("Failed to generate IV: {}", e))?;
+    // The data key was just generated, so unwrapping it again would only cost
+    // another DPAPI or TPM call (or a MAC scan for any_mac)
+    let (mut key, iv) = match protected_key {
+        Some(key) => (key, metadata.iv.clone()),
+        None => derive_key_iv(&metadata, &computer_info, passphrase.as_deref())?,
+    };
+
+    // With escrow the payload key is random; the derived key only wraps it,
+    // next to a copy wrapped for the recovery key holder
+    if let Some(public_key) = &recovery_public_key {
+        let mut data_key = vec![0u8; ESCROW_DATA_KEY_LEN];
+        rng.try_fill_bytes(&mut data_key)
+            .map_err(|e| format!("Failed to generate data key: {}", e))?;
+        metadata.wrapped_dek =
+            encrypt_data(&data_key, &key, &iv, KeySize::Aes256, CipherMode::Cbc)?;
+        metadata.escrow_id = escrow_key_id(public_key)?;
+        metadata.escrow_key = escrow_wrap(rng, public_key, &data_key)?;
+        println!(
+            "Data key escrowed to recovery key {}",
+            hex::encode(&metadata.escrow_id)
+        );
+        key = data_key;
+    }
+    timing.derive_ms = elapsed_ms(started);
+
+    // Split a recovery secret between the header and the admin share file.
+    // The share is written first so a failure leaves no unrecoverable config.
+    if let Some(share_path) = &recovery_share_path {
+        let admin_share = create_recovery_shares(rng, &mut metadata, &key, &iv)?;
+        save_recovery_share(&admin_share, &metadata.recovery_id, share_path).await?;
+        println!("Recovery share saved to: {}", share_path);
+    }
+
+    // Create metadata string
+    let metadata = metadata.encode();
+    println!("Metadata: {} (size: {} bytes)", metadata, metadata.len());
+
+    // Encrypt the data
+    let started = Instant::now();
+    let data_to_encrypt = json_data.as_bytes();
+    let progress = ProgressReporter::new(app_handle.as_ref(), "encrypt", data_to_encrypt.len());
+    progress.report("encrypting", 0, data_to_encrypt.len());
+    let encrypted_data = match encrypt_data(data_to_encrypt, &key, &iv, key_size, cipher_mode) {
         Ok(data) => data,
-        Err(e) => return Err(format!("Decryption error: {}", e)),
+        Err(e) => return Err(format!("Encryption error: {}", e)),
     };
+    progress.report("encrypting", data_to_encrypt.len(), data_to_encrypt.len());
 
-    println!("Decryption successful, got {} bytes", decrypted_data.len());
+    println!("Encrypted data size: {} bytes", encrypted_data.len());
 
-    // Convert decrypted bytes to string
-    match String::from_utf8(decrypted_data) {
-        Ok(json_string) => {
-            println!("Successfully converted decrypted data to JSON string");
-            Ok(DecryptionResult {
-                success: true,
-                message: "Decryption successful".to_string(),
-                json_data: json_string,
+    // Combine metadata length, metadata, and encrypted data
+    let final_data = assemble_encrypted_file(&metadata, &encrypted_data);
+    timing.encrypt_ms = elapsed_ms(started);
+
+    println!("Final data size with metadata: {} bytes", final_data.len());
+
+    // An automatic pick becomes the preference once a config is saved with it
+    let first_selection = mac_selection
+        .as_ref()
+        .filter(|_| protection == Protection::None && interface_mac.is_none())
+        .cloned();
+    let (warning, interfaces, mac_decision) = match mac_selection {
+        Some(selection) => (
+            selection.warning,
+            selection.interfaces,
+            Some(selection.decision),
+        ),
+        None => (None, Vec::new(), None),
+    };
+    let warning: Vec<String> = warning.into_iter().chain(binding_warnings).collect();
+    let warning = (!warning.is_empty()).then(|| warning.join("; "));
+
+    // Return the blob to the caller instead of writing a file
+    if return_blob.unwrap_or(false) {
+        return Ok(EncryptionResult {
+            success: true,
+            message: "Encryption successful. Encrypted data returned in memory".to_string(),
+            file_path: String::new(),
+            recovery_share_path,
+            data_base64: Some(BASE64.encode(&final_data)),
+            writes: Vec::new(),
+            timings: timings.unwrap_or(false).then_some(timing),
+            warning,
+            warnings,
+            mac_decision,
+            interfaces,
+        });
+    }
+
+    // Write the identical blob to every path, recording each outcome
+    let started = Instant::now();
+    let hash_sidecar = hash_sidecar.unwrap_or(false);
+    let total_write = final_data.len() * requested_paths.len();
+    let mut writes = Vec::with_capacity(requested_paths.len());
+    for (i, path) in requested_paths.into_iter().enumerate() {
+        progress.report("writing", i * final_data.len(), total_write);
+        let write = match save_encrypted_data(&final_data, &path, hash_sidecar).await {
+            Ok(_) => {
+                println!("Encrypted data saved to: {}", path);
+                // The config is already written; a failed audit record must
+                // not turn a successful save into an error
+                if let Err(e) = append_audit_record("encrypt", &path, &final_data) {
+                    println!("Failed to append audit record: {}", e);
+                }
+                WriteResult {
+                    path,
+                    success: true,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                println!("Failed to save {}: {}", path, e);
+                WriteResult {
+                    path,
+                    success: false,
+                    error: Some(e),
+                }
+            }
+        };
+        writes.push(write);
+    }
+    progress.report("writing", total_write, total_write);
+    timing.write_ms = elapsed_ms(started);
+
+    let saved: Vec<&str> = writes
+        .iter()
+        .filter(|w| w.success)
+        .map(|w| w.path.as_str())
+        .collect();
+    if saved.is_empty() {
+        let errors: Vec<String> = writes
+            .iter()
+            .map(|w| {
+                format!(
+                    "{}: {}",
+                    w.path,
+                    w.error.as_deref().unwrap_or("unknown error")
+                )
             })
+            .collect();
+        return Err(format!("Failed to save file: {}", errors.join("; ")));
+    }
+    if let Some(selection) = first_selection {
+        let _ = tokio::task::spawn_blocking(move || remember_first_selection(&selection)).await;
+    }
+
+    let all_saved = saved.len() == writes.len();
+    let message = if all_saved {
+        format!("Encryption successful. File saved to: {}", saved.join(", "))
+    } else {
+        format!(
+            "Encryption partially successful. Saved {} of {} files: {}",
+            saved.len(),
+            writes.len(),
+            saved.join(", ")
+        )
+    };
+    let file_path = saved[0].to_string();
+
+    Ok(EncryptionResult {
+        success: all_saved,
+        message,
+        file_path,
+        recovery_share_path,
+        data_base64: None,
+        // A single write is fully described by file_path
+        writes: if writes.len() > 1 { writes } else { Vec::new() },
+        timings: timings.unwrap_or(false).then_some(timing),
+        warning,
+        warnings,
+        mac_decision,
+        interfaces,
+    })
+}
+
+// Function to get the milliseconds elapsed since an instant
+fn elapsed_ms(started: Instant) -> u64 {
+    started.elapsed().as_millis() as u64
+}
+
+// Function to pad key material with a specific character to exactly `length` bytes
+//
+// Works on bytes: a hostname like "SERVIDOR-CAFÉ" makes String::len() and the
+// char count differ, and truncating the string could split a character.
+// For every input the old string version handled (ASCII pad char, cut on a
+// char boundary) the bytes are identical, so legacy files still decrypt.
+fn pad_with_char(input: &str, length: usize, pad_char: char) -> Vec<u8> {
+    let mut pad = [0u8; 4];
+    let pad = pad_char.encode_utf8(&mut pad).as_bytes();
+
+    let mut result: Vec<u8> = input.bytes().take(length).collect();
+    let missing = length - result.len();
+    result.extend(pad.iter().cycle().take(missing));
+    result
+}
+
+// Function to create a key of specified length based on computer info
+fn get_key(key_length: usize, computer_info: &str, pad_char: char) -> Vec<u8> {
+    pad_with_char(computer_info, key_length, pad_char)
+}
+
+// Function to derive the key and IV of a file from its metadata
+//
+// computer_info is only used for machine-bound files and passphrase only for
+// passphrase-bound ones. The organization secret is mixed in last, so it
+// applies the same way to every binding and KDF.
+fn derive_key_iv(
+    metadata: &FileMetadata,
+    computer_info: &str,
+    passphrase: Option<&str>,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    // Platform-protected files carry their own random key
+    if metadata.protection != Protection::None {
+        let (key, iv) = unwrap_data_key(metadata)?;
+        return Ok((key, stored_iv_or(metadata, iv)));
+    }
+
+    let key_char = metadata.key_char.unwrap_or('T');
+
+    let (mut key, mut iv) = match metadata.binding {
+        Binding::Machine => match metadata.kdf {
+            Kdf::Legacy => (
+                get_key(32, computer_info, key_char),
+                get_key(16, computer_info, key_char),
+            ),
+            // Machine-bound files written before salts were added have none
+            Kdf::HkdfSha256 => hkdf_key_iv(
+                (!metadata.salt.is_empty()).then_some(metadata.salt.as_slice()),
+                format!("{}{}", computer_info, key_char).as_bytes(),
+            ),
+        },
+        Binding::Passphrase => {
+            let stretched = stretch_for_file(metadata, passphrase)?;
+            match metadata.kdf {
+                Kdf::Legacy => (stretched[..32].to_vec(), stretched[32..].to_vec()),
+                Kdf::HkdfSha256 => hkdf_key_iv(None, &stretched[..32]),
+            }
         }
-        Err(e) => Err(format!("Failed to convert decrypted data to string: {}", e)),
+        Binding::Both => {
+            // Combined binding has only ever been written with HKDF
+            let stretched = stretch_for_file(metadata, passphrase)?;
+            let mut input = stretched[..32].to_vec();
+            input.extend_from_slice(format!("{}{}", computer_info, key_char).as_bytes());
+            hkdf_key_iv(None, &input)
+        }
+    };
+
+    if metadata.org_secret_applied {
+        if let Some(secret) = ORG_SECRET {
+            mix_org_secret(&mut key, secret);
+            mix_org_secret(&mut iv, secret);
+        }
+    }
+    let iv = stored_iv_or(metadata, iv);
+
+    // Escrowed files are encrypted under a random key the derived one wraps
+    if !metadata.wrapped_dek.is_empty() {
+        let data_key = decrypt_data(
+            &metadata.wrapped_dek,
+            &key,
+            &iv,
+            KeySize::Aes256,
+            CipherMode::Cbc,
+        )
+        .map_err(|e| format!("Failed to unwrap the data key: {}", e))?;
+        return Ok((data_key, iv));
+    }
+
+    Ok((key, iv))
+}
+
+// Function to pick the IV stored in the header; files written before IVs
+// were stored use the one derived with the key
+fn stored_iv_or(metadata: &FileMetadata, derived_iv: Vec<u8>) -> Vec<u8> {
+    if metadata.iv.is_empty() {
+        derived_iv
+    } else {
+        metadata.iv.clone()
+    }
+}
+
+// Function to seal a random data key (key followed by IV) with the platform
+fn wrap_data_key(metadata: &FileMetadata, data_key: &[u8]) -> Result<Vec<u8>, String> {
+    match metadata.protection {
+        Protection::None => Err("No key protection selected".to_string()),
+        Protection::Dpapi => dpapi_protect(data_key),
+        Protection::Tpm => tpm_wrap(data_key, &metadata.key_name),
+        Protection::AnyMac => Err("Any-MAC keys are wrapped per adapter".to_string()),
+    }
+}
+
+// Function to wrap the data key once for each adapter MAC. Each wrapping key
+// is derived like a machine-bound file's key, from that MAC and the hostname.
+fn wrap_mac_keys(
+    metadata: &FileMetadata,
+    macs: &[String],
+    data_key: &[u8],
+) -> Result<Vec<MacKey>, String> {
+    macs.iter()
+        .map(|mac| {
+            let (key, iv) = mac_wrapping_key(metadata, mac);
+            let wrapped = encrypt_data(data_key, &key, &iv, KeySize::Aes256, CipherMode::Cbc)?;
+            Ok(MacKey {
+                id: mac_key_id(&metadata.salt, mac),
+                wrapped,
+            })
+        })
+        .collect()
+}
+
+// Function to unwrap the data key of an any-MAC file with whichever listed
+// adapter is present
+fn unwrap_mac_key(metadata: &FileMetadata) -> Result<Vec<u8>, String> {
+    unwrap_mac_key_with(metadata, &detect_mac().interfaces)
+}
+
+fn unwrap_mac_key_with(
+    metadata: &FileMetadata,
+    interfaces: &[NetworkInterface],
+) -> Result<Vec<u8>, String> {
+    for interface in interfaces {
+        let id = mac_key_id(&metadata.salt, &interface.mac);
+        let Some(mac_key) = metadata
+            .mac_keys
+            .iter()
+            .find(|mac_key| constant_time_eq(&mac_key.id, &id))
+        else {
+            continue;
+        };
+        let (key, iv) = mac_wrapping_key(metadata, &interface.mac);
+        match decrypt_data(
+            &mac_key.wrapped,
+            &key,
+            &iv,
+            KeySize::Aes256,
+            CipherMode::Cbc,
+        ) {
+            Ok(data_key) => {
+                println!("Data key unwrapped with adapter {}", interface.name);
+                return Ok(data_key);
+            }
+            Err(e) => println!("Adapter {} didn't unwrap the key: {}", interface.name, e),
+        }
+    }
+    Err("none of the adapters it was bound to is present".to_string())
+}
+
+fn mac_wrapping_key(metadata: &FileMetadata, mac: &str) -> (Vec<u8>, Vec<u8>) {
+    let computer_info = get_computer_info(mac, "", "", &metadata.hostname, "");
+    let key_char = metadata.key_char.unwrap_or('T');
+    hkdf_key_iv(
+        Some(&metadata.salt),
+        format!("{}{}", computer_info, key_char).as_bytes(),
+    )
+}
+
+fn mac_key_id(salt: &[u8], mac: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(MAC_KEY_LABEL);
+    hasher.update(salt);
+    hasher.update(mac.as_bytes());
+    hasher.finalize()[..MAC_KEY_ID_LEN].to_vec()
+}
+
+// Function to unseal the data key of a platform-protected file
+//
+// Failures here almost always mean the file was copied from another computer,
+// so they are reported like any other machine mismatch
+fn unwrap_data_key(metadata: &FileMetadata) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let data_key = match metadata.protection {
+        Protection::None => return Err("File has no protected data key".to_string()),
+        Protection::Dpapi => dpapi_unprotect(&metadata.wrapped_key),
+        Protection::Tpm => tpm_unwrap(&metadata.wrapped_key, &metadata.key_name),
+        Protection::AnyMac => unwrap_mac_key(metadata),
+    }
+    .map_err(|e| format!("This file is bound to a different machine: {}", e))?;
+
+    if data_key.len() != 48 {
+        return Err("Protected data key has an unexpected length".to_string());
+    }
+    Ok((data_key[..32].to_vec(), data_key[32..].to_vec()))
+}
+
+// Function to stretch the passphrase of a passphrase-bound file
+fn stretch_for_file(metadata: &FileMetadata, passphrase: Option<&str>) -> Result<[u8; 48], String> {
+    let passphrase = passphrase.ok_or_else(|| {
+        format!(
+            "Passphrase required: this file uses '{}' binding",
+            metadata.binding.as_str()
+        )
+    })?;
+    if metadata.salt.is_empty() {
+        return Err("Passphrase-protected file is missing its salt".to_string());
+    }
+    let iterations = metadata.iterations.unwrap_or(PBKDF2_DEFAULT_ITERATIONS);
+    if !(PBKDF2_MIN_ITERATIONS..=PBKDF2_MAX_ITERATIONS).contains(&iterations) {
+        return Err(format!(
+            "File asks for {} PBKDF2 iterations, outside the allowed range {}..={}",
+            iterations, PBKDF2_MIN_ITERATIONS, PBKDF2_MAX_ITERATIONS
+        ));
     }
+    Ok(stretch_passphrase(passphrase, &metadata.salt, iterations))
 }
 
+// Function to expand input key material into independent key and IV
+//
+// The legacy derivation pads one string to both lengths, so the IV is just
+// the first 16 bytes of the key. HKDF-SHA256 with distinct info labels gives
+// two outputs that reveal nothing about each other. Passphrase inputs are
+// already salted by PBKDF2 and go in unsalted.
+fn hkdf_key_iv(salt: Option<&[u8]>, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let hkdf = Hkdf::<Sha256>::new(salt, input);
+    let mut key = vec![0u8; 32];
+    let mut iv = vec![0u8; 16];
+    hkdf.expand(HKDF_KEY_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    hkdf.expand(HKDF_IV_INFO, &mut iv)
+        .expect("16 bytes is a valid HKDF-SHA256 output length");
+    (key, iv)
+}
+
+// Function to generate a random salt for key derivation
+fn generate_salt(rng: &mut (impl RngCore + CryptoRng)) -> Result<Vec<u8>, String> {
+    let mut salt = vec![0u8; SALT_LEN];
+    rng.try_fill_bytes(&mut salt)
+        .map_err(|e| format!("Failed to generate salt: {}", e))?;
+    Ok(salt)
+}
+
+// Function to stretch a passphrase with PBKDF2-HMAC-SHA256
+fn stretch_passphrase(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; 48] {
+    let mut output = [0u8; 48];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut output);
+    output
+}
+
+// Command to measure how many PBKDF2 iterations take about 250 ms here
 #[tauri::command]
-pub async fn config_exists(_app_handle: AppHandle, _username: String) -> Result<bool, String> {
-    // Check in the ProgramData directory
-    let mut config_path = get_config_dir();
-    config_path.push("config");
+pub async fn calibrate_pbkdf2_iterations() -> Result<u32, String> {
+    let salt = generate_salt(&mut OsRng)?;
+    let start = Instant::now();
+    stretch_passphrase("calibration", &salt, PBKDF2_MIN_ITERATIONS);
+    let elapsed_ms = start.elapsed().as_millis().max(1);
+
+    // Scale the sample to the target and round down to whole thousands
+    let iterations = PBKDF2_MIN_ITERATIONS as u128 * PBKDF2_CALIBRATION_TARGET_MS / elapsed_ms;
+    let iterations = (iterations.min(PBKDF2_MAX_ITERATIONS as u128) as u32 / 1000) * 1000;
+    let iterations = iterations.max(PBKDF2_MIN_ITERATIONS);
+
+    println!(
+        "PBKDF2 calibration: {} iterations in {} ms, suggesting {}",
+        PBKDF2_MIN_ITERATIONS, elapsed_ms, iterations
+    );
+    Ok(iterations)
+}
+
+// Function to split a recovery secret between the header and an admin share
+//
+// The escrowed secret is the file's final key and IV. It is XORed with a
+// random pad of the same length: the pad is the admin share and the result
+// goes into the header, so either share alone is indistinguishable from
+// random. Returns the admin share.
+fn create_recovery_shares(
+    rng: &mut (impl RngCore + CryptoRng),
+    metadata: &mut FileMetadata,
+    key: &[u8],
+    iv: &[u8],
+) -> Result<Vec<u8>, String> {
+    let mut secret = key.to_vec();
+    secret.extend_from_slice(iv);
+
+    let mut admin_share = vec![0u8; secret.len()];
+    rng.try_fill_bytes(&mut admin_share)
+        .map_err(|e| format!("Failed to generate recovery share: {}", e))?;
+    let mut recovery_id = vec![0u8; RECOVERY_ID_LEN];
+    rng.try_fill_bytes(&mut recovery_id)
+        .map_err(|e| format!("Failed to generate recovery id: {}", e))?;
+
+    metadata.recovery_share = secret
+        .iter()
+        .zip(&admin_share)
+        .map(|(s, a)| s ^ a)
+        .collect();
+    metadata.recovery_check = recovery_check(&secret);
+    metadata.recovery_id = recovery_id;
+
+    Ok(admin_share)
+}
+
+// Function to combine the header share with an admin share into key and IV
+fn combine_recovery_shares(
+    metadata: &FileMetadata,
+    admin_share: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if metadata.recovery_share.len() != RECOVERY_SECRET_LEN {
+        return Err("This file has no usable recovery share in its header".to_string());
+    }
+    if admin_share.len() != RECOVERY_SECRET_LEN {
+        return Err("Recovery share is corrupt: unexpected length".to_string());
+    }
+
+    let secret: Vec<u8> = metadata
+        .recovery_share
+        .iter()
+        .zip(admin_share)
+        .map(|(h, a)| h ^ a)
+        .collect();
+    if !constant_time_eq(&recovery_check(&secret), &metadata.recovery_check) {
+        return Err("Recovery share is corrupt or does not belong to this file".to_string());
+    }
+
+    Ok((secret[..32].to_vec(), secret[32..].to_vec()))
+}
+
+// Function to compute the check value that confirms a recombined secret
+fn recovery_check(secret: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(RECOVERY_CHECK_LABEL);
+    hasher.update(secret);
+    hasher.finalize()[..RECOVERY_CHECK_LEN].to_vec()
+}
+
+// Function to write the admin share file
+async fn save_recovery_share(
+    admin_share: &[u8],
+    recovery_id: &[u8],
+    file_path: &str,
+) -> Result<(), String> {
+    let contents = format!(
+        "{}\nID={}\nSHARE={}\n",
+        RECOVERY_SHARE_HEADER,
+        hex::encode(recovery_id),
+        hex::encode(admin_share)
+    );
+    save_encrypted_data(contents.as_bytes(), file_path, false).await
+}
+
+// Function to parse an admin share file into its recovery id and share
+fn parse_recovery_share(contents: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut lines = contents.lines().map(str::trim);
+    if lines.next() != Some(RECOVERY_SHARE_HEADER) {
+        return Err("Not a recovery share file".to_string());
+    }
+
+    let mut recovery_id = None;
+    let mut share = None;
+    for line in lines {
+        if let Some(id_val) = line.strip_prefix("ID=") {
+            recovery_id = hex::decode(id_val).ok();
+        } else if let Some(share_val) = line.strip_prefix("SHARE=") {
+            share = hex::decode(share_val).ok();
+        }
+    }
+
+    match (recovery_id, share) {
+        (Some(recovery_id), Some(share)) => Ok((recovery_id, share)),
+        _ => Err("Recovery share is corrupt: missing or invalid fields".to_string()),
+    }
+}
+
+// Function to mix the organization secret into derived key material
+//
+// Appending the secret to computer_info would not work: pad_with_char
+// truncates to the key length, so the secret would mostly be cut off. Instead
+// every byte is XORed with a SHA-256 digest of the secret, which keeps the
+// machine binding intact and covers the whole key.
+fn mix_org_secret(key_material: &mut [u8], secret: &str) {
+    let digest = Sha256::digest(secret.as_bytes());
+    for (i, byte) in key_material.iter_mut().enumerate() {
+        *byte ^= digest[i % digest.len()];
+    }
+}
+
+// Constant-time comparison for security-sensitive values.
+//
+// Every comparison below must go through this helper instead of `==`:
+// - integrity (HMAC) tags read from a file against the recomputed tag
+// - the MAC address / hostname stored in the metadata against the live machine
+// - anything else derived from key material (key check values, fingerprints)
+//
+// Slice `==` returns at the first differing byte, so its running time tells
+// an attacker how long the matching prefix is. `ct_eq` always looks at every
+// byte; only a length mismatch (lengths are not secret) short-circuits.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+// Function to encrypt data using AES-CBC with PKCS7 padding, or AES-CTR
+fn encrypt_data(
+    data: &[u8],
+    key: &[u8],
+    iv: &[u8],
+    key_size: KeySize,
+    mode: CipherMode,
+) -> Result<Vec<u8>, String> {
+    let key = cipher_key(key, key_size)?;
+
+    // Print debug info
+    println!("Data length: {} bytes", data.len());
+    println!("Key length: {} bytes", key.len());
+    println!("IV length: {} bytes", iv.len());
+
+    // CTR is a stream mode, the ciphertext is as long as the data
+    if mode == CipherMode::Ctr {
+        return ctr_apply(key, iv, data, key_size);
+    }
+
+    // Calculate needed buffer size (data length + padding)
+    let block_size = 16; // AES block size is always 16 bytes
+    let padding_len = block_size - (data.len() % block_size);
+    let buffer_len = data.len() + padding_len;
+
+    println!(
+        "Buffer size calculated: {} bytes (with {} padding)",
+        buffer_len, padding_len
+    );
+
+    // Create properly sized buffer
+    let mut buffer = vec![0u8; buffer_len];
+    buffer[..data.len()].copy_from_slice(data);
+
+    // Encrypt with PKCS7 padding using the cipher for the key size
+    let encrypted = match key_size {
+        KeySize::Aes128 => cbc_encrypt::<Aes128CbcEnc>(key, iv, &mut buffer, data.len()),
+        KeySize::Aes192 => cbc_encrypt::<Aes192CbcEnc>(key, iv, &mut buffer, data.len()),
+        KeySize::Aes256 => cbc_encrypt::<Aes256CbcEnc>(key, iv, &mut buffer, data.len()),
+    }?;
+    println!(
+        "Encryption successful, output length: {} bytes",
+        encrypted.len()
+    );
+    Ok(encrypted)
+}
+
+// Function to XOR data with the AES-CTR keystream, which both encrypts and
+// decrypts
+fn ctr_apply(key: &[u8], iv: &[u8], data: &[u8], key_size: KeySize) -> Result<Vec<u8>, String> {
+    let mut buffer = data.to_vec();
+    match key_size {
+        KeySize::Aes128 => ctr_keystream::<Aes128Ctr>(key, iv, &mut buffer),
+        KeySize::Aes192 => ctr_keystream::<Aes192Ctr>(key, iv, &mut buffer),
+        KeySize::Aes256 => ctr_keystream::<Aes256Ctr>(key, iv, &mut buffer),
+    }?;
+    Ok(buffer)
+}
+
+fn ctr_keystream<C: KeyIvInit + StreamCipher>(
+    key: &[u8],
+    iv: &[u8],
+    buffer: &mut [u8],
+) -> Result<(), String> {
+    let mut cipher =
+        C::new_from_slices(key, iv).map_err(|e| format!("Error creating cipher: {}", e))?;
+    cipher
+        .try_apply_keystream(buffer)
+        .map_err(|e| format!("Error applying keystream: {}", e))
+}
+
+fn cbc_encrypt<C: KeyIvInit + BlockEncryptMut>(
+    key: &[u8],
+    iv: &[u8],
+    buffer: &mut [u8],
+    data_len: usize,
+) -> Result<Vec<u8>, String> {
+    let cipher =
+        C::new_from_slices(key, iv).map_err(|e| format!("Error creating cipher: {}", e))?;
+    cipher
+        .encrypt_padded_mut::<Pkcs7>(buffer, data_len)
+        .map(|encrypted| encrypted.to_vec())
+        .map_err(|e| format!("Error during encryption: {}", e))
+}
+
+// Function to take the cipher key for a key size from derived key material
+fn cipher_key(key: &[u8], key_size: KeySize) -> Result<&[u8], String> {
+    key.get(..key_size.key_len())
+        .ok_or_else(|| format!("Key material is too short for AES-{}", key_size.bits()))
+}
+
+// Function to decrypt data using AES-CBC with PKCS7 padding, or AES-CTR
+fn decrypt_data(
+    encrypted_data: &[u8],
+    key: &[u8],
+    iv: &[u8],
+    key_size: KeySize,
+    mode: CipherMode,
+) -> Result<Vec<u8>, String> {
+    let key = cipher_key(key, key_size)?;
+
+    // Print debug info
+    println!("Encrypted data length: {} bytes", encrypted_data.len());
+    println!("Key length: {} bytes", key.len());
+    println!("IV length: {} bytes", iv.len());
+
+    if mode == CipherMode::Ctr {
+        return ctr_apply(key, iv, encrypted_data, key_size);
+    }
+
+    // Create buffer for decrypted output (same size as input)
+    let mut buffer = encrypted_data.to_vec();
 
-    Ok(config_path.exists())
+    // Decrypt with PKCS7 unpadding using the cipher for the key size
+    match key_size {
+        KeySize::Aes128 => cbc_decrypt::<Aes128CbcDec>(key, iv, &mut buffer),
+        KeySize::Aes192 => cbc_decrypt::<Aes192CbcDec>(key, iv, &mut buffer),
+        KeySize::Aes256 => cbc_decrypt::<Aes256CbcDec>(key, iv, &mut buffer),
+    }
+}
+
+fn cbc_decrypt<C: KeyIvInit + BlockDecryptMut>(
+    key: &[u8],
+    iv: &[u8],
+    buffer: &mut [u8],
+) -> Result<Vec<u8>, String> {
+    let cipher =
+        C::new_from_slices(key, iv).map_err(|e| format!("Error creating cipher: {}", e))?;
+    cipher
+        .decrypt_padded_mut::<Pkcs7>(buffer)
+        .map(|decrypted| decrypted.to_vec())
+        .map_err(|e| format!("Error during decryption: {}", e))
+}
+
+// Function to resolve an output path, relative paths live in the config dir
+fn resolve_output_path(path: String) -> String {
+    // Check if the path is absolute or just a filename
+    let path_obj = Path::new(&path);
+    if path_obj.is_absolute() {
+        path
+    } else {
+        // If relative, use the ProgramData directory as the base
+        let mut config_path = get_config_dir();
+        config_path.push(path);
+        config_path.to_string_lossy().to_string()
+    }
+}
+
+// Function to pick the file a command works on: the given path, relative
+// paths in the config dir, or else the profile's config ("config" without one)
+fn config_target_path(file_path: Option<String>, profile: Option<&str>) -> Result<String, String> {
+    match file_path {
+        Some(path) => Ok(resolve_output_path(path)),
+        None => Ok(resolve_output_path(config_file_name(profile)?)),
+    }
+}
+
+// Function to save encrypted data to a file
+//
+// The data goes to a "<file>.tmp-<random>" file next to the target, is
+// synced to disk and then renamed over it, so a crash or a full disk never
+// leaves a half-written config. Temp files left by earlier failed writes are
+// removed first.
+//
+// With hash_sidecar a "<file>.sha256" reference for check_tamper is written
+// next to it. An existing sidecar is always refreshed, so a later write
+// without the flag doesn't leave a stale hash that reads as tampering.
+async fn save_encrypted_data(
+    data: &[u8],
+    file_path: &str,
+    hash_sidecar: bool,
+) -> Result<(), String> {
+    // Create parent directories if they don't exist
+    if let Some(parent) = Path::new(file_path).parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| write_error("Failed to create directory", parent, e))?;
+    }
+
+    // Another configurator instance may be writing or reading the same file
+    let _lock = lock_config(file_path).await?;
+    write_locked(data, file_path, hash_sidecar).await
+}
+
+// Function to write a file the way save_encrypted_data does, for a command
+// that already holds the config's lock across a read-modify-write. Backups
+// of the config are written with it under the config's lock.
+async fn write_locked(data: &[u8], file_path: &str, hash_sidecar: bool) -> Result<(), String> {
+    write_locked_with(data, file_path, hash_sidecar, |temp_path| async move {
+        write_synced(&temp_path, data).await
+    })
+    .await
+}
+
+// Function behind write_locked, with the write of the temp file passed in so
+// tests can make it fail the way a busy or full disk would
+async fn write_locked_with<W, Fut>(
+    data: &[u8],
+    file_path: &str,
+    hash_sidecar: bool,
+    mut write_temp: W,
+) -> Result<(), String>
+where
+    W: FnMut(String) -> Fut,
+    Fut: Future<Output = std::io::Result<()>>,
+{
+    // With the lock held no other writer can be using a temp file
+    remove_stale_temp_files(Path::new(file_path)).await;
+
+    // Write data to a temporary file, then move it into place
+    let mut suffix = [0u8; 4];
+    getrandom::getrandom(&mut suffix)
+        .map_err(|e| format!("Failed to generate temp file name: {}", e))?;
+    let temp_path = format!("{}.tmp-{}", file_path, hex::encode(suffix));
+    if let Err(e) = retry_transient(|| write_temp(temp_path.clone())).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(write_error("Failed to write file", Path::new(file_path), e));
+    }
+    if let Err(e) = retry_transient(|| tokio::fs::rename(&temp_path, file_path)).await {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(write_error(
+            "Failed to replace file",
+            Path::new(file_path),
+            e,
+        ));
+    }
+
+    let sidecar_path = hash_sidecar_path(file_path);
+    if hash_sidecar || Path::new(&sidecar_path).exists() {
+        tokio::fs::write(&sidecar_path, hash_sidecar_line(data, file_path))
+            .await
+            .map_err(|e| format!("Failed to write hash sidecar: {}", e))?;
+    }
+    Ok(())
+}
+
+// Function to write a file and flush it to disk before it is renamed into
+// place, so the rename can't land before the data does
+async fn write_synced(path: &str, data: &[u8]) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await
+}
+
+// Function to remove the temp files of earlier writes to a file that failed
+// before their rename: "<name>.tmp-<random>", and "<name>.tmp" from before
+// temp names were randomized
+async fn remove_stale_temp_files(target: &Path) {
+    let (Some(parent), Some(file_name)) = (target.parent(), target.file_name()) else {
+        return;
+    };
+    let file_name = file_name.to_string_lossy();
+    let legacy_name = format!("{}.tmp", file_name);
+    let prefix = format!("{}.tmp-", file_name);
+
+    let Ok(mut entries) = tokio::fs::read_dir(parent).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name == legacy_name || name.starts_with(&prefix) {
+            println!("Removing stale temp file: {}", entry.path().display());
+            let _ = tokio::fs::remove_file(entry.path()).await;
+        }
+    }
+}
+
+// Function to run a file operation, retrying it while it fails with a
+// transient error. Anything else, and the last transient error, is returned.
+async fn retry_transient<T, F, Fut>(mut operation: F) -> std::io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    let mut delay = WRITE_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(e) if attempt < WRITE_ATTEMPTS && is_transient_write_error(&e) => {
+                println!(
+                    "Write attempt {} of {} failed ({}), retrying in {} ms",
+                    attempt,
+                    WRITE_ATTEMPTS,
+                    e,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Function to tell a file briefly held by another process from a permanent
+// failure. Permission denied is permanent: retrying won't grant access.
+fn is_transient_write_error(e: &std::io::Error) -> bool {
+    is_file_in_use(e) || e.kind() == std::io::ErrorKind::Interrupted
+}
+
+// Function to check whether an error means another process, usually the
+// connector service, has the file open
+fn is_file_in_use(e: &std::io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    cfg!(windows)
+        && matches!(
+            e.raw_os_error(),
+            Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+        )
+}
+
+// Function to take the exclusive lock on a config file for a write, without
+// blocking the runtime while another process holds it
+async fn lock_config(file_path: &str) -> Result<FileLock, String> {
+    let path = std::path::PathBuf::from(file_path);
+    tokio::task::spawn_blocking(move || FileLock::acquire(&path))
+        .await
+        .map_err(|e| format!("Failed to lock {}: {}", file_path, e))?
+}
+
+// Function to take the shared lock on a config file for a read
+async fn lock_config_shared(file_path: &str) -> Result<FileLock, String> {
+    let path = std::path::PathBuf::from(file_path);
+    tokio::task::spawn_blocking(move || FileLock::acquire_shared(&path))
+        .await
+        .map_err(|e| format!("Failed to lock {}: {}", file_path, e))?
+}
+
+// Function to read a config file under its shared lock, so a file being
+// replaced is never read half-written
+async fn read_locked(file_path: &str) -> Result<Vec<u8>, String> {
+    let _lock = lock_config_shared(file_path).await?;
+    tokio::fs::read(file_path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", file_path, e))
+}
+
+// Function to describe a failed write. Group policy often locks down
+// ProgramData, and the raw "Access is denied" doesn't say what to do about it.
+fn write_error(failure: &str, path: &Path, e: std::io::Error) -> String {
+    if e.kind() == std::io::ErrorKind::PermissionDenied {
+        format!(
+            "Permission denied writing to {}: run the configurator as administrator or choose a different output_path",
+            path.display()
+        )
+    } else {
+        format!("{}: {}", failure, e)
+    }
+}
+
+// Function to check a file can be written before any work is done for it,
+// by creating and removing a probe file in its directory
+async fn check_writable(file_path: &str) -> Result<(), String> {
+    let Some(dir) = Path::new(file_path).parent() else {
+        return Ok(());
+    };
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| write_error("Failed to create directory", dir, e))?;
+    let probe = dir.join(format!(".write-probe-{}.tmp", std::process::id()));
+    tokio::fs::write(&probe, b"")
+        .await
+        .map_err(|e| write_error("Failed to write file", dir, e))?;
+    let _ = tokio::fs::remove_file(&probe).await;
+    Ok(())
+}
+
+fn hash_sidecar_path(file_path: &str) -> String {
+    format!("{}.sha256", file_path)
+}
+
+// Function to format the sidecar like sha256sum output, so it can also be
+// checked with `sha256sum -c` from the config directory
+fn hash_sidecar_line(data: &[u8], file_path: &str) -> String {
+    let file_name = Path::new(file_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("{}  {}\n", hex::encode(Sha256::digest(data)), file_name)
+}
+
+// Function to run MAC detection off the async runtime: ipconfig can take
+// hundreds of milliseconds and would otherwise stall the UI
+async fn detect_mac_blocking() -> Result<MacSelection, String> {
+    tokio::task::spawn_blocking(detect_mac)
+        .await
+        .map_err(|e| format!("MAC detection failed: {}", e))
+}
+
+// Helper function to get the standard configuration directory path
+pub(crate) fn get_config_dir() -> std::path::PathBuf {
+    // Direct hardcoded path to C:\ProgramData\Btic\ConfigConnectorBitrix
+    std::path::PathBuf::from("C:\\ProgramData\\Btic\\ConfigConnectorBitrix")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DecryptionResult {
+    success: bool,
+    message: String,
+    json_data: String,
+    // Whether the content was stored in canonical JSON form
+    canonical: bool,
+    // A combined-binding file was opened although this machine's hostname
+    // no longer matches; the UI should offer to rebind it
+    hostname_ignored: bool,
+    // Deployment tag from the header, if the file was stamped with one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    // The adapter a combined-binding file turned out to be bound to when
+    // automatic selection picked another one; it is now remembered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matched_interface: Option<String>,
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn decrypt_json(
+    app_handle: AppHandle,
+    file_path: Option<String>,
+    char_key: Option<String>,
+    _username: Option<String>,
+    passphrase: Option<String>,
+    data_base64: Option<String>,
+    profile: Option<String>,
+    ignore_hostname: Option<bool>,
+) -> Result<DecryptionResult, String> {
+    // Take the encrypted data from the caller or read it from the file
+    let encrypted_data = match data_base64 {
+        Some(data) => {
+            println!("Attempting to decrypt in-memory data");
+            BASE64
+                .decode(data.trim())
+                .map_err(|e| format!("Invalid base64 data: {}", e))?
+        }
+        None => {
+            // Determine input path
+            let input_path = match file_path {
+                Some(path) => path,
+                None => {
+                    // Use the standard ProgramData directory
+                    let mut config_path = get_config_dir();
+                    config_path.push(config_file_name(profile.as_deref())?);
+                    config_path.to_string_lossy().to_string()
+                }
+            };
+
+            println!("Attempting to decrypt file: {}", input_path);
+
+            // Read the encrypted file, waiting for a write in progress so a
+            // half-replaced file is never read
+            let _lock = lock_config_shared(&input_path).await?;
+            match tokio::fs::read(&input_path).await {
+                Ok(data) => data,
+                Err(e) => return Err(format!("Failed to read file: {}", e)),
+            }
+        }
+    };
+
+    println!("Read {} bytes of encrypted data", encrypted_data.len());
+    let total = encrypted_data.len();
+    let progress = ProgressReporter::new(Some(&app_handle), "decrypt", total);
+    progress.report("reading", total, total);
+
+    // Key derivation can run PBKDF2 or MAC detection, both blocking
+    progress.report("decrypting", 0, total);
+    let result = tokio::task::spawn_blocking(move || {
+        decrypt_file_contents(
+            &encrypted_data,
+            char_key,
+            passphrase,
+            ignore_hostname.unwrap_or(false),
+        )
+    })
+    .await
+    .map_err(|e| format!("Decryption failed: {}", e))?;
+    progress.report("decrypting", total, total);
+    result
+}
+
+// Command to decrypt a config for review with its secrets shown as ***, so a
+// screenshot of the UI doesn't leak them. decrypt_json stays the only way to
+// get the real values. mask_rules replaces the default key patterns.
+#[tauri::command]
+pub async fn decrypt_json_masked(
+    app_handle: AppHandle,
+    file_path: Option<String>,
+    char_key: Option<String>,
+    passphrase: Option<String>,
+    profile: Option<String>,
+    mask_rules: Option<Vec<String>>,
+) -> Result<DecryptionResult, String> {
+    let mut result = decrypt_json(
+        app_handle, file_path, char_key, None, passphrase, None, profile, None,
+    )
+    .await?;
+    result.json_data = mask_secrets(&result.json_data, mask_rules.as_deref())?;
+    Ok(result)
+}
+
+// Command to decrypt a config into the typed connector model, failing when
+// the content doesn't have the shape the service reads
+#[tauri::command]
+pub async fn decrypt_config(
+    app_handle: AppHandle,
+    file_path: Option<String>,
+    char_key: Option<String>,
+    passphrase: Option<String>,
+    profile: Option<String>,
+) -> Result<ConnectorConfig, String> {
+    let result = decrypt_json(
+        app_handle, file_path, char_key, None, passphrase, None, profile, None,
+    )
+    .await?;
+    serde_json::from_str(&result.json_data)
+        .map_err(|e| format!("Config doesn't match the connector schema: {}", e))
+}
+
+// Command to decrypt an encrypted blob the frontend already holds in memory
+#[tauri::command]
+pub async fn decrypt_bytes(
+    _app_handle: AppHandle,
+    data: Vec<u8>,
+    char_key: Option<String>,
+    passphrase: Option<String>,
+) -> Result<DecryptionResult, String> {
+    println!("Attempting to decrypt {} bytes from memory", data.len());
+    tokio::task::spawn_blocking(move || decrypt_file_contents(&data, char_key, passphrase, false))
+        .await
+        .map_err(|e| format!("Decryption failed: {}", e))?
+}
+
+// Command to find which of the candidate key chars a machine-bound file was
+// encrypted with. Only the matching char is returned, never the content, so
+// support staff can run it without seeing the config.
+#[tauri::command]
+pub async fn probe_key_char(
+    _app_handle: AppHandle,
+    file_path: String,
+    candidates: Vec<String>,
+) -> Result<Option<String>, String> {
+    let encrypted_data = read_locked(&file_path).await?;
+    // Each candidate costs a key derivation and a decryption
+    tokio::task::spawn_blocking(move || probe_candidates(&encrypted_data, candidates))
+        .await
+        .map_err(|e| format!("Key char probe failed: {}", e))?
+}
+
+// Function to try each candidate key char on a machine-bound file
+fn probe_candidates(
+    encrypted_data: &[u8],
+    candidates: Vec<String>,
+) -> Result<Option<String>, String> {
+    let (mut metadata, actual_encrypted_data) = split_encrypted_file(encrypted_data)?;
+
+    if metadata.protection != Protection::None || metadata.binding != Binding::Machine {
+        return Err("Key chars only apply to machine-bound files".to_string());
+    }
+    if metadata.org_secret_applied && ORG_SECRET.is_none() {
+        return Err(
+            "This file requires the BTic distribution build: it was encrypted with the organization secret"
+                .to_string(),
+        );
+    }
+
+    let computer_info = get_computer_info(
+        &metadata.mac,
+        &metadata.bios_uuid,
+        &metadata.machine_sid,
+        &metadata.hostname,
+        &metadata.domain,
+    );
+    for candidate in candidates {
+        let Some(key_char) = candidate.chars().next() else {
+            continue;
+        };
+        metadata.key_char = Some(key_char);
+        // An escrowed file already fails here, unwrapping its data key
+        let Ok((key, iv)) = derive_key_iv(&metadata, &computer_info, None) else {
+            continue;
+        };
+
+        // A wrong key almost always fails the padding check; the JSON parse
+        // rules out the rare wrong key whose padding happens to be valid and
+        // every wrong key of a CTR file, which has no padding
+        let decrypts = decrypt_data(
+            actual_encrypted_data,
+            &key,
+            &iv,
+            metadata.key_size,
+            metadata.cipher_mode,
+        )
+        .ok()
+        .and_then(|data| String::from_utf8(data).ok())
+        .map(|json_data| parse_json(&json_data).is_ok())
+        .unwrap_or(false);
+        if decrypts {
+            println!("Key char probe matched candidate {:?}", key_char);
+            return Ok(Some(key_char.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+// What the configurator sees of this machine, for support tickets. Only
+// non-secret values are fields here, so the JSON can't leak the key: the MAC
+// and hostname are stored in clear in every file header.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MachineFingerprint {
+    mac: String,
+    interface: Option<String>,
+    mac_source: String,
+    decision: String,
+    hostname: String,
+    // Length of the MAC and hostname string keys are derived from
+    computer_info_len: usize,
+    // The config the key fingerprints are for
+    file_path: String,
+    // The key this machine derives for the file; None unless the file exists
+    // and is machine-bound
+    key_fingerprint: Option<String>,
+    // The key the MAC and hostname in the file's header derive
+    file_key_fingerprint: Option<String>,
+    warning: Option<String>,
+    // What showed this machine is a VM (system model or CPUID), if it is one
+    virtual_machine: Option<String>,
+    // Hypervisor vendor whose OUI the bound MAC is from
+    virtual_mac_vendor: Option<String>,
+}
+
+// Command to describe the machine binding of a config (the profile's by
+// default). Keys are salted per file, so the key fingerprints only compare
+// within one file: they are equal exactly when this machine derives the key
+// the file's header does.
+#[tauri::command]
+pub async fn get_machine_fingerprint(
+    _app_handle: AppHandle,
+    file_path: Option<String>,
+    profile: Option<String>,
+    char_key: Option<String>,
+) -> Result<MachineFingerprint, String> {
+    let selection = detect_mac_blocking().await?;
+    let hostname = get_hostname_for_metadata();
+    let computer_info_len = get_computer_info(&selection.mac, "", "", &hostname, "").len();
+
+    let file_path = config_target_path(file_path, profile.as_deref())?;
+    let (key_fingerprint, file_key_fingerprint) = if Path::new(&file_path).exists() {
+        let encrypted_data = read_locked(&file_path).await?;
+        tokio::task::spawn_blocking(move || file_key_fingerprints(&encrypted_data, char_key))
+            .await
+            .map_err(|e| format!("Fingerprint failed: {}", e))??
+    } else {
+        (None, None)
+    };
+
+    let interface = selection
+        .interfaces
+        .iter()
+        .find(|interface| interface.mac == selection.mac)
+        .map(|interface| interface.name.clone());
+    let virtual_machine = tokio::task::spawn_blocking(detect_virtual_machine)
+        .await
+        .map_err(|e| format!("Virtual machine detection failed: {}", e))?;
+    let mac_vendor = virtual_mac_vendor(&selection.mac).map(str::to_string);
+    Ok(MachineFingerprint {
+        mac: selection.mac,
+        interface,
+        mac_source: selection.source.as_str().to_string(),
+        decision: selection.decision,
+        hostname,
+        computer_info_len,
+        file_path,
+        key_fingerprint,
+        file_key_fingerprint,
+        warning: selection.warning,
+        virtual_machine,
+        virtual_mac_vendor: mac_vendor,
+    })
+}
+
+// The inputs of this machine's legacy key material, without the string
+// itself: computer_info is the key material before padding
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ComputerInfoSummary {
+    mac: String,
+    hostname: String,
+    key_char: char,
+    // Length of the MAC and hostname string, as built by get_computer_info
+    computer_info_len: usize,
+    // Bytes of padded key material for the key size
+    padded_key_len: usize,
+    // Key char bytes added to reach padded_key_len; 0 when computer_info is
+    // long enough and its end is cut off instead
+    padding_len: usize,
+}
+
+// Command to show what goes into this machine's key, to compare against the
+// MAC and HOST of a file's header when decryption fails
+#[tauri::command]
+pub async fn get_computer_info_summary(
+    _app_handle: AppHandle,
+    char_key: Option<String>,
+    key_bits: Option<u16>,
+) -> Result<ComputerInfoSummary, String> {
+    let key_size = match key_bits {
+        Some(bits) => KeySize::from_bits(bits)?,
+        None => KeySize::Aes256,
+    };
+    let selection = detect_mac_blocking().await?;
+    let hostname = get_hostname_for_metadata();
+    let key_char = resolve_key_char(char_key);
+    let computer_info = get_computer_info(&selection.mac, "", "", &hostname, "");
+
+    let padded_key_len = get_key(key_size.key_len(), &computer_info, key_char).len();
+    Ok(ComputerInfoSummary {
+        mac: selection.mac,
+        hostname,
+        key_char,
+        computer_info_len: computer_info.len(),
+        padded_key_len,
+        padding_len: padded_key_len.saturating_sub(computer_info.len()),
+    })
+}
+
+// Function to fingerprint the key of a machine-bound file as this machine
+// derives it and as the values in its header do, both with the file's salt,
+// key char and KDF. Other bindings have no machine key to compare.
+fn file_key_fingerprints(
+    encrypted_data: &[u8],
+    char_key: Option<String>,
+) -> Result<(Option<String>, Option<String>), String> {
+    let (mut metadata, _) = split_encrypted_file(encrypted_data)?;
+    if metadata.binding != Binding::Machine || metadata.protection != Protection::None {
+        return Ok((None, None));
+    }
+    if metadata.key_char.is_none() {
+        metadata.key_char = Some(resolve_key_char(char_key));
+    }
+    // An escrowed file's payload key is random; the derived key wrapping it
+    // is the one that depends on the machine
+    metadata.wrapped_dek.clear();
+
+    let current = |stored: &str, read: fn() -> Result<String, String>| {
+        if stored.is_empty() {
+            String::new()
+        } else {
+            read().unwrap_or_default()
+        }
+    };
+    let machine_info = get_computer_info(
+        &current_machine_mac(&metadata)?,
+        &current(&metadata.bios_uuid, read_bios_uuid),
+        &current(&metadata.machine_sid, read_machine_sid),
+        &metadata.hostname_case.apply(get_hostname_for_metadata()),
+        &current(&metadata.domain, read_machine_domain),
+    );
+    let file_info = get_computer_info(
+        &metadata.mac,
+        &metadata.bios_uuid,
+        &metadata.machine_sid,
+        &metadata.hostname,
+        &metadata.domain,
+    );
+    Ok((
+        Some(key_fingerprint(&metadata, &machine_info)?),
+        Some(key_fingerprint(&metadata, &file_info)?),
+    ))
+}
+
+// Function to fingerprint a machine-bound key as a labelled SHA-256, which
+// identifies the key without revealing it
+fn key_fingerprint(metadata: &FileMetadata, computer_info: &str) -> Result<String, String> {
+    let (key, _) = derive_key_iv(metadata, computer_info, None)?;
+    let mut hasher = Sha256::new();
+    hasher.update(FINGERPRINT_LABEL);
+    hasher.update(&key);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// One sub-check of the self-test
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelfTestCheck {
+    name: String,
+    passed: bool,
+    detail: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelfTestReport {
+    passed: bool,
+    checks: Vec<SelfTestCheck>,
+}
+
+// Command to check an install before trusting it with a real config: the
+// cipher against known vectors, the file format against a golden file, a
+// full encrypt/decrypt round trip, MAC detection and write access to the
+// config directory. Nothing is written except a probe file that is removed
+// again.
+#[tauri::command]
+pub async fn run_self_test() -> Result<SelfTestReport, String> {
+    tokio::task::spawn_blocking(|| {
+        let checks = vec![
+            self_test_check("known_vector", self_test_known_vector()),
+            self_test_check("ctr_vector", self_test_ctr_vector()),
+            self_test_check("golden_file", self_test_golden_file()),
+            self_test_check("round_trip", self_test_round_trip(&mut OsRng)),
+            self_test_check("mac_detection", self_test_mac_detection()),
+            self_test_check("config_dir_writable", self_test_config_dir()),
+        ];
+        SelfTestReport {
+            passed: checks.iter().all(|check| check.passed),
+            checks,
+        }
+    })
+    .await
+    .map_err(|e| format!("Self-test failed: {}", e))
+}
+
+fn self_test_check(name: &str, result: Result<String, String>) -> SelfTestCheck {
+    let (passed, detail) = match result {
+        Ok(detail) => (true, detail),
+        Err(detail) => (false, detail),
+    };
+    println!(
+        "Self-test {}: {} ({})",
+        name,
+        if passed { "passed" } else { "FAILED" },
+        detail
+    );
+    SelfTestCheck {
+        name: name.to_string(),
+        passed,
+        detail,
+    }
+}
+
+// Function to check the AES-256-CBC implementation against the stored vector
+fn self_test_known_vector() -> Result<String, String> {
+    let key: Vec<u8> = (0u8..32).collect();
+    let iv: Vec<u8> = (0u8..16).collect();
+    let expected = hex::decode(SELF_TEST_CIPHERTEXT)
+        .map_err(|e| format!("Invalid self-test vector: {}", e))?;
+
+    let encrypted = encrypt_data(
+        SELF_TEST_JSON.as_bytes(),
+        &key,
+        &iv,
+        KeySize::Aes256,
+        CipherMode::Cbc,
+    )?;
+    if encrypted != expected {
+        return Err("Ciphertext doesn't match the known vector".to_string());
+    }
+    let decrypted = decrypt_data(&encrypted, &key, &iv, KeySize::Aes256, CipherMode::Cbc)?;
+    if decrypted != SELF_TEST_JSON.as_bytes() {
+        return Err("Decrypted vector doesn't match the sample".to_string());
+    }
+    Ok("AES-256-CBC matches the known vector".to_string())
+}
+
+// Function to check the AES-256-CTR implementation against the stored vector
+fn self_test_ctr_vector() -> Result<String, String> {
+    let key: Vec<u8> = (0u8..32).collect();
+    let iv: Vec<u8> = (0u8..16).collect();
+    let expected = hex::decode(SELF_TEST_CTR_CIPHERTEXT)
+        .map_err(|e| format!("Invalid self-test vector: {}", e))?;
+
+    let encrypted = encrypt_data(
+        SELF_TEST_JSON.as_bytes(),
+        &key,
+        &iv,
+        KeySize::Aes256,
+        CipherMode::Ctr,
+    )?;
+    if encrypted != expected {
+        return Err("Ciphertext doesn't match the known vector".to_string());
+    }
+    let decrypted = decrypt_data(&encrypted, &key, &iv, KeySize::Aes256, CipherMode::Ctr)?;
+    if decrypted != SELF_TEST_JSON.as_bytes() {
+        return Err("Decrypted vector doesn't match the sample".to_string());
+    }
+    Ok("AES-256-CTR matches the known vector".to_string())
+}
+
+// Function to compare the file built from the golden inputs byte for byte
+// with the checked-in fixture
+fn self_test_golden_file() -> Result<String, String> {
+    let file = golden_file(&mut ChaCha20Rng::seed_from_u64(GOLDEN_SEED))?;
+    if file != GOLDEN_FILE {
+        return Err("Encrypted file doesn't match the golden file, the format changed".to_string());
+    }
+    Ok(format!("{} byte file matches the golden file", file.len()))
+}
+
+// Function to build a machine-bound file for the golden MAC and hostname,
+// with its salt and IV drawn from rng
+fn golden_file(rng: &mut (impl RngCore + CryptoRng)) -> Result<Vec<u8>, String> {
+    let mut metadata = FileMetadata {
+        mac: GOLDEN_MAC.to_string(),
+        hostname: GOLDEN_HOSTNAME.to_string(),
+        key_char: Some('T'),
+        kdf: Kdf::HkdfSha256,
+        salt: generate_salt(rng)?,
+        ..Default::default()
+    };
+    metadata.iv = vec![0u8; IV_LEN];
+    rng.try_fill_bytes(&mut metadata.iv)
+        .map_err(|e| format!("Failed to generate IV: {}", e))?;
+    let computer_info = get_computer_info(GOLDEN_MAC, "", "", GOLDEN_HOSTNAME, "");
+
+    let (key, iv) = derive_key_iv(&metadata, &computer_info, None)?;
+    let encrypted = encrypt_data(
+        SELF_TEST_JSON.as_bytes(),
+        &key,
+        &iv,
+        metadata.key_size,
+        metadata.cipher_mode,
+    )?;
+    Ok(assemble_encrypted_file(&metadata.encode(), &encrypted))
+}
+
+// Function to write and read back a passphrase-bound file in memory, covering
+// the header, PBKDF2, HKDF and the cipher the way decrypt_json uses them
+fn self_test_round_trip(rng: &mut (impl RngCore + CryptoRng)) -> Result<String, String> {
+    let mut metadata = FileMetadata {
+        binding: Binding::Passphrase,
+        kdf: Kdf::HkdfSha256,
+        iterations: Some(PBKDF2_MIN_ITERATIONS),
+        salt: generate_salt(rng)?,
+        ..Default::default()
+    };
+    metadata.iv = vec![0u8; IV_LEN];
+    rng.try_fill_bytes(&mut metadata.iv)
+        .map_err(|e| format!("Failed to generate IV: {}", e))?;
+
+    let (key, iv) = derive_key_iv(&metadata, "", Some(SELF_TEST_PASSPHRASE))?;
+    let encrypted = encrypt_data(
+        SELF_TEST_JSON.as_bytes(),
+        &key,
+        &iv,
+        metadata.key_size,
+        metadata.cipher_mode,
+    )?;
+    let file = assemble_encrypted_file(&metadata.encode(), &encrypted);
+
+    let result = decrypt_file_contents(&file, None, Some(SELF_TEST_PASSPHRASE.to_string()), false)?;
+    if result.json_data != SELF_TEST_JSON {
+        return Err("Decrypted content doesn't match the sample".to_string());
+    }
+    Ok(format!(
+        "{} byte file decrypted back to the sample",
+        file.len()
+    ))
+}
+
+// Function to check MAC detection found a real adapter or machine identifier
+fn self_test_mac_detection() -> Result<String, String> {
+    let selection = detect_mac();
+    if selection.decision == SHARED_FALLBACK_DECISION {
+        return Err(
+            "No network interface or machine identifier was detected; the shared fallback MAC would be used"
+                .to_string(),
+        );
+    }
+    let mut detail = format!(
+        "{} via {} ({})",
+        selection.mac,
+        selection.source.as_str(),
+        selection.decision
+    );
+    if let Some(warning) = selection.warning {
+        detail = format!("{}; {}", detail, warning);
+    }
+    Ok(detail)
+}
+
+// Function to check the config directory can be created and written
+fn self_test_config_dir() -> Result<String, String> {
+    let dir = get_config_dir();
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let probe = dir.join(".self-test.tmp");
+    fs::write(&probe, SELF_TEST_JSON)
+        .map_err(|e| format!("{} is not writable: {}", dir.display(), e))?;
+    let _ = fs::remove_file(&probe);
+    Ok(format!("{} is writable", dir.display()))
+}
+
+// Function to pick the key char: an explicit char_key wins, then the
+// BTIC_KEY_CHAR environment variable, then the default 'T'
+fn resolve_key_char(char_key: Option<String>) -> char {
+    resolve_key_char_with(char_key, |name| std::env::var(name).ok())
+}
+
+// Function behind resolve_key_char, with the environment lookup passed in
+fn resolve_key_char_with(char_key: Option<String>, env: impl Fn(&str) -> Option<String>) -> char {
+    char_key
+        .or_else(|| env(KEY_CHAR_ENV))
+        .and_then(|c| c.chars().next())
+        .unwrap_or('T')
+}
+
+// Function to decrypt a whole encrypted file (header and payload) held in memory
+//
+// With ignore_hostname a combined-binding file whose MAC still matches opens
+// after a rename (e.g. a domain migration). The key is derived from the
+// header's hostname either way, so a wrong file still fails the padding and
+// UTF-8 checks rather than decrypting to garbage.
+fn decrypt_file_contents(
+    encrypted_data: &[u8],
+    char_key: Option<String>,
+    passphrase: Option<String>,
+    ignore_hostname: bool,
+) -> Result<DecryptionResult, String> {
+    // Split the file into its metadata and encrypted payload
+    let (mut metadata, actual_encrypted_data) = split_encrypted_file(encrypted_data)?;
+
+    if metadata.org_secret_applied && ORG_SECRET.is_none() {
+        return Err(
+            "This file requires the BTic distribution build: it was encrypted with the organization secret"
+                .to_string(),
+        );
+    }
+
+    // Files written without KEY_CHAR use the caller's key char
+    if metadata.key_char.is_none() {
+        metadata.key_char = Some(resolve_key_char(char_key));
+    }
+
+    // Ask for a missing passphrase before touching the machine binding
+    if metadata.binding.uses_passphrase() && passphrase.is_none() {
+        return Err(format!(
+            "Passphrase required: this file uses '{}' binding",
+            metadata.binding.as_str()
+        ));
+    }
+
+    // Combined binding also requires the file to be opened on its own machine
+    let mut hostname_ignored = false;
+    let mut matched_interface = None;
+    if metadata.binding == Binding::Both {
+        let current_mac = current_machine_mac(&metadata)?;
+        let current_hostname = get_hostname_for_metadata();
+        let mut same_mac = same_mac(&metadata.mac, &current_mac);
+        // The key comes from the header MAC, so trying each adapter comes
+        // down to finding one that carries it
+        if !same_mac && metadata.mac_source == MachineIdSource::Mac {
+            matched_interface = match_present_interface(&metadata.mac);
+            same_mac = matched_interface.is_some();
+        }
+        let same_host = same_hostname(&metadata.hostname, &current_hostname);
+        let matches = |stored: &str, current: Result<String, String>| {
+            stored.is_empty()
+                || current
+                    .is_ok_and(|current| constant_time_eq(current.as_bytes(), stored.as_bytes()))
+        };
+        let same_bios = matches(&metadata.bios_uuid, read_bios_uuid());
+        let same_sid = matches(&metadata.machine_sid, read_machine_sid());
+        let same_domain = matches(&metadata.domain, read_machine_domain());
+        if !same_host && same_mac && same_bios && same_sid && same_domain && ignore_hostname {
+            println!(
+                "Hostname changed from {} to {}, ignoring it as requested",
+                metadata.hostname, current_hostname
+            );
+            hostname_ignored = true;
+        } else if !(same_mac & same_host & same_bios & same_sid & same_domain) {
+            return Err("This file is bound to a different machine".to_string());
+        }
+    }
+
+    // Recreate the computer_info string that was used for encryption
+    let computer_info = get_computer_info(
+        &metadata.mac,
+        &metadata.bios_uuid,
+        &metadata.machine_sid,
+        &metadata.hostname,
+        &metadata.domain,
+    );
+    if metadata.binding.uses_machine() {
+        println!("Extracted MAC: {}", metadata.mac);
+        println!("Extracted hostname: {}", metadata.hostname);
+    }
+    println!("Key derivation: {}", metadata.kdf.as_str());
+
+    // Generate the same key and IV
+    let (key, iv) = derive_key_iv(&metadata, &computer_info, passphrase.as_deref())?;
+    println!(
+        "Generated key length: {}, IV length: {}",
+        key.len(),
+        iv.len()
+    );
+
+    let mut result = decrypt_payload(&metadata, actual_encrypted_data, &key, &iv)?;
+    result.hostname_ignored = hostname_ignored;
+    result.matched_interface = matched_interface;
+    Ok(result)
+}
+
+// Function to get this machine's value for a file's MAC field, from the same
+// source the file was bound with
+fn current_machine_mac(metadata: &FileMetadata) -> Result<String, String> {
+    if metadata.mac_source != MachineIdSource::Mac {
+        return get_machine_id_mac(metadata.mac_source);
+    }
+
+    // Files from before the machine identifier fallback stored the shared
+    // fallback MAC when no adapter was found; they still match such a machine
+    let selection = detect_mac();
+    if selection.source != MachineIdSource::Mac && metadata.mac == FALLBACK_MAC {
+        return Ok(FALLBACK_MAC.to_string());
+    }
+    Ok(selection.mac)
+}
+
+// Function to check the adapter a file is bound to is on this machine; for
+// an any-MAC file one of its adapters is enough
+fn bound_mac_present(metadata: &FileMetadata) -> bool {
+    if metadata.protection == Protection::AnyMac {
+        let interfaces = detect_mac().interfaces;
+        return interfaces.iter().any(|interface| {
+            let id = mac_key_id(&metadata.salt, &interface.mac);
+            metadata
+                .mac_keys
+                .iter()
+                .any(|mac_key| constant_time_eq(&mac_key.id, &id))
+        });
+    }
+    current_machine_mac(metadata).is_ok_and(|current| same_mac(&metadata.mac, &current))
+}
+
+// Function to build an encrypted file from its metadata and ciphertext
+//
+// Layout: 4-byte little-endian metadata length, metadata, ciphertext.
+fn assemble_encrypted_file(metadata: &str, encrypted_data: &[u8]) -> Vec<u8> {
+    let metadata_bytes = metadata.as_bytes();
+    let metadata_len_bytes = (metadata_bytes.len() as u32).to_le_bytes();
+
+    let mut final_data = Vec::with_capacity(4 + metadata_bytes.len() + encrypted_data.len());
+    final_data.extend_from_slice(&metadata_len_bytes);
+    final_data.extend_from_slice(metadata_bytes);
+    final_data.extend_from_slice(encrypted_data);
+    final_data
+}
+
+// Function to split an encrypted file into its metadata and payload,
+// the inverse of assemble_encrypted_file
+fn split_encrypted_file(encrypted_data: &[u8]) -> Result<(FileMetadata, &[u8]), String> {
+    // File must be at least 4 bytes (for metadata length)
+    if encrypted_data.len() < 4 {
+        return Err("File is too small to contain valid data".to_string());
+    }
+
+    // Extract metadata length (first 4 bytes)
+    let metadata_len = u32::from_le_bytes([
+        encrypted_data[0],
+        encrypted_data[1],
+        encrypted_data[2],
+        encrypted_data[3],
+    ]) as usize;
+
+    println!("Metadata length: {} bytes", metadata_len);
+
+    // Real metadata is a few hundred bytes; anything near u32::MAX is a
+    // corrupt or crafted header, reject it before using the length
+    if metadata_len > MAX_METADATA_LEN {
+        return Err(format!(
+            "Invalid metadata: length {} exceeds the maximum of {} bytes",
+            metadata_len, MAX_METADATA_LEN
+        ));
+    }
+
+    // Validate metadata length
+    if encrypted_data.len() < 4 + metadata_len {
+        return Err("File is too small to contain complete metadata".to_string());
+    }
+
+    // Extract metadata
+    let metadata_str = match String::from_utf8(encrypted_data[4..4 + metadata_len].to_vec()) {
+        Ok(s) => s,
+        Err(_) => return Err("Invalid metadata encoding".to_string()),
+    };
+
+    println!("Metadata: {}", metadata_str);
+
+    // Parse metadata to extract binding, MAC address, hostname, and key char
+    let metadata = FileMetadata::parse(&metadata_str)?;
+
+    Ok((metadata, &encrypted_data[4 + metadata_len..]))
+}
+
+// Function to decrypt the payload of a file and return it as a JSON string
+fn decrypt_payload(
+    metadata: &FileMetadata,
+    actual_encrypted_data: &[u8],
+    key: &[u8],
+    iv: &[u8],
+) -> Result<DecryptionResult, String> {
+    println!(
+        "Actual encrypted data size: {} bytes",
+        actual_encrypted_data.len()
+    );
+
+    // Decrypt the data
+    let decrypted_data = match decrypt_data(
+        actual_encrypted_data,
+        key,
+        iv,
+        metadata.key_size,
+        metadata.cipher_mode,
+    ) {
+        Ok(data) => data,
+        Err(e) => return Err(format!("Decryption error: {}", e)),
+    };
+
+    println!("Decryption successful, got {} bytes", decrypted_data.len());
+
+    // Files from before empty input was rejected can hold nothing, which
+    // must not reach the frontend as a successfully loaded config
+    if decrypted_data.iter().all(u8::is_ascii_whitespace) {
+        return Err("Empty input: the file decrypted to no content".to_string());
+    }
+
+    // CTR has no padding check to reject a wrong key, so garbage must not
+    // reach the frontend as a config
+    if metadata.cipher_mode == CipherMode::Ctr
+        && !std::str::from_utf8(&decrypted_data).is_ok_and(|json| parse_json(json).is_ok())
+    {
+        return Err("Decryption error: wrong key or corrupt data".to_string());
+    }
+
+    // Convert decrypted bytes to string
+    match String::from_utf8(decrypted_data) {
+        Ok(json_string) => {
+            println!("Successfully converted decrypted data to JSON string");
+            Ok(DecryptionResult {
+                success: true,
+                message: "Decryption successful".to_string(),
+                json_data: json_string,
+                canonical: metadata.canonical,
+                hostname_ignored: false,
+                matched_interface: None,
+                tag: metadata.tag.clone(),
+            })
+        }
+        Err(e) => Err(format!("Failed to convert decrypted data to string: {}", e)),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RebindResult {
+    success: bool,
+    message: String,
+    file_path: String,
+    backup_path: String,
+    binding: String,
+}
+
+// Command to turn a machine-bound config into a passphrase-bound one, e.g.
+// before moving the connector into a VM. The file is decrypted with this
+// machine's binding, the original kept as "<name>.bak.rebind", and the
+// content re-encrypted in place with the passphrase.
+#[tauri::command]
+pub async fn rebind_to_passphrase(
+    app_handle: AppHandle,
+    file_path: Option<String>,
+    passphrase: String,
+    profile: Option<String>,
+) -> Result<RebindResult, String> {
+    let target_path = config_target_path(file_path, profile.as_deref())?;
+    // Held until the rebound file is in place, so no write lands in between
+    let _lock = lock_config(&target_path).await?;
+    let encrypted_data = tokio::fs::read(&target_path)
+        .await
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+
+    let (metadata, _) = split_encrypted_file(&encrypted_data)?;
+    if metadata.binding != Binding::Machine {
+        return Err(format!(
+            "{} uses '{}' binding, only machine-bound files can be rebound",
+            target_path,
+            metadata.binding.as_str()
+        ));
+    }
+
+    let decrypted = decrypt_file_contents(&encrypted_data, None, None, false).map_err(|e| {
+        format!(
+            "This machine can't decrypt {}, so it can't be rebound: {}",
+            target_path, e
+        )
+    })?;
+
+    let backup_path = format!("{}.bak.rebind", target_path);
+    write_locked(&encrypted_data, &backup_path, false)
+        .await
+        .map_err(|e| format!("Failed to back up {}: {}", target_path, e))?;
+
+    // Keep the stored form: canonical content stays canonical, exact bytes
+    // stay exact. The blob is written here, under the lock already held.
+    let result = encrypt_json_inner(
+        Some(app_handle),
+        decrypted.json_data,
+        EncryptOptions {
+            passphrase: Some(passphrase),
+            binding_mode: Some(Binding::Passphrase.as_str().to_string()),
+            return_blob: Some(true),
+            canonicalize: Some(decrypted.canonical),
+            tag: decrypted.tag,
+            ..Default::default()
+        },
+        &mut OsRng,
+    )
+    .await?;
+    let rebound = BASE64
+        .decode(result.data_base64.unwrap_or_default())
+        .map_err(|e| format!("Invalid base64 data: {}", e))?;
+
+    write_locked(&rebound, &target_path, false).await?;
+    if let Err(e) = append_audit_record("rebind", &target_path, &rebound) {
+        println!("Failed to append audit record: {}", e);
+    }
+
+    Ok(RebindResult {
+        success: result.success,
+        message: format!(
+            "{} rebound to passphrase binding, original kept at {}",
+            target_path, backup_path
+        ),
+        file_path: target_path,
+        backup_path,
+        binding: Binding::Passphrase.as_str().to_string(),
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IvMigrationResult {
+    // False when the file already had a random IV and was left untouched
+    migrated: bool,
+    message: String,
+    file_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup_path: Option<String>,
+}
+
+// Command to check whether a config still uses the IV derived from its key
+// material instead of a random one stored in the header
+#[tauri::command]
+pub async fn needs_iv_migration(
+    _app_handle: AppHandle,
+    file_path: Option<String>,
+    profile: Option<String>,
+) -> Result<bool, String> {
+    let target_path = config_target_path(file_path, profile.as_deref())?;
+    let encrypted_data = read_locked(&target_path).await?;
+
+    let (metadata, _) = split_encrypted_file(&encrypted_data)?;
+    Ok(metadata.iv.is_empty())
+}
+
+// Command to re-encrypt a config written before IVs were stored with a
+// random IV. The binding is kept as it is; legacy padded derivation is
+// upgraded to HKDF, with a fresh salt for machine-bound files. The file must
+// decrypt on this machine, and the new contents must decrypt back to the
+// same JSON, before anything is written. The original is kept as
+// "<name>.bak.iv".
+#[tauri::command]
+pub async fn migrate_to_random_iv(
+    _app_handle: AppHandle,
+    file_path: Option<String>,
+    char_key: Option<String>,
+    passphrase: Option<String>,
+    profile: Option<String>,
+) -> Result<IvMigrationResult, String> {
+    migrate_to_random_iv_inner(file_path, char_key, passphrase, profile, &mut OsRng).await
+}
+
+// Function behind migrate_to_random_iv. The new salt and IV come from rng,
+// as in encrypt_json_inner; production callers must pass OsRng.
+async fn migrate_to_random_iv_inner(
+    file_path: Option<String>,
+    char_key: Option<String>,
+    passphrase: Option<String>,
+    profile: Option<String>,
+    rng: &mut (impl RngCore + CryptoRng + Send),
+) -> Result<IvMigrationResult, String> {
+    let target_path = config_target_path(file_path, profile.as_deref())?;
+    let _lock = lock_config(&target_path).await?;
+    let encrypted_data = tokio::fs::read(&target_path)
+        .await
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+
+    let (mut metadata, _) = split_encrypted_file(&encrypted_data)?;
+    if !metadata.iv.is_empty() {
+        return Ok(IvMigrationResult {
+            migrated: false,
+            message: format!("{} already uses a random IV", target_path),
+            file_path: target_path,
+            backup_path: None,
+        });
+    }
+    // The escrowed recovery secret includes the IV, so the admin share
+    // would stop matching
+    if !metadata.recovery_id.is_empty() {
+        return Err(format!(
+            "{} has a recovery share, re-create it with encrypt_json to get a random IV and a new share",
+            target_path
+        ));
+    }
+
+    let decrypted =
+        decrypt_file_contents(&encrypted_data, char_key.clone(), passphrase.clone(), false)
+            .map_err(|e| {
+                format!(
+                    "This machine can't decrypt {}, so it can't be migrated: {}",
+                    target_path, e
+                )
+            })?;
+
+    // Record the key char the file was opened with, it no longer has to be passed
+    if metadata.key_char.is_none() {
+        metadata.key_char = Some(resolve_key_char(char_key.clone()));
+    }
+    if metadata.protection == Protection::None && metadata.kdf == Kdf::Legacy {
+        metadata.kdf = Kdf::HkdfSha256;
+        if metadata.binding == Binding::Machine {
+            metadata.salt = generate_salt(rng)?;
+        }
+    }
+    metadata.iv = vec![0u8; IV_LEN];
+    rng.try_fill_bytes(&mut metadata.iv)
+        .map_err(|e| format!("Failed to generate IV: {}", e))?;
+
+    let computer_info = get_computer_info(
+        &metadata.mac,
+        &metadata.bios_uuid,
+        &metadata.machine_sid,
+        &metadata.hostname,
+        &metadata.domain,
+    );
+    let (key, iv) = derive_key_iv(&metadata, &computer_info, passphrase.as_deref())?;
+    let encrypted = encrypt_data(
+        decrypted.json_data.as_bytes(),
+        &key,
+        &iv,
+        metadata.key_size,
+        metadata.cipher_mode,
+    )?;
+    let migrated = assemble_encrypted_file(&metadata.encode(), &encrypted);
+
+    let verified = decrypt_file_contents(&migrated, char_key, passphrase, false)?;
+    if verified.json_data != decrypted.json_data {
+        return Err("Migrated file doesn't decrypt to the original content".to_string());
+    }
+
+    let backup_path = format!("{}.bak.iv", target_path);
+    write_locked(&encrypted_data, &backup_path, false)
+        .await
+        .map_err(|e| format!("Failed to back up {}: {}", target_path, e))?;
+
+    write_locked(&migrated, &target_path, false).await?;
+    if let Err(e) = append_audit_record("migrate-iv", &target_path, &migrated) {
+        println!("Failed to append audit record: {}", e);
+    }
+
+    println!("Migrated {} to a random IV", target_path);
+    Ok(IvMigrationResult {
+        migrated: true,
+        message: format!(
+            "{} re-encrypted with a random IV, original kept at {}",
+            target_path, backup_path
+        ),
+        file_path: target_path,
+        backup_path: Some(backup_path),
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportResult {
+    success: bool,
+    message: String,
+    source_path: String,
+    file_path: String,
+    source_size: u64,
+    encrypted_size: u64,
+}
+
+// Command to encrypt a plaintext JSON config from disk, so the frontend
+// doesn't have to read it and pass it through encrypt_json as a string.
+// With validate_schema the content must also be a complete connector config.
+#[tauri::command]
+pub async fn import_and_encrypt(
+    app_handle: AppHandle,
+    source_path: String,
+    output_path: Option<String>,
+    char_key: Option<String>,
+    validate_schema: Option<bool>,
+) -> Result<ImportResult, String> {
+    let source = tokio::fs::read_to_string(&source_path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", source_path, e))?;
+    let source_size = source.len() as u64;
+
+    // Editors on Windows like to save JSON with a byte order mark
+    let json_data = source.trim_start_matches('\u{feff}').to_string();
+    if validate_schema.unwrap_or(false) {
+        validate_connector_config(&json_data)?;
+    }
+
+    let result = encrypt_json_inner(
+        Some(app_handle),
+        json_data,
+        EncryptOptions {
+            output_path,
+            char_key,
+            ..Default::default()
+        },
+        &mut OsRng,
+    )
+    .await?;
+
+    let encrypted_size = tokio::fs::metadata(&result.file_path)
+        .await
+        .map(|metadata| metadata.len())
+        .map_err(|e| format!("Failed to read {}: {}", result.file_path, e))?;
+    println!(
+        "Imported {} ({} bytes) into {} ({} bytes)",
+        source_path, source_size, result.file_path, encrypted_size
+    );
+
+    Ok(ImportResult {
+        success: result.success,
+        message: result.message,
+        source_path,
+        file_path: result.file_path,
+        source_size,
+        encrypted_size,
+    })
+}
+
+// Command to decrypt a file with its header share and an admin recovery share
+#[tauri::command]
+pub async fn recover_with_share(
+    _app_handle: AppHandle,
+    file_path: Option<String>,
+    share_path: String,
+    profile: Option<String>,
+) -> Result<DecryptionResult, String> {
+    // Determine input path
+    let input_path = match file_path {
+        Some(path) => path,
+        None => {
+            let mut config_path = get_config_dir();
+            config_path.push(config_file_name(profile.as_deref())?);
+            config_path.to_string_lossy().to_string()
+        }
+    };
+
+    println!(
+        "Attempting recovery of {} with share {}",
+        input_path, share_path
+    );
+
+    let encrypted_data = read_locked(&input_path).await?;
+    let share_contents = tokio::fs::read_to_string(&share_path)
+        .await
+        .map_err(|e| format!("Failed to read recovery share: {}", e))?;
+
+    let (metadata, actual_encrypted_data) = split_encrypted_file(&encrypted_data)?;
+    let (recovery_id, admin_share) = parse_recovery_share(&share_contents)?;
+
+    if metadata.recovery_id.is_empty() {
+        return Err("This file was not encrypted with a recovery share".to_string());
+    }
+    if !constant_time_eq(&recovery_id, &metadata.recovery_id) {
+        return Err("Recovery share belongs to a different config file".to_string());
+    }
+
+    let (key, iv) = combine_recovery_shares(&metadata, &admin_share)?;
+    decrypt_payload(&metadata, actual_encrypted_data, &key, &iv)
+}
+
+// Command to decrypt an escrowed file off its machine with the recovery
+// private key (PEM), e.g. after the machine died
+#[tauri::command]
+pub async fn recover_with_key(
+    _app_handle: AppHandle,
+    file_path: String,
+    recovery_private_key: String,
+) -> Result<DecryptionResult, String> {
+    println!("Attempting recovery of {} with a recovery key", file_path);
+
+    let encrypted_data = read_locked(&file_path).await?;
+    let (metadata, actual_encrypted_data) = split_encrypted_file(&encrypted_data)?;
+    if metadata.escrow_key.is_empty() {
+        return Err("This file was not encrypted with recovery key escrow".to_string());
+    }
+
+    let private_key = parse_private_key(&recovery_private_key)?;
+    if !constant_time_eq(
+        &escrow_key_id(&private_key.to_public_key())?,
+        &metadata.escrow_id,
+    ) {
+        return Err("Recovery key doesn't match the key this file was escrowed to".to_string());
+    }
+    let data_key = escrow_unwrap(&private_key, &metadata.escrow_key)?;
+    decrypt_payload(&metadata, actual_encrypted_data, &data_key, &metadata.iv)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryRewrapResult {
+    success: bool,
+    message: String,
+    file_path: String,
+    escrow_id: String,
+}
+
+// Command to move an escrowed file to a new recovery key without decrypting
+// and re-encrypting it: the data key is unwrapped with the old private key
+// and wrapped again for the new public key. The machine-wrapped copy and the
+// ciphertext are written back unchanged.
+#[tauri::command]
+pub async fn rewrap_recovery_key(
+    _app_handle: AppHandle,
+    file_path: String,
+    old_recovery_private_key: String,
+    new_recovery_public_key: String,
+) -> Result<RecoveryRewrapResult, String> {
+    println!("Rewrapping the recovery key of {}", file_path);
+
+    // Held from the read to the write so a concurrent save isn't overwritten
+    // with the old payload
+    let _lock = lock_config(&file_path).await?;
+    let encrypted_data = tokio::fs::read(&file_path)
+        .await
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    let (mut metadata, actual_encrypted_data) = split_encrypted_file(&encrypted_data)?;
+    if metadata.escrow_key.is_empty() {
+        return Err("This file was not encrypted with recovery key escrow".to_string());
+    }
+
+    let old_private_key = parse_private_key(&old_recovery_private_key)?;
+    if !constant_time_eq(
+        &escrow_key_id(&old_private_key.to_public_key())?,
+        &metadata.escrow_id,
+    ) {
+        return Err("Recovery key doesn't match the key this file was escrowed to".to_string());
+    }
+    let new_public_key = parse_public_key(&new_recovery_public_key)?;
+
+    // The unwrapped key must open the payload before the old wrap is replaced,
+    // otherwise the file would lose its recovery path
+    let data_key = escrow_unwrap(&old_private_key, &metadata.escrow_key)?;
+    decrypt_payload(&metadata, actual_encrypted_data, &data_key, &metadata.iv)
+        .map_err(|e| format!("Unwrapped data key doesn't decrypt the file: {}", e))?;
+
+    metadata.escrow_id = escrow_key_id(&new_public_key)?;
+    metadata.escrow_key = escrow_wrap(&mut OsRng, &new_public_key, &data_key)?;
+    let rewrapped = assemble_encrypted_file(&metadata.encode(), actual_encrypted_data);
+
+    write_locked(&rewrapped, &file_path, false).await?;
+    if let Err(e) = append_audit_record("rewrap-recovery", &file_path, &rewrapped) {
+        println!("Failed to append audit record: {}", e);
+    }
+
+    let escrow_id = hex::encode(&metadata.escrow_id);
+    println!("{} escrowed to recovery key {}", file_path, escrow_id);
+    Ok(RecoveryRewrapResult {
+        success: true,
+        message: format!(
+            "{} is now escrowed to recovery key {}",
+            file_path, escrow_id
+        ),
+        file_path,
+        escrow_id,
+    })
+}
+
+// Outcome of comparing a config file with its hash sidecar
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TamperStatus {
+    Tampered,
+    Intact,
+    // No sidecar was written for the file
+    NoReference,
+}
+
+// Command to check a config file against the hash recorded when it was
+// written. Needs no key, so it works for any binding or protection.
+#[tauri::command]
+pub async fn check_tamper(
+    _app_handle: AppHandle,
+    file_path: Option<String>,
+    profile: Option<String>,
+) -> Result<TamperStatus, String> {
+    let file_path = match file_path {
+        Some(path) => path,
+        None => {
+            let mut config_path = get_config_dir();
+            config_path.push(config_file_name(profile.as_deref())?);
+            config_path.to_string_lossy().to_string()
+        }
+    };
+
+    // The sidecar is rewritten with the file, under the same lock
+    let _lock = lock_config_shared(&file_path).await?;
+    let reference = match tokio::fs::read_to_string(hash_sidecar_path(&file_path)).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(TamperStatus::NoReference),
+        Err(e) => return Err(format!("Failed to read hash sidecar: {}", e)),
+    };
+    let expected = reference
+        .split_whitespace()
+        .next()
+        .and_then(|digest| hex::decode(digest).ok())
+        .ok_or_else(|| "Hash sidecar is corrupt".to_string())?;
+
+    let data = match tokio::fs::read(&file_path).await {
+        Ok(data) => data,
+        // A sidecar without its file means the file was removed or renamed
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(TamperStatus::Tampered),
+        Err(e) => return Err(format!("Failed to read file: {}", e)),
+    };
+
+    if constant_time_eq(&Sha256::digest(&data), &expected) {
+        Ok(TamperStatus::Intact)
+    } else {
+        println!("Hash of {} doesn't match its sidecar", file_path);
+        Ok(TamperStatus::Tampered)
+    }
+}
+
+// One machine-binding field of a file compared with this machine
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BindingFieldCheck {
+    field: String,
+    file_value: Option<String>,
+    current_value: Option<String>,
+    // None when the file doesn't record the field
+    matches: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BindingDiagnosis {
+    binding: String,
+    protection: String,
+    fields: Vec<BindingFieldCheck>,
+    remediation: Vec<String>,
+}
+
+// Command to tell which part of a machine binding no longer matches. Only
+// the header is read: nothing is decrypted and no key is derived.
+#[tauri::command]
+pub async fn diagnose_binding(
+    _app_handle: AppHandle,
+    file_path: String,
+    char_key: Option<String>,
+) -> Result<BindingDiagnosis, String> {
+    let contents = read_locked(&file_path).await?;
+    let (metadata, _) = split_encrypted_file(&contents)?;
+
+    let mut diagnosis = BindingDiagnosis {
+        binding: metadata.binding.as_str().to_string(),
+        protection: metadata.protection.as_str().to_string(),
+        fields: Vec::new(),
+        remediation: Vec::new(),
+    };
+    if metadata.org_secret_applied && ORG_SECRET.is_none() {
+        diagnosis.remediation.push(
+            "The file was encrypted with the organization secret - open it with the BTic distribution build"
+                .to_string(),
+        );
+    }
+    if metadata.protection != Protection::None {
+        diagnosis.remediation.push(format!(
+            "The key is sealed with {} on the machine that wrote the file - it can only be opened there or with a recovery share",
+            metadata.protection.as_str()
+        ));
+        return Ok(diagnosis);
+    }
+    if !metadata.binding.uses_machine() {
+        diagnosis
+            .remediation
+            .push("The file is not machine-bound - check the passphrase".to_string());
+        return Ok(diagnosis);
+    }
+
+    let current = tokio::task::spawn_blocking(move || {
+        let mac = current_machine_mac(&metadata);
+        let bios_uuid = (!metadata.bios_uuid.is_empty()).then(read_bios_uuid);
+        let machine_sid = (!metadata.machine_sid.is_empty()).then(read_machine_sid);
+        let domain = (!metadata.domain.is_empty()).then(read_machine_domain);
+        (metadata, mac, bios_uuid, machine_sid, domain)
+    })
+    .await
+    .map_err(|e| format!("Machine detection failed: {}", e))?;
+    let (metadata, current_mac, current_bios_uuid, current_machine_sid, current_domain) = current;
+
+    let current_mac = current_mac.ok();
+    let mac_matches = current_mac
+        .as_deref()
+        .is_some_and(|current| same_mac(&metadata.mac, current));
+    diagnosis.fields.push(BindingFieldCheck {
+        field: "MAC".to_string(),
+        file_value: Some(metadata.mac.clone()),
+        current_value: current_mac,
+        matches: Some(mac_matches),
+    });
+    if !mac_matches {
+        diagnosis.remediation.push(format!(
+            "MAC changed ({} identifier) - pick the original adapter with list_network_interfaces if it is still installed, otherwise re-encrypt the config on this machine",
+            metadata.mac_source.as_str()
+        ));
+    }
+
+    let current_hostname = get_hostname_for_metadata();
+    let hostname_matches = same_hostname(&metadata.hostname, &current_hostname);
+    diagnosis.fields.push(BindingFieldCheck {
+        field: "HOST".to_string(),
+        file_value: Some(metadata.hostname.clone()),
+        current_value: Some(current_hostname),
+        matches: Some(hostname_matches),
+    });
+    if !hostname_matches {
+        diagnosis.remediation.push(format!(
+            "Hostname changed - rename the computer back to {} or re-encrypt the config on this machine",
+            metadata.hostname
+        ));
+    }
+
+    if let Some(current_bios_uuid) = current_bios_uuid {
+        let current_bios_uuid = current_bios_uuid.ok();
+        let bios_matches = current_bios_uuid.as_deref() == Some(metadata.bios_uuid.as_str());
+        diagnosis.fields.push(BindingFieldCheck {
+            field: "BIOSUUID".to_string(),
+            file_value: Some(metadata.bios_uuid.clone()),
+            current_value: current_bios_uuid,
+            matches: Some(bios_matches),
+        });
+        if !bios_matches {
+            diagnosis.remediation.push(
+                "BIOS UUID changed - the file comes from another computer or the motherboard was replaced; re-encrypt the config on this machine"
+                    .to_string(),
+            );
+        }
+    }
+
+    if let Some(current_machine_sid) = current_machine_sid {
+        let current_machine_sid = current_machine_sid.ok();
+        let sid_matches = current_machine_sid.as_deref() == Some(metadata.machine_sid.as_str());
+        diagnosis.fields.push(BindingFieldCheck {
+            field: "SID".to_string(),
+            file_value: Some(metadata.machine_sid.clone()),
+            current_value: current_machine_sid,
+            matches: Some(sid_matches),
+        });
+        if !sid_matches {
+            diagnosis.remediation.push(
+                "Machine SID changed - Windows was reinstalled or the image was sysprepped; re-encrypt the config on this machine"
+                    .to_string(),
+            );
+        }
+    }
+
+    if let Some(current_domain) = current_domain {
+        let current_domain = current_domain.ok();
+        let domain_matches = current_domain.as_deref() == Some(metadata.domain.as_str());
+        diagnosis.fields.push(BindingFieldCheck {
+            field: "DOMAIN".to_string(),
+            file_value: Some(metadata.domain.clone()),
+            current_value: current_domain,
+            matches: Some(domain_matches),
+        });
+        if !domain_matches {
+            diagnosis.remediation.push(format!(
+                "Domain changed - the machine was joined to another domain or a workgroup; rejoin {} or re-encrypt the config on this machine",
+                metadata.domain
+            ));
+        }
+    }
+
+    // Files written before KEY_CHAR was recorded use the caller's key char,
+    // which can only be confirmed by trying it
+    let current_key_char = resolve_key_char(char_key).to_string();
+    let file_key_char = metadata.key_char.map(|c| c.to_string());
+    let key_char_matches = file_key_char.as_ref().map(|c| *c == current_key_char);
+    diagnosis.fields.push(BindingFieldCheck {
+        field: "KEY_CHAR".to_string(),
+        file_value: file_key_char,
+        current_value: Some(current_key_char),
+        matches: key_char_matches,
+    });
+    if key_char_matches.is_none() {
+        diagnosis.remediation.push(
+            "The key char isn't recorded in this file - find it with probe_key_char".to_string(),
+        );
+    }
+
+    if diagnosis.remediation.is_empty() {
+        diagnosis.remediation.push(
+            "The binding matches this machine - if decryption still fails the file is damaged; try repair_config or a backup"
+                .to_string(),
+        );
+    }
+    Ok(diagnosis)
+}
+
+// State of the default config file, read from its header without decrypting
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ConfigStatus {
+    exists: bool,
+    // The file could be read and has a valid header
+    readable: bool,
+    // The MAC and hostname in the header are this machine's, or the file
+    // is not machine-bound
+    metadata_matches_machine: bool,
+    size: u64,
+}
+
+// The config is machine-wide, so the username existing callers pass is not
+// used; config_status describes the file in more detail
+#[tauri::command]
+pub async fn config_exists(
+    _app_handle: AppHandle,
+    _username: String,
+    profile: Option<String>,
+) -> Result<bool, String> {
+    // Check in the ProgramData directory
+    let mut config_path = get_config_dir();
+    config_path.push(config_file_name(profile.as_deref())?);
+
+    Ok(tokio::fs::try_exists(&config_path).await.unwrap_or(false))
+}
+
+// Command to describe the default config (or a profile's) from its header:
+// whether it can be read and is bound to this machine
+#[tauri::command]
+pub async fn config_status(
+    _app_handle: AppHandle,
+    profile: Option<String>,
+) -> Result<ConfigStatus, String> {
+    let mut config_path = get_config_dir();
+    config_path.push(config_file_name(profile.as_deref())?);
+
+    let contents = match tokio::fs::read(&config_path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ConfigStatus::default()),
+        Err(e) => {
+            println!("Failed to read config file: {}", e);
+            return Ok(ConfigStatus {
+                exists: true,
+                ..ConfigStatus::default()
+            });
+        }
+    };
+    let size = contents.len() as u64;
+
+    let metadata = match split_encrypted_file(&contents) {
+        Ok((metadata, _)) => metadata,
+        Err(e) => {
+            println!("Config file has an invalid header: {}", e);
+            return Ok(ConfigStatus {
+                exists: true,
+                size,
+                ..ConfigStatus::default()
+            });
+        }
+    };
+
+    // Matching the MAC runs adapter detection
+    let matches = tokio::task::spawn_blocking(move || metadata_matches_machine(&metadata))
+        .await
+        .map_err(|e| format!("MAC detection failed: {}", e))?;
+
+    Ok(ConfigStatus {
+        exists: true,
+        readable: true,
+        metadata_matches_machine: matches,
+        size,
+    })
+}
+
+// Function to check the MAC and hostname in a header are this machine's;
+// files that aren't machine-bound match anywhere
+fn metadata_matches_machine(metadata: &FileMetadata) -> bool {
+    !metadata.binding.uses_machine()
+        || (bound_mac_present(metadata)
+            && same_hostname(&metadata.hostname, &get_hostname_for_metadata()))
+}
+
+// Function to get the config file name for a profile: "config" without one,
+// "config.<profile>" with one. Names are restricted so a profile can't
+// escape the config dir or pass for a backup ("config.bak*") or for the
+// leftovers of a write ("config.tmp-*") or its lock file ("config.lock").
+fn config_file_name(profile: Option<&str>) -> Result<String, String> {
+    let Some(profile) = profile else {
+        return Ok("config".to_string());
+    };
+
+    let valid = !profile.is_empty()
+        && profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !profile.starts_with("bak")
+        && profile != "tmp"
+        && !profile.starts_with("tmp-")
+        && profile != "lock";
+    if !valid {
+        return Err(format!(
+            "Invalid profile name '{}': use letters, digits, '-' and '_'",
+            profile
+        ));
+    }
+    Ok(format!("config.{}", profile))
+}
+
+// Command to get the absolute path a profile's config is read from and
+// written to, so the frontend doesn't rebuild it. The file need not exist;
+// the username is accepted like config_exists does but files aren't per user.
+#[tauri::command]
+pub async fn resolve_config_path(
+    profile: Option<String>,
+    _username: Option<String>,
+) -> Result<String, String> {
+    let mut config_path = get_config_dir();
+    config_path.push(config_file_name(profile.as_deref())?);
+    Ok(config_path.to_string_lossy().to_string())
+}
+
+// Command to list the profiles with a config file in the config directory
+#[tauri::command]
+pub async fn list_profiles(_app_handle: AppHandle) -> Result<Vec<String>, String> {
+    let config_dir = get_config_dir();
+    if !config_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let entries =
+        fs::read_dir(&config_dir).map_err(|e| format!("Failed to read config directory: {}", e))?;
+    let mut profiles: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let profile = name.strip_prefix("config.")?.to_string();
+            config_file_name(Some(&profile)).ok().map(|_| profile)
+        })
+        .collect();
+
+    profiles.sort();
+    Ok(profiles)
+}
+
+// A config file found in the config directory, described from its header
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigFileInfo {
+    name: String,
+    // Set for "config.<profile>" files, the default "config" has none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    size: u64,
+    modified: Option<String>,
+    mac: String,
+    hostname: String,
+    binding: String,
+    protection: String,
+    // The header carries no version number; the key derivation and cipher
+    // mode are what tell file formats apart
+    kdf: String,
+    cipher_mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+}
+
+// Command to list the encrypted configs in the config directory. Only the
+// plaintext header is read, so files bound to other machines are listed too;
+// anything without a valid header (audit log, recovery shares) is skipped.
+#[tauri::command]
+pub async fn list_configs(_app_handle: AppHandle) -> Result<Vec<ConfigFileInfo>, String> {
+    tokio::task::spawn_blocking(move || -> Result<Vec<ConfigFileInfo>, String> {
+        let config_dir = get_config_dir();
+        if !config_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let entries = fs::read_dir(&config_dir)
+            .map_err(|e| format!("Failed to read config directory: {}", e))?;
+
+        let mut configs = Vec::new();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Leftovers of an interrupted write and lock files are not configs
+            if !path.is_file()
+                || name.ends_with(".tmp")
+                || name.contains(".tmp-")
+                || name.ends_with(".lock")
+            {
+                continue;
+            }
+
+            let Ok(contents) = fs::read(&path) else {
+                continue;
+            };
+            let Ok((metadata, _)) = split_encrypted_file(&contents) else {
+                continue;
+            };
+
+            let modified = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .map(|time| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339());
+
+            let profile = name
+                .strip_prefix("config.")
+                .filter(|profile| config_file_name(Some(profile)).is_ok())
+                .map(|profile| profile.to_string());
+
+            configs.push(ConfigFileInfo {
+                name,
+                profile,
+                size: contents.len() as u64,
+                modified,
+                mac: metadata.mac,
+                hostname: metadata.hostname,
+                binding: metadata.binding.as_str().to_string(),
+                protection: metadata.protection.as_str().to_string(),
+                kdf: metadata.kdf.as_str().to_string(),
+                cipher_mode: metadata.cipher_mode.as_str().to_string(),
+                tag: metadata.tag,
+            });
+        }
+
+        configs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(configs)
+    })
+    .await
+    .map_err(|e| format!("Failed to list configs: {}", e))?
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteResult {
+    existed: bool,
+    deleted: bool,
+    backups_removed: Vec<String>,
+    // Where the config was moved to, unless it was securely erased
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moved_to: Option<String>,
+}
+
+// Deleted configs are moved here as "<name>.<UTC timestamp>" until
+// purge_deleted removes them
+const DELETED_DIR: &str = "deleted";
+const DELETED_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+// Command to delete a config file. By default it is moved into the
+// "deleted" folder of the config dir so a mistake can be undone; secure
+// overwrites it and removes it for good, so the ciphertext can't be
+// recovered from the disk. Only files inside the config dir are touched.
+#[tauri::command]
+pub async fn delete_config(
+    _app_handle: AppHandle,
+    file_path: Option<String>,
+    secure: bool,
+    remove_backups: Option<bool>,
+    profile: Option<String>,
+) -> Result<DeleteResult, String> {
+    tokio::task::spawn_blocking(move || -> Result<DeleteResult, String> {
+        let target_path = config_target_path(file_path, profile.as_deref())?;
+        let target = Path::new(&target_path);
+        // A mistyped profile must not take the backups of nothing with it
+        if !target.exists() {
+            return Ok(DeleteResult {
+                existed: false,
+                deleted: false,
+                backups_removed: Vec::new(),
+                moved_to: None,
+            });
+        }
+        ensure_in_config_dir(target)?;
+
+        let mut backups_removed = Vec::new();
+        if remove_backups.unwrap_or(false) {
+            for backup in find_backups(target)? {
+                discard_config_file(&backup, secure)?;
+                backups_removed.push(backup.to_string_lossy().to_string());
+            }
+        }
+
+        let contents = fs::read(target).map_err(|e| format!("Failed to read file: {}", e))?;
+        let moved_to = discard_config_file(target, secure)?;
+        match &moved_to {
+            Some(moved_to) => println!(
+                "Moved config file {} to {}",
+                target_path,
+                moved_to.display()
+            ),
+            None => println!("Deleted config file: {}", target_path),
+        }
+        // A sidecar left behind would report the deleted file as tampered
+        let _ = fs::remove_file(hash_sidecar_path(&target_path));
+
+        if let Err(e) = append_audit_record("delete", &target_path, &contents) {
+            println!("Failed to append audit record: {}", e);
+        }
+
+        Ok(DeleteResult {
+            existed: true,
+            deleted: true,
+            backups_removed,
+            moved_to: moved_to.map(|path| path.to_string_lossy().to_string()),
+        })
+    })
+    .await
+    .map_err(|e| format!("Failed to delete config: {}", e))?
+}
+
+// Command to remove configs that were moved to the "deleted" folder more
+// than older_than_days ago. Returns the files removed.
+#[tauri::command]
+pub async fn purge_deleted(
+    _app_handle: AppHandle,
+    older_than_days: u32,
+) -> Result<Vec<String>, String> {
+    tokio::task::spawn_blocking(move || -> Result<Vec<String>, String> {
+        let deleted_dir = get_config_dir().join(DELETED_DIR);
+        if !deleted_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let cutoff =
+            chrono::Utc::now().naive_utc() - chrono::Duration::days(older_than_days as i64);
+        let entries = fs::read_dir(&deleted_dir)
+            .map_err(|e| format!("Failed to read deleted configs: {}", e))?;
+
+        let mut purged = Vec::new();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            // Renaming keeps the modification time, so the age comes from the
+            // timestamp delete_config put in the name
+            let deleted_at = path.extension().and_then(|ext| {
+                chrono::NaiveDateTime::parse_from_str(
+                    &ext.to_string_lossy(),
+                    DELETED_TIMESTAMP_FORMAT,
+                )
+                .ok()
+            });
+            let Some(deleted_at) = deleted_at else {
+                continue;
+            };
+            if !path.is_file() || deleted_at > cutoff {
+                continue;
+            }
+
+            fs::remove_file(&path).map_err(|e| file_op_error("delete", &path, e))?;
+            purged.push(path.to_string_lossy().to_string());
+        }
+
+        println!("Purged {} deleted config files", purged.len());
+        purged.sort();
+        Ok(purged)
+    })
+    .await
+    .map_err(|e| format!("Failed to purge deleted configs: {}", e))?
+}
+
+// Function to refuse paths outside the config dir, e.g. an absolute path or
+// one climbing out with "..". The file itself may not exist, but its
+// directory must, since a path that can't be resolved can't be checked.
+fn ensure_in_config_dir(target: &Path) -> Result<(), String> {
+    let outside = || {
+        format!(
+            "{} is outside the config directory, only configs there can be deleted",
+            target.display()
+        )
+    };
+    let Some(parent) = target.parent() else {
+        return Err(outside());
+    };
+    let resolve = |path: &Path| {
+        path.canonicalize()
+            .map_err(|e| format!("Failed to resolve {}: {}", path.display(), e))
+    };
+    let config_dir = resolve(&get_config_dir())?;
+    let parent = resolve(parent)?;
+    if parent.starts_with(&config_dir) {
+        Ok(())
+    } else {
+        Err(outside())
+    }
+}
+
+// Function to get rid of a config file: erased when secure, otherwise moved
+// into the "deleted" folder, returning where it went
+fn discard_config_file(path: &Path, secure: bool) -> Result<Option<std::path::PathBuf>, String> {
+    if secure {
+        remove_config_file(path, true)?;
+        return Ok(None);
+    }
+
+    let deleted_dir = get_config_dir().join(DELETED_DIR);
+    fs::create_dir_all(&deleted_dir)
+        .map_err(|e| write_error("Failed to create directory", &deleted_dir, e))?;
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "config".to_string());
+    let timestamp = chrono::Utc::now().format(DELETED_TIMESTAMP_FORMAT);
+    let mut destination = deleted_dir.join(format!("{}.{}", file_name, timestamp));
+    // Two deletions within a second get a counter before the timestamp
+    let mut counter = 1;
+    while destination.exists() {
+        destination = deleted_dir.join(format!("{}.{}.{}", file_name, counter, timestamp));
+        counter += 1;
+    }
+
+    fs::rename(path, &destination).map_err(|e| file_op_error("move", path, e))?;
+    Ok(Some(destination))
+}
+
+// Function to describe a failed delete or move, with its own "InUse:" prefix
+// when the connector service holds the file so the frontend can say so
+fn file_op_error(operation: &str, path: &Path, e: std::io::Error) -> String {
+    if is_file_in_use(&e) {
+        format!(
+            "InUse: {} is in use by another process, stop the connector service and try again",
+            path.display()
+        )
+    } else {
+        format!("Failed to {} {}: {}", operation, path.display(), e)
+    }
+}
+
+// A backup of a config, "<name>.bak.<id>" next to it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupInfo {
+    // What wrote it: "rebind", "iv", "repair" or "restore"
+    id: String,
+    file_path: String,
+    size: u64,
+    // When the backup was written
+    modified: Option<String>,
+    // Whether its header could be read
+    readable: bool,
+    metadata_matches_machine: bool,
+}
+
+// Command to list the backups of a profile's config, newest first. Only
+// headers are read.
+#[tauri::command]
+pub async fn list_backups(
+    _app_handle: AppHandle,
+    profile: Option<String>,
+) -> Result<Vec<BackupInfo>, String> {
+    let target_path = config_target_path(None, profile.as_deref())?;
+    let prefix = format!("{}.bak.", target_path);
+
+    let mut backups = Vec::new();
+    for path in find_backups(Path::new(&target_path))? {
+        let file_path = path.to_string_lossy().to_string();
+        let Some(id) = file_path.strip_prefix(&prefix).map(|id| id.to_string()) else {
+            continue;
+        };
+        // A backup still being written, or one whose write failed
+        if id.contains(".tmp") {
+            continue;
+        }
+        let Ok(file_metadata) = fs::metadata(&path) else {
+            continue;
+        };
+        let modified = file_metadata
+            .modified()
+            .ok()
+            .map(|time| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339());
+        let header = fs::read(&path)
+            .ok()
+            .and_then(|contents| split_encrypted_file(&contents).ok().map(|(m, _)| m));
+
+        backups.push(BackupInfo {
+            id,
+            file_path,
+            size: file_metadata.len(),
+            modified,
+            readable: header.is_some(),
+            metadata_matches_machine: header.as_ref().is_some_and(metadata_matches_machine),
+        });
+    }
+
+    // RFC 3339 timestamps in UTC sort chronologically as strings
+    backups.sort_by(|a, b| b.modified.cmp(&a.modified).then_with(|| a.id.cmp(&b.id)));
+    Ok(backups)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoreResult {
+    success: bool,
+    message: String,
+    file_path: String,
+    // Where the replaced config was kept, if there was one
+    backup_path: Option<String>,
+}
+
+// Command to put a backup back in place of a profile's config. The backup
+// must decrypt on this machine, and one written for another machine is
+// refused unless forced. The current config is kept as "<name>.bak.restore".
+#[tauri::command]
+pub async fn restore_backup(
+    _app_handle: AppHandle,
+    profile: Option<String>,
+    backup_id: String,
+    char_key: Option<String>,
+    passphrase: Option<String>,
+    force: Option<bool>,
+) -> Result<RestoreResult, String> {
+    let target_path = config_target_path(None, profile.as_deref())?;
+    // The id names a sibling, it can't be a path
+    let valid_id = !backup_id.is_empty()
+        && backup_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_id {
+        return Err(format!("Invalid backup id '{}'", backup_id));
+    }
+    let backup_path = format!("{}.bak.{}", target_path, backup_id);
+
+    // The backups are written under the config's lock too
+    let _lock = lock_config(&target_path).await?;
+    let backup_data = match tokio::fs::read(&backup_path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!("No backup '{}' for {}", backup_id, target_path))
+        }
+        Err(e) => return Err(format!("Failed to read backup: {}", e)),
+    };
+    let (metadata, _) = split_encrypted_file(&backup_data)?;
+    if !force.unwrap_or(false) && !metadata_matches_machine(&metadata) {
+        return Err(format!(
+            "Backup '{}' was written for {} ({}), not this machine; pass force to restore it anyway",
+            backup_id, metadata.hostname, metadata.mac
+        ));
+    }
+    decrypt_file_contents(&backup_data, char_key, passphrase, false).map_err(|e| {
+        format!(
+            "Backup '{}' doesn't decrypt on this machine, it was not restored: {}",
+            backup_id, e
+        )
+    })?;
+
+    // Keep what is being replaced, unless it is the backup being restored
+    let mut replaced_path = None;
+    if let Ok(current) = tokio::fs::read(&target_path).await {
+        if backup_id != "restore" {
+            let path = format!("{}.bak.restore", target_path);
+            write_locked(&current, &path, false)
+                .await
+                .map_err(|e| format!("Failed to back up {}: {}", target_path, e))?;
+            replaced_path = Some(path);
+        }
+    }
+
+    write_locked(&backup_data, &target_path, false).await?;
+    if let Err(e) = append_audit_record("restore", &target_path, &backup_data) {
+        println!("Failed to append audit record: {}", e);
+    }
+
+    println!("Restored {} from {}", target_path, backup_path);
+    Ok(RestoreResult {
+        success: true,
+        message: match &replaced_path {
+            Some(path) => format!(
+                "{} restored from backup '{}', previous config kept at {}",
+                target_path, backup_id, path
+            ),
+            None => format!("{} restored from backup '{}'", target_path, backup_id),
+        },
+        file_path: target_path,
+        backup_path: replaced_path,
+    })
+}
+
+// Function to find the backups of a config file: "<name>.bak*" siblings
+fn find_backups(target: &Path) -> Result<Vec<std::path::PathBuf>, String> {
+    let (Some(parent), Some(file_name)) = (target.parent(), target.file_name()) else {
+        return Ok(Vec::new());
+    };
+    if !parent.exists() {
+        return Ok(Vec::new());
+    }
+
+    let prefix = format!("{}.bak", file_name.to_string_lossy());
+    let entries =
+        fs::read_dir(parent).map_err(|e| format!("Failed to read config directory: {}", e))?;
+    Ok(entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().starts_with(&prefix))
+                    .unwrap_or(false)
+        })
+        .collect())
+}
+
+// Function to remove a file, overwriting it with random bytes first if asked
+fn remove_config_file(path: &Path, secure: bool) -> Result<(), String> {
+    if secure {
+        overwrite_with_random(path)?;
+    }
+    fs::remove_file(path).map_err(|e| file_op_error("delete", path, e))
+}
+
+// Function to overwrite a file in place with random bytes and flush to disk
+fn overwrite_with_random(path: &Path) -> Result<(), String> {
+    use std::io::Write;
+
+    let len = fs::metadata(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+        .len() as usize;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+
+    let mut chunk = vec![0u8; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = remaining.min(chunk.len());
+        getrandom::getrandom(&mut chunk[..n])
+            .map_err(|e| format!("Failed to generate random data: {}", e))?;
+        file.write_all(&chunk[..n])
+            .map_err(|e| format!("Failed to overwrite {}: {}", path.display(), e))?;
+        remaining -= n;
+    }
+    file.sync_all()
+        .map_err(|e| format!("Failed to flush {}: {}", path.display(), e))
+}
+
+// What repair_config found in a damaged file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepairResult {
+    // The file already splits cleanly into metadata and payload
+    intact: bool,
+    // A single consistent metadata boundary was found
+    repairable: bool,
+    written: bool,
+    message: String,
+    recorded_metadata_len: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_offset: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_len: Option<usize>,
+    // The metadata keys present in the recovered header
+    found_keys: Vec<String>,
+}
+
+// Command to salvage a config whose metadata length field doesn't match its
+// metadata. The metadata is located by its known keys and its end by a ';'
+// after which the payload is a whole number of AES blocks. Nothing is written
+// unless write_fixed is set and exactly one boundary fits; the original is
+// kept as "<name>.bak.repair".
+#[tauri::command]
+pub async fn repair_config(
+    _app_handle: AppHandle,
+    file_path: String,
+    write_fixed: bool,
+) -> Result<RepairResult, String> {
+    // Only a repair writes, but the file must not change between the scan
+    // and the write
+    let _lock = if write_fixed {
+        lock_config(&file_path).await?
+    } else {
+        lock_config_shared(&file_path).await?
+    };
+    let data = tokio::fs::read(&file_path)
+        .await
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    let recorded_metadata_len =
+        (data.len() >= 4).then(|| u32::from_le_bytes([data[0], data[1], data[2], data[3]]));
+
+    let mut result = RepairResult {
+        intact: false,
+        repairable: false,
+        written: false,
+        message: String::new(),
+        recorded_metadata_len,
+        metadata_offset: None,
+        metadata_len: None,
+        found_keys: Vec::new(),
+    };
+
+    if split_encrypted_file(&data).is_ok() {
+        result.intact = true;
+        result.message = "The file's metadata is intact, nothing to repair".to_string();
+        return Ok(result);
+    }
+
+    // Metadata starts with one of the keys it can open with
+    let Some(offset) = METADATA_START_KEYS
+        .iter()
+        .filter_map(|key| find_bytes(&data, key.as_bytes()))
+        .min()
+    else {
+        result.message = "No metadata keys found in the file".to_string();
+        return Ok(result);
+    };
+    result.metadata_offset = Some(offset);
+
+    // Candidate ends are the ';' in the printable run after the start
+    let printable_end = data[offset..]
+        .iter()
+        .position(|b| !(0x20..0x7F).contains(b))
+        .map(|p| offset + p)
+        .unwrap_or(data.len());
+    let candidates: Vec<usize> = (offset..printable_end)
+        .filter(|&i| data[i] == b';')
+        .map(|i| i + 1)
+        .filter(|&end| {
+            let payload_len = data.len() - end;
+            payload_len > 0
+                && payload_len % 16 == 0
+                && std::str::from_utf8(&data[offset..end])
+                    .map(|metadata| FileMetadata::parse(metadata).is_ok())
+                    .unwrap_or(false)
+        })
+        .collect();
+
+    let end = match candidates.as_slice() {
+        [end] => *end,
+        [] => {
+            result.message =
+                "No metadata boundary leaves a whole number of encrypted blocks".to_string();
+            return Ok(result);
+        }
+        _ => {
+            result.message = format!(
+                "{} possible metadata boundaries found, not repairing",
+                candidates.len()
+            );
+            return Ok(result);
+        }
+    };
+
+    let metadata = String::from_utf8_lossy(&data[offset..end]).to_string();
+    result.repairable = true;
+    result.metadata_len = Some(end - offset);
+    result.found_keys = metadata
+        .split(';')
+        .filter_map(|part| part.split_once('=').map(|(key, _)| key.to_string()))
+        .collect();
+    result.message = format!(
+        "Metadata found at offset {} ({} bytes, length field says {:?})",
+        offset,
+        end - offset,
+        recorded_metadata_len
+    );
+
+    if write_fixed {
+        let backup_path = format!("{}.bak.repair", file_path);
+        write_locked(&data, &backup_path, false)
+            .await
+            .map_err(|e| format!("Failed to back up {}: {}", file_path, e))?;
+
+        let fixed = assemble_encrypted_file(&metadata, &data[end..]);
+        write_locked(&fixed, &file_path, false).await?;
+        if let Err(e) = append_audit_record("repair", &file_path, &fixed) {
+            println!("Failed to append audit record: {}", e);
+        }
+        result.written = true;
+        result.message = format!(
+            "{}; repaired, original kept at {}",
+            result.message, backup_path
+        );
+    }
+
+    println!("Repair of {}: {}", file_path, result.message);
+    Ok(result)
+}
+
+// Function to find the first occurrence of a byte string
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Command to compare two encrypted configs, e.g. a backup and the live file.
+// Both must decrypt on this machine, or with the given key char / passphrase.
+#[tauri::command]
+pub async fn config_diff(
+    _app_handle: AppHandle,
+    path_a: String,
+    path_b: String,
+    char_key: Option<String>,
+    passphrase: Option<String>,
+) -> Result<Vec<JsonDiffEntry>, String> {
+    let old = decrypt_to_value(&path_a, char_key.clone(), passphrase.clone()).await?;
+    let new = decrypt_to_value(&path_b, char_key, passphrase).await?;
+    Ok(diff_json(&old, &new))
+}
+
+// Function to decrypt a config file and parse its contents as JSON
+async fn decrypt_to_value(
+    file_path: &str,
+    char_key: Option<String>,
+    passphrase: Option<String>,
+) -> Result<serde_json::Value, String> {
+    let encrypted_data = read_locked(file_path).await?;
+    let decrypted = tokio::task::spawn_blocking(move || {
+        decrypt_file_contents(&encrypted_data, char_key, passphrase, false)
+    })
+    .await
+    .map_err(|e| format!("Decryption failed: {}", e))?
+    .map_err(|e| format!("Failed to decrypt {}: {}", file_path, e))?;
+    parse_json(&decrypted.json_data).map_err(|e| format!("{}: {}", file_path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    // Commands are async; the tests drive them on a current-thread runtime
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn interrupted() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Interrupted, "interrupted")
+    }
+
+    #[test]
+    fn retry_transient_succeeds_after_two_transient_failures() {
+        let attempts = AtomicU32::new(0);
+        let result = block_on(retry_transient(|| async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(interrupted()),
+                _ => Ok("written"),
+            }
+        }));
+        assert_eq!(result.unwrap(), "written");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn retry_transient_returns_the_last_error_when_every_attempt_fails() {
+        let attempts = AtomicU32::new(0);
+        let result: std::io::Result<()> = block_on(retry_transient(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(interrupted())
+        }));
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::Interrupted);
+        assert_eq!(attempts.load(Ordering::SeqCst), WRITE_ATTEMPTS);
+    }
+
+    #[test]
+    fn retry_transient_does_not_retry_permanent_errors() {
+        let attempts = AtomicU32::new(0);
+        let result: std::io::Result<()> = block_on(retry_transient(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        }));
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn write_retries_a_busy_temp_file_then_replaces_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("config").to_string_lossy().to_string();
+        fs::write(&file_path, b"old").unwrap();
+
+        let attempts = AtomicU32::new(0);
+        block_on(write_locked_with(b"new", &file_path, false, |temp_path| {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt < 2 {
+                    return Err(interrupted());
+                }
+                write_synced(&temp_path, b"new").await
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(fs::read(&file_path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn failed_write_keeps_the_config_and_removes_its_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("config").to_string_lossy().to_string();
+        fs::write(&file_path, b"old").unwrap();
+
+        let result = block_on(write_locked_with(b"new", &file_path, false, |_| async {
+            Err(interrupted())
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&file_path).unwrap(), b"old");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn crash_before_the_rename_leaves_the_config_and_the_next_save_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("config").to_string_lossy().to_string();
+        fs::write(&file_path, b"old").unwrap();
+
+        // A process that died after writing its temp file, before the rename
+        let stale_path = format!("{}.tmp-0badf00d", file_path);
+        fs::write(&stale_path, b"new but never renamed").unwrap();
+        let legacy_path = format!("{}.tmp", file_path);
+        fs::write(&legacy_path, b"from an older version").unwrap();
+        assert_eq!(fs::read(&file_path).unwrap(), b"old");
+
+        block_on(save_encrypted_data(b"newer", &file_path, false)).unwrap();
+
+        assert_eq!(fs::read(&file_path).unwrap(), b"newer");
+        assert!(!Path::new(&stale_path).exists());
+        assert!(!Path::new(&legacy_path).exists());
+        let leftovers: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.contains(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "temp files left: {:?}", leftovers);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_every_byte_and_the_length() {
+        let value = b"00:11:22:33:44:55";
+        assert!(constant_time_eq(value, b"00:11:22:33:44:55"));
+        assert!(constant_time_eq(b"", b""));
+        // A difference in the last byte only
+        assert!(!constant_time_eq(value, b"00:11:22:33:44:56"));
+        // A prefix is not equal
+        assert!(!constant_time_eq(value, b"00:11:22:33:44:5"));
+        assert!(!constant_time_eq(value, b"00:11:22:33:44:550"));
+        assert!(!constant_time_eq(value, b""));
+    }
+
+    const TEST_COMPUTER_INFO: &str = "001A2B3C4D5ESERVER01";
+
+    #[test]
+    fn hkdf_matches_rfc5869_test_vectors() {
+        let ikm = [0x0b; 22];
+        let mut okm = [0u8; 42];
+
+        // Test case 1: with salt and info
+        let salt = hex::decode("000102030405060708090a0b0c").unwrap();
+        let info = hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap();
+        Hkdf::<Sha256>::new(Some(&salt), &ikm)
+            .expand(&info, &mut okm)
+            .unwrap();
+        assert_eq!(
+            hex::encode(okm),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"
+        );
+
+        // Test case 3: no salt, as passphrase inputs go in, and no info
+        Hkdf::<Sha256>::new(None, &ikm)
+            .expand(&[], &mut okm)
+            .unwrap();
+        assert_eq!(
+            hex::encode(okm),
+            "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8"
+        );
+    }
+
+    #[test]
+    fn hkdf_key_and_iv_are_independent() {
+        let salt: Vec<u8> = (0x20..0x30).collect();
+        let input = format!("{}T", TEST_COMPUTER_INFO);
+        let (key, iv) = hkdf_key_iv(Some(&salt), input.as_bytes());
+        assert_eq!(key.len(), 32);
+        assert_eq!(iv.len(), 16);
+        // The legacy IV is the key's prefix; an HKDF one is not
+        assert_ne!(&key[..16], iv.as_slice());
+
+        let (other_key, other_iv) = hkdf_key_iv(Some(&[0u8; 16]), input.as_bytes());
+        assert_ne!(key, other_key);
+        assert_ne!(iv, other_iv);
+        assert_eq!(hkdf_key_iv(Some(&salt), input.as_bytes()), (key, iv));
+    }
+
+    #[test]
+    fn header_kdf_selects_the_derivation() {
+        let legacy = FileMetadata::parse("MAC=001A2B3C4D5E;HOST=SERVER01;").unwrap();
+        assert_eq!(legacy.kdf, Kdf::Legacy);
+        let (key, iv) = derive_key_iv(&legacy, TEST_COMPUTER_INFO, None).unwrap();
+        assert_eq!(key, b"001A2B3C4D5ESERVER01TTTTTTTTTTTT");
+        assert_eq!(iv, b"001A2B3C4D5ESERV");
+
+        let hkdf = FileMetadata::parse(
+            "MAC=001A2B3C4D5E;HOST=SERVER01;KDF=hkdf-sha256;SALT=202122232425262728292a2b2c2d2e2f;",
+        )
+        .unwrap();
+        assert_eq!(hkdf.kdf, Kdf::HkdfSha256);
+        let (key, iv) = derive_key_iv(&hkdf, TEST_COMPUTER_INFO, None).unwrap();
+        assert_eq!(
+            hex::encode(key),
+            "967212308f83fdf98a89236f40299a2a1704c86f37c07ee9ef211744e227a85c"
+        );
+        assert_eq!(hex::encode(iv), "143c6434c653157ea51e50a065ad259e");
+
+        assert!(FileMetadata::parse("MAC=001A2B3C4D5E;KDF=scrypt;").is_err());
+    }
+
+    // Output of encrypt_json_inner for the sample below under
+    // ChaCha20Rng::seed_from_u64(42): the salt and IV are the first 32 bytes
+    // of its keystream. Computed independently with Python's hashlib and
+    // cryptography packages.
+    const SEEDED_JSON: &str = r#"{"DB":{"DB_Port":"1433"},"CodigoCliente":"SEEDED"}"#;
+    const SEEDED_PASSPHRASE: &str = "correct horse battery staple";
+    const SEEDED_HEADER: &str = "BINDING=passphrase;SALT=7848b5d711bc9883996317a3f9c90269;ITER=10000;KDF=hkdf-sha256;IV=d56771005d540a19184939c9e8d0db2a;CANONICAL=1;";
+    const SEEDED_CIPHERTEXT: &str = "231ee4739c68604ea6a1791b8fbdd1b6759c9db54db351a6c877dba223e2fbdd\
+                                     d903f628eda3d5abdc1845df71136f9b48c293b598d47f2841c0e4df007953e8";
+
+    // Options for an in-memory, passphrase-bound file, which needs no MAC
+    fn blob_options() -> EncryptOptions {
+        EncryptOptions {
+            passphrase: Some(SEEDED_PASSPHRASE.to_string()),
+            pbkdf2_iterations: Some(PBKDF2_MIN_ITERATIONS),
+            return_blob: Some(true),
+            ..Default::default()
+        }
+    }
+
+    fn encrypt_to_blob(
+        json: &str,
+        options: EncryptOptions,
+        rng: &mut (impl RngCore + CryptoRng + Send),
+    ) -> Result<Vec<u8>, String> {
+        let result = block_on(encrypt_json_inner(None, json.to_string(), options, rng))?;
+        Ok(BASE64.decode(result.data_base64.unwrap()).unwrap())
+    }
+
+    fn encrypt_seeded(seed: u64) -> Vec<u8> {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        encrypt_to_blob(SEEDED_JSON, blob_options(), &mut rng).unwrap()
+    }
+
+    #[test]
+    fn seeded_rng_gives_the_exact_file() {
+        // An organization secret built in adds ORG=1 and changes the key
+        if ORG_SECRET.is_some() {
+            return;
+        }
+        let file = encrypt_seeded(42);
+        let expected =
+            assemble_encrypted_file(SEEDED_HEADER, &hex::decode(SEEDED_CIPHERTEXT).unwrap());
+        assert_eq!(file[..4], 132u32.to_le_bytes());
+        assert_eq!(file, expected);
+
+        assert_ne!(encrypt_seeded(43), file);
+        let json = decrypt_blob(&file, None, Some(SEEDED_PASSPHRASE.to_string())).unwrap();
+        assert_eq!(
+            json,
+            r#"{"CodigoCliente":"SEEDED","DB":{"DB_Port":"1433"}}"#
+        );
+    }
+
+    #[test]
+    fn seeded_golden_file_matches_the_fixture() {
+        let mut rng = ChaCha20Rng::seed_from_u64(GOLDEN_SEED);
+        assert_eq!(golden_file(&mut rng).unwrap(), GOLDEN_FILE);
+        // A different seed gives a different salt and IV
+        let mut rng = ChaCha20Rng::seed_from_u64(GOLDEN_SEED + 1);
+        assert_ne!(golden_file(&mut rng).unwrap(), GOLDEN_FILE);
+    }
+
+    #[test]
+    fn self_test_round_trip_draws_from_the_given_rng() {
+        let mut rng = ChaCha20Rng::seed_from_u64(GOLDEN_SEED);
+        assert!(self_test_round_trip(&mut rng).is_ok());
+    }
+
+    #[test]
+    fn pad_with_char_counts_bytes_of_a_multibyte_hostname() {
+        // "É" is two bytes, so the 13 characters are 14 bytes
+        assert_eq!(
+            pad_with_char("SERVIDOR-CAFÉ", 16, 'T'),
+            b"SERVIDOR-CAF\xc3\x89TT"
+        );
+        // Truncating may cut a character; the key is bytes, not a string
+        assert_eq!(pad_with_char("SERVIDOR-CAFÉ", 13, 'T'), b"SERVIDOR-CAF\xc3");
+        assert_eq!(pad_with_char("AB", 5, 'É'), b"AB\xc3\x89\xc3");
+        // ASCII input gives what the old string version gave
+        assert_eq!(pad_with_char("SERVER01", 10, 'T'), b"SERVER01TT");
+    }
+
+    #[test]
+    fn multibyte_hostname_round_trips() {
+        let metadata = FileMetadata {
+            mac: "001A2B3C4D5E".to_string(),
+            hostname: "SERVIDOR-CAFÉ".to_string(),
+            key_char: Some('T'),
+            ..Default::default()
+        };
+        let computer_info = get_computer_info(&metadata.mac, "", "", &metadata.hostname, "");
+        let (key, iv) = derive_key_iv(&metadata, &computer_info, None).unwrap();
+        assert_eq!(key.len(), 32);
+        assert_eq!(iv.len(), 16);
+        let encrypted = encrypt_data(
+            SELF_TEST_JSON.as_bytes(),
+            &key,
+            &iv,
+            KeySize::Aes256,
+            CipherMode::Cbc,
+        )
+        .unwrap();
+
+        // Read back through the header, as decryption on the machine does
+        let parsed = FileMetadata::parse(&metadata.encode()).unwrap();
+        assert_eq!(parsed.hostname, "SERVIDOR-CAFÉ");
+        let computer_info = get_computer_info(&parsed.mac, "", "", &parsed.hostname, "");
+        let (key, iv) = derive_key_iv(&parsed, &computer_info, None).unwrap();
+        let decrypted =
+            decrypt_data(&encrypted, &key, &iv, KeySize::Aes256, CipherMode::Cbc).unwrap();
+        assert_eq!(decrypted, SELF_TEST_JSON.as_bytes());
+    }
+
+    #[test]
+    fn empty_and_whitespace_input_is_rejected() {
+        for json in ["", "   ", "\r\n\t "] {
+            let error = encrypt_to_blob(json, blob_options(), &mut OsRng).unwrap_err();
+            assert!(error.starts_with("Empty input"), "{:?}: {}", json, error);
+        }
+    }
+
+    #[test]
+    fn empty_object_is_encrypted() {
+        let file = encrypt_to_blob("{}", blob_options(), &mut OsRng).unwrap();
+        let json = decrypt_blob(&file, None, Some(SEEDED_PASSPHRASE.to_string())).unwrap();
+        assert_eq!(json, "{}");
+    }
+
+    #[test]
+    fn file_holding_no_content_is_rejected() {
+        let metadata = FileMetadata {
+            binding: Binding::Passphrase,
+            kdf: Kdf::HkdfSha256,
+            iterations: Some(PBKDF2_MIN_ITERATIONS),
+            salt: vec![7u8; SALT_LEN],
+            iv: vec![9u8; IV_LEN],
+            ..Default::default()
+        };
+        let (key, iv) = derive_key_iv(&metadata, "", Some(SEEDED_PASSPHRASE)).unwrap();
+        let encrypted = encrypt_data(b"", &key, &iv, KeySize::Aes256, CipherMode::Cbc).unwrap();
+        let file = assemble_encrypted_file(&metadata.encode(), &encrypted);
+
+        let error = decrypt_blob(&file, None, Some(SEEDED_PASSPHRASE.to_string())).unwrap_err();
+        assert!(error.starts_with("Empty input"), "{}", error);
+    }
+
+    #[test]
+    fn ctr_file_round_trips_without_padding() {
+        let options = EncryptOptions {
+            cipher_mode: Some("ctr".to_string()),
+            ..blob_options()
+        };
+        let file = encrypt_to_blob(SELF_TEST_JSON, options, &mut OsRng).unwrap();
+        let (metadata, payload) = split_encrypted_file(&file).unwrap();
+        assert_eq!(metadata.cipher_mode, CipherMode::Ctr);
+        assert_eq!(payload.len(), SELF_TEST_JSON.len());
+
+        let json = decrypt_blob(&file, None, Some(SEEDED_PASSPHRASE.to_string())).unwrap();
+        assert_eq!(json, SELF_TEST_JSON);
+        // No padding to check, so a wrong key is caught by the JSON check
+        assert!(decrypt_blob(&file, None, Some("wrong passphrase".to_string())).is_err());
+    }
+
+    #[test]
+    fn cbc_file_still_decrypts() {
+        let file = encrypt_to_blob(SELF_TEST_JSON, blob_options(), &mut OsRng).unwrap();
+        let (metadata, payload) = split_encrypted_file(&file).unwrap();
+        assert_eq!(metadata.cipher_mode, CipherMode::Cbc);
+        assert!(!metadata.encode().contains("MODE="));
+        assert_eq!(payload.len() % 16, 0);
+
+        let json = decrypt_blob(&file, None, Some(SEEDED_PASSPHRASE.to_string())).unwrap();
+        assert_eq!(json, SELF_TEST_JSON);
+    }
+
+    #[test]
+    fn recovery_shares_combine_to_the_key_and_iv() {
+        let key = vec![0x11u8; 32];
+        let iv = vec![0x22u8; 16];
+        let mut metadata = FileMetadata::default();
+        let admin_share = create_recovery_shares(&mut OsRng, &mut metadata, &key, &iv).unwrap();
+
+        // Neither share is the secret itself
+        let mut secret = key.clone();
+        secret.extend_from_slice(&iv);
+        assert_ne!(admin_share, secret);
+        assert_ne!(metadata.recovery_share, secret);
+
+        assert_eq!(
+            combine_recovery_shares(&metadata, &admin_share).unwrap(),
+            (key, iv)
+        );
+    }
+
+    #[test]
+    fn wrong_recovery_share_is_rejected() {
+        let mut metadata = FileMetadata::default();
+        let admin_share =
+            create_recovery_shares(&mut OsRng, &mut metadata, &[0x11; 32], &[0x22; 16]).unwrap();
+
+        // A flipped bit gives a secret that fails recovery_check
+        let mut corrupt = admin_share.clone();
+        corrupt[0] ^= 1;
+        let error = combine_recovery_shares(&metadata, &corrupt).unwrap_err();
+        assert!(error.contains("does not belong"), "{}", error);
+
+        // So does the share of another file with the same key
+        let mut other = FileMetadata::default();
+        let other_share =
+            create_recovery_shares(&mut OsRng, &mut other, &[0x11; 32], &[0x22; 16]).unwrap();
+        assert!(combine_recovery_shares(&metadata, &other_share).is_err());
+
+        let error = combine_recovery_shares(&metadata, &admin_share[1..]).unwrap_err();
+        assert!(error.contains("unexpected length"), "{}", error);
+    }
+
+    #[test]
+    fn saved_recovery_share_decrypts_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let share_path = dir.path().join("admin.share");
+        let options = EncryptOptions {
+            recovery_share_path: Some(share_path.to_string_lossy().to_string()),
+            ..blob_options()
+        };
+        let file = encrypt_to_blob(SELF_TEST_JSON, options, &mut OsRng).unwrap();
+
+        let (metadata, payload) = split_encrypted_file(&file).unwrap();
+        let contents = std::fs::read_to_string(&share_path).unwrap();
+        let (recovery_id, admin_share) = parse_recovery_share(&contents).unwrap();
+        assert_eq!(recovery_id, metadata.recovery_id);
+
+        let (key, iv) = combine_recovery_shares(&metadata, &admin_share).unwrap();
+        let result = decrypt_payload(&metadata, payload, &key, &iv).unwrap();
+        assert_eq!(result.json_data, SELF_TEST_JSON);
+    }
+
+    fn adapter(name: &str, mac: &str) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            mac: mac.to_string(),
+            is_virtual: false,
+            connected: true,
+            default_route: false,
+            removable: false,
+        }
+    }
+
+    // An any-MAC header, as read back from a file, bound to the given MACs
+    fn any_mac_metadata(macs: &[&str], data_key: &[u8]) -> FileMetadata {
+        let mut metadata = FileMetadata {
+            protection: Protection::AnyMac,
+            hostname: "SERVER01".to_string(),
+            key_char: Some('T'),
+            salt: vec![5u8; SALT_LEN],
+            ..Default::default()
+        };
+        let macs: Vec<String> = macs.iter().map(|mac| mac.to_string()).collect();
+        metadata.mac_keys = wrap_mac_keys(&metadata, &macs, data_key).unwrap();
+        FileMetadata::parse(&metadata.encode()).unwrap()
+    }
+
+    #[test]
+    fn any_mac_key_unwraps_after_an_adapter_is_added() {
+        let data_key = vec![0x42u8; 48];
+        let metadata = any_mac_metadata(&["001A2B3C4D5E", "001A2B3C4D5F"], &data_key);
+        assert_eq!(metadata.mac_keys.len(), 2);
+
+        let interfaces = [
+            adapter("ethernet2", "00155D000001"),
+            adapter("ethernet0", "001A2B3C4D5E"),
+            adapter("ethernet1", "001A2B3C4D5F"),
+        ];
+        assert_eq!(
+            unwrap_mac_key_with(&metadata, &interfaces).unwrap(),
+            data_key
+        );
+    }
+
+    #[test]
+    fn any_mac_key_unwraps_while_one_bound_adapter_remains() {
+        let data_key = vec![0x42u8; 48];
+        let metadata = any_mac_metadata(&["001A2B3C4D5E", "001A2B3C4D5F"], &data_key);
+
+        for remaining in ["001A2B3C4D5E", "001A2B3C4D5F"] {
+            let interfaces = [adapter("ethernet0", remaining)];
+            assert_eq!(
+                unwrap_mac_key_with(&metadata, &interfaces).unwrap(),
+                data_key
+            );
+        }
+
+        // Every bound adapter swapped out
+        let interfaces = [adapter("ethernet0", "00155D000001")];
+        assert!(unwrap_mac_key_with(&metadata, &interfaces).is_err());
+        assert!(unwrap_mac_key_with(&metadata, &[]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_directory_fails_before_encrypting() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+        // Root writes through the permission bits
+        if std::fs::write(dir.path().join("probe"), b"").is_ok() {
+            return;
+        }
+        let output_path = dir.path().join("config").to_string_lossy().to_string();
+
+        let error = block_on(check_writable(&output_path)).unwrap_err();
+        assert!(
+            error.starts_with("Permission denied writing to"),
+            "{}",
+            error
+        );
+        assert!(error.contains("output_path"), "{}", error);
+
+        let options = EncryptOptions {
+            output_path: Some(output_path.clone()),
+            return_blob: None,
+            ..blob_options()
+        };
+        let error = encrypt_to_blob(SELF_TEST_JSON, options, &mut OsRng).unwrap_err();
+        assert!(
+            error.starts_with("Permission denied writing to"),
+            "{}",
+            error
+        );
+        assert!(!Path::new(&output_path).exists());
+
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn hostname_case_modes_round_trip() {
+        let reported = "Servidor-01";
+        for (case, stored) in [
+            (HostnameCase::Preserve, "Servidor-01"),
+            (HostnameCase::Lower, "servidor-01"),
+            (HostnameCase::Upper, "SERVIDOR-01"),
+        ] {
+            assert_eq!(HostnameCase::parse(case.as_str()).unwrap(), case);
+            let metadata = FileMetadata {
+                mac: "001A2B3C4D5E".to_string(),
+                hostname: case.apply(reported.to_string()),
+                hostname_case: case,
+                key_char: Some('T'),
+                kdf: Kdf::HkdfSha256,
+                salt: vec![3u8; SALT_LEN],
+                iv: vec![4u8; IV_LEN],
+                ..Default::default()
+            };
+            assert_eq!(metadata.hostname, stored);
+
+            let encoded = metadata.encode();
+            assert_eq!(
+                encoded.contains("HOST_CASE="),
+                case != HostnameCase::Preserve
+            );
+            let parsed = FileMetadata::parse(&encoded).unwrap();
+            assert_eq!(parsed.hostname, stored);
+            assert_eq!(parsed.hostname_case, case);
+            // The OS may report either case; the binding still matches
+            assert!(same_hostname(&parsed.hostname, reported));
+
+            let computer_info = get_computer_info(&metadata.mac, "", "", &metadata.hostname, "");
+            let (key, iv) = derive_key_iv(&metadata, &computer_info, None).unwrap();
+            let encrypted = encrypt_data(
+                SELF_TEST_JSON.as_bytes(),
+                &key,
+                &iv,
+                KeySize::Aes256,
+                CipherMode::Cbc,
+            )
+            .unwrap();
+            let computer_info = get_computer_info(&parsed.mac, "", "", &parsed.hostname, "");
+            let (key, iv) = derive_key_iv(&parsed, &computer_info, None).unwrap();
+            let decrypted =
+                decrypt_data(&encrypted, &key, &iv, KeySize::Aes256, CipherMode::Cbc).unwrap();
+            assert_eq!(decrypted, SELF_TEST_JSON.as_bytes());
+        }
+        assert!(HostnameCase::parse("title").is_err());
+    }
+
+    // The only test that sets BTIC_KEY_CHAR; the others pass key chars
+    // explicitly or use bindings that don't involve one
+    #[test]
+    fn key_char_falls_back_to_the_environment() {
+        let env = |name: &str| (name == KEY_CHAR_ENV).then(|| "X".to_string());
+        assert_eq!(resolve_key_char_with(None, env), 'X');
+        assert_eq!(resolve_key_char_with(Some("Q".to_string()), env), 'Q');
+        assert_eq!(resolve_key_char_with(None, |_| None), 'T');
+
+        // Encryption records the resolved char in the header
+        let options = EncryptOptions {
+            char_key: Some("X".to_string()),
+            return_blob: Some(true),
+            ..Default::default()
+        };
+        let file = encrypt_to_blob(SELF_TEST_JSON, options, &mut OsRng).unwrap();
+        let (metadata, _) = split_encrypted_file(&file).unwrap();
+        assert_eq!(metadata.key_char, Some('X'));
+
+        // A file that doesn't record one needs it passed in
+        let key = get_key(32, TEST_COMPUTER_INFO, 'X');
+        let iv = get_key(16, TEST_COMPUTER_INFO, 'X');
+        let encrypted = encrypt_data(
+            SELF_TEST_JSON.as_bytes(),
+            &key,
+            &iv,
+            KeySize::Aes256,
+            CipherMode::Cbc,
+        )
+        .unwrap();
+        let file = assemble_encrypted_file("MAC=001A2B3C4D5E;HOST=SERVER01;", &encrypted);
+        assert_eq!(
+            decrypt_blob(&file, Some("X".to_string()), None).unwrap(),
+            SELF_TEST_JSON
+        );
+    }
+
+    #[test]
+    fn invalid_json_is_encrypted_as_is_whatever_canonicalize_says() {
+        let raw = "DB_Host=sage01; not json";
+        for canonicalize in [None, Some(true), Some(false)] {
+            let options = EncryptOptions {
+                canonicalize,
+                ..blob_options()
+            };
+            let result = block_on(encrypt_json_inner(
+                None,
+                raw.to_string(),
+                options,
+                &mut OsRng,
+            ))
+            .unwrap();
+            assert_eq!(result.warnings, vec![WARNING_NOT_CANONICALIZED.to_string()]);
+
+            let file = BASE64.decode(result.data_base64.unwrap()).unwrap();
+            let (metadata, _) = split_encrypted_file(&file).unwrap();
+            assert!(!metadata.canonical);
+            let json = decrypt_blob(&file, None, Some(SEEDED_PASSPHRASE.to_string())).unwrap();
+            assert_eq!(json, raw);
+
+            // CTR files must decrypt to JSON
+            let options = EncryptOptions {
+                canonicalize,
+                cipher_mode: Some("ctr".to_string()),
+                ..blob_options()
+            };
+            assert!(encrypt_to_blob(raw, options, &mut OsRng).is_err());
+        }
+    }
+
+    #[test]
+    fn canonicalize_only_decides_whether_json_is_normalized() {
+        let json = r#"{"b":1,"a":2}"#;
+        let encrypt = |canonicalize| {
+            let options = EncryptOptions {
+                canonicalize,
+                ..blob_options()
+            };
+            let file = encrypt_to_blob(json, options, &mut OsRng).unwrap();
+            let (metadata, _) = split_encrypted_file(&file).unwrap();
+            let decrypted = decrypt_blob(&file, None, Some(SEEDED_PASSPHRASE.to_string())).unwrap();
+            (metadata.canonical, decrypted)
+        };
+        assert_eq!(encrypt(Some(false)), (false, json.to_string()));
+        let (canonical, decrypted) = encrypt(None);
+        assert!(canonical);
+        assert_eq!(decrypted, canonicalize_json(json).unwrap());
+    }
+
+    #[test]
+    fn key_fingerprint_is_salted_like_the_file_key() {
+        let metadata = |salt: u8| FileMetadata {
+            mac: "001A2B3C4D5E".to_string(),
+            hostname: "SERVER01".to_string(),
+            key_char: Some('T'),
+            kdf: Kdf::HkdfSha256,
+            salt: vec![salt; SALT_LEN],
+            ..Default::default()
+        };
+        let fingerprint = key_fingerprint(&metadata(1), TEST_COMPUTER_INFO).unwrap();
+        assert_eq!(
+            fingerprint,
+            key_fingerprint(&metadata(1), TEST_COMPUTER_INFO).unwrap()
+        );
+        assert_ne!(
+            fingerprint,
+            key_fingerprint(&metadata(2), TEST_COMPUTER_INFO).unwrap()
+        );
+        assert_ne!(
+            fingerprint,
+            key_fingerprint(&metadata(1), "001A2B3C4D5FSERVER01").unwrap()
+        );
+
+        let (key, _) = derive_key_iv(&metadata(1), TEST_COMPUTER_INFO, None).unwrap();
+        assert_ne!(fingerprint, hex::encode(key));
+    }
+
+    #[test]
+    fn passphrase_file_has_no_key_fingerprint() {
+        let file = encrypt_to_blob(SELF_TEST_JSON, blob_options(), &mut OsRng).unwrap();
+        assert_eq!(file_key_fingerprints(&file, None).unwrap(), (None, None));
+    }
+
+    #[test]
+    fn iv_migration_takes_the_iv_from_rng() {
+        let file = encrypt_seeded(42);
+        let (mut metadata, _) = split_encrypted_file(&file).unwrap();
+        // Rewrite it the way files were written before IVs were stored
+        metadata.iv.clear();
+        let (key, iv) = derive_key_iv(&metadata, "", Some(SEEDED_PASSPHRASE)).unwrap();
+        let payload = encrypt_data(
+            SEEDED_JSON.as_bytes(),
+            &key,
+            &iv,
+            metadata.key_size,
+            metadata.cipher_mode,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.enc");
+        std::fs::write(&path, assemble_encrypted_file(&metadata.encode(), &payload)).unwrap();
+
+        let result = block_on(migrate_to_random_iv_inner(
+            Some(path.to_string_lossy().to_string()),
+            None,
+            Some(SEEDED_PASSPHRASE.to_string()),
+            None,
+            &mut ChaCha20Rng::seed_from_u64(334),
+        ))
+        .unwrap();
+        assert!(result.migrated);
+
+        let migrated = std::fs::read(&path).unwrap();
+        let (metadata, _) = split_encrypted_file(&migrated).unwrap();
+        let mut expected_iv = vec![0u8; IV_LEN];
+        ChaCha20Rng::seed_from_u64(334).fill_bytes(&mut expected_iv);
+        assert_eq!(metadata.iv, expected_iv);
+        let decrypted =
+            decrypt_file_contents(&migrated, None, Some(SEEDED_PASSPHRASE.to_string()), false)
+                .unwrap();
+        assert_eq!(decrypted.json_data, SEEDED_JSON);
+    }
 }
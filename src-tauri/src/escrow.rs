@@ -0,0 +1,68 @@
+use rand_core::{CryptoRng, RngCore};
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey};
+use rsa::traits::PublicKeyParts;
+use rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+
+// Recovery key escrow: the data key of a file is also wrapped under an
+// operator's RSA public key with OAEP-SHA256, so whoever holds the private
+// key can open the file off the machine it is bound to.
+
+// Length of the key id stored with the escrowed key, enough to tell an
+// organization's recovery keys apart
+const ESCROW_ID_LEN: usize = 8;
+const MIN_RSA_BITS: usize = 2048;
+
+// Function to read an RSA public key in SPKI ("PUBLIC KEY") or PKCS#1
+// ("RSA PUBLIC KEY") PEM form
+pub fn parse_public_key(pem: &str) -> Result<RsaPublicKey, String> {
+    let pem = pem.trim();
+    let public_key = RsaPublicKey::from_public_key_pem(pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+        .map_err(|e| format!("Invalid recovery public key: {}", e))?;
+    if public_key.size() * 8 < MIN_RSA_BITS {
+        return Err(format!(
+            "Recovery public key must be at least {} bits",
+            MIN_RSA_BITS
+        ));
+    }
+    Ok(public_key)
+}
+
+// Function to read an RSA private key in PKCS#8 ("PRIVATE KEY") or PKCS#1
+// ("RSA PRIVATE KEY") PEM form
+pub fn parse_private_key(pem: &str) -> Result<RsaPrivateKey, String> {
+    let pem = pem.trim();
+    RsaPrivateKey::from_pkcs8_pem(pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+        .map_err(|e| format!("Invalid recovery private key: {}", e))
+}
+
+// Function to identify a recovery key by a hash of its public half, so a
+// wrong private key is reported before trying to unwrap
+pub fn escrow_key_id(public_key: &RsaPublicKey) -> Result<Vec<u8>, String> {
+    let der = public_key
+        .to_public_key_der()
+        .map_err(|e| format!("Failed to encode recovery public key: {}", e))?;
+    Ok(Sha256::digest(der.as_bytes())[..ESCROW_ID_LEN].to_vec())
+}
+
+// Function to wrap a data key under the recovery public key. OAEP is
+// randomized; rng is OsRng everywhere but in deterministic tests.
+pub fn escrow_wrap(
+    rng: &mut (impl RngCore + CryptoRng),
+    public_key: &RsaPublicKey,
+    data_key: &[u8],
+) -> Result<Vec<u8>, String> {
+    public_key
+        .encrypt(rng, Oaep::new::<Sha256>(), data_key)
+        .map_err(|e| format!("Failed to wrap the data key for recovery: {}", e))
+}
+
+// Function to unwrap an escrowed data key with the recovery private key
+pub fn escrow_unwrap(private_key: &RsaPrivateKey, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+    private_key
+        .decrypt(Oaep::new::<Sha256>(), wrapped)
+        .map_err(|e| format!("Recovery key can't unwrap the data key: {}", e))
+}
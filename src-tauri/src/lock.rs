@@ -0,0 +1,136 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// How long to wait for another process before giving up
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+// Lock on a file, held as an OS lock (LockFileEx on Windows, flock
+// elsewhere) on a "<file>.lock" sibling. The connector service takes the
+// same lock, shared, while it reads the config. The OS drops the lock with
+// the handle, so a crashed process never leaves it held.
+//
+// The lock file itself stays: removing it while another process waits on it
+// would let a third create a new one and lock it at the same time.
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    // Exclusive lock, for writers
+    pub fn acquire(file_path: &Path) -> Result<FileLock, String> {
+        Self::acquire_with(file_path, false)
+    }
+
+    // Shared lock, for readers: any number of them, but no writer
+    pub fn acquire_shared(file_path: &Path) -> Result<FileLock, String> {
+        Self::acquire_with(file_path, true)
+    }
+
+    fn acquire_with(file_path: &Path, shared: bool) -> Result<FileLock, String> {
+        let lock_path = lock_path(file_path);
+        if let Some(parent) = lock_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|e| format!("Failed to open lock file: {}", e))?;
+
+        let start = SystemTime::now();
+        loop {
+            let attempt = if shared {
+                file.try_lock_shared()
+            } else {
+                file.try_lock()
+            };
+            match attempt {
+                Ok(()) => return Ok(FileLock { _file: file }),
+                Err(TryLockError::WouldBlock) => {
+                    if start.elapsed().unwrap_or_default() > LOCK_TIMEOUT {
+                        return Err(format!(
+                            "Busy: the configuration file {} is locked by another process",
+                            file_path.display()
+                        ));
+                    }
+                    thread::sleep(LOCK_RETRY_INTERVAL);
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(format!("Failed to lock {}: {}", file_path.display(), e))
+                }
+            }
+        }
+    }
+}
+
+fn lock_path(file_path: &Path) -> PathBuf {
+    let mut lock_path = file_path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    PathBuf::from(lock_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn writer_waits_for_the_other_thread_to_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.bin");
+        let held = FileLock::acquire(&path).unwrap();
+        let released = Arc::new(AtomicBool::new(false));
+
+        let waiter = {
+            let path = path.clone();
+            let released = Arc::clone(&released);
+            thread::spawn(move || {
+                let _lock = FileLock::acquire(&path).unwrap();
+                released.load(Ordering::SeqCst)
+            })
+        };
+
+        thread::sleep(Duration::from_millis(300));
+        released.store(true, Ordering::SeqCst);
+        drop(held);
+        assert!(waiter.join().unwrap(), "lock was taken while still held");
+    }
+
+    #[test]
+    fn readers_share_but_exclude_a_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.bin");
+        let reader = FileLock::acquire_shared(&path).unwrap();
+
+        let other_reader = {
+            let path = path.clone();
+            thread::spawn(move || FileLock::acquire_shared(&path).is_ok())
+        };
+        assert!(other_reader.join().unwrap());
+
+        let released = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let path = path.clone();
+            let released = Arc::clone(&released);
+            thread::spawn(move || {
+                let _lock = FileLock::acquire(&path).unwrap();
+                released.load(Ordering::SeqCst)
+            })
+        };
+
+        thread::sleep(Duration::from_millis(300));
+        released.store(true, Ordering::SeqCst);
+        drop(reader);
+        assert!(
+            writer.join().unwrap(),
+            "writer got in while a reader held the lock"
+        );
+    }
+}
@@ -0,0 +1,2449 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+use crate::command::{run_command, COMMAND_TIMEOUT};
+use crate::encryption::{constant_time_eq, get_config_dir};
+
+// MAC used when neither an adapter nor a machine identifier can be found.
+// Every such machine shares it, so it is a last resort only.
+pub(crate) const FALLBACK_MAC: &str = "902E168B9AC1";
+
+// File in the config dir remembering the adapter every command binds to
+const INTERFACE_PREFERENCE_FILE: &str = "interface.json";
+
+// File in the config dir with the operator's allow and deny patterns
+const INTERFACE_FILTERS_FILE: &str = "interface_filters.json";
+
+// Adapters that never identify the machine, as (lowercase name fragment,
+// reason). Collected from support cases where configs were bound to them.
+const EXCLUDED_ADAPTERS: &[(&str, &str)] = &[
+    ("loopback", "loopback adapter"),
+    ("bluetooth", "Bluetooth personal area network"),
+    ("wan miniport", "WAN Miniport"),
+    ("tap-windows", "TAP-Windows VPN adapter"),
+    ("openvpn", "OpenVPN adapter"),
+    ("wintun", "Wintun tunnel"),
+    ("wireguard", "WireGuard tunnel"),
+    ("npcap", "Npcap loopback"),
+    ("winpcap", "WinPcap loopback"),
+    ("default switch", "Hyper-V Default Switch"),
+    ("hyper-v", "Hyper-V virtual adapter"),
+    ("virtualbox", "VirtualBox host-only adapter"),
+    ("vmware", "VMware virtual adapter"),
+    ("teredo", "Teredo tunnel"),
+    ("isatap", "ISATAP tunnel"),
+    ("6to4", "6to4 tunnel"),
+];
+
+// Selection decision recorded when FALLBACK_MAC was used
+pub(crate) const SHARED_FALLBACK_DECISION: &str = "shared_fallback";
+// Selection decision for the Hyper-V external switch adapter of a host whose
+// adapters are all virtual
+pub(crate) const EXTERNAL_VSWITCH_DECISION: &str = "external_vswitch";
+
+// MAC selection of this process. Enumerating adapters shells out to route
+// (and ipconfig on fallback) and costs hundreds of milliseconds, so it runs
+// once and is reused until refresh_machine_info or an interface change.
+// The hostname is a plain system call and is always read fresh.
+static MAC_SELECTION_CACHE: Mutex<Option<MacSelection>> = Mutex::new(None);
+
+// Most adapters checked when looking for the one a file was bound to
+const MAX_MAC_CANDIDATES: usize = 16;
+
+// Label hashed with a machine identifier to turn it into a MAC-shaped value
+const MACHINE_ID_LABEL: &[u8] = b"btic-machine-id-v1";
+
+// Console code pages ipconfig output is decoded from
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const CP_UTF8: u32 = 65001;
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const CP_MULTILINGUAL_LATIN1: u32 = 850;
+
+// Characters 0x80-0xFF of code page 850, the OEM code page of Spanish,
+// Catalan, French, German and Portuguese Windows
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+const CP850_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ', 'Æ',
+    'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', 'ø', '£', 'Ø', '×', 'ƒ', 'á', 'í', 'ó', 'ú', 'ñ', 'Ñ',
+    'ª', 'º', '¿', '®', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', 'Á', 'Â', 'À', '©',
+    '╣', '║', '╗', '╝', '¢', '¥', '┐', '└', '┴', '┬', '├', '─', '┼', 'ã', 'Ã', '╚', '╔', '╩', '╦',
+    '╠', '═', '╬', '¤', 'ð', 'Ð', 'Ê', 'Ë', 'È', 'ı', 'Í', 'Î', 'Ï', '┘', '┌', '█', '▄', '¦', 'Ì',
+    '▀', 'Ó', 'ß', 'Ô', 'Ò', 'õ', 'Õ', 'µ', 'þ', 'Þ', 'Ú', 'Û', 'Ù', 'ý', 'Ý', '¯', '´', '\u{ad}',
+    '±', '‗', '¾', '¶', '§', '÷', '¸', '°', '¨', '·', '¹', '³', '²', '■', '\u{a0}',
+];
+
+// A network adapter found on this machine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub mac: String,
+    pub is_virtual: bool,
+    // Link is up / the adapter has a usable address, when the source says so
+    pub connected: bool,
+    // The adapter carries the IPv4 default route
+    pub default_route: bool,
+    // USB or docking-station adapter that disappears when unplugged
+    #[serde(default)]
+    pub removable: bool,
+}
+
+// Where the value in a file's MAC field came from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MachineIdSource {
+    // A network adapter's MAC address
+    #[default]
+    Mac,
+    // HKLM\SOFTWARE\Microsoft\Cryptography\MachineGuid on Windows
+    MachineGuid,
+    // /etc/machine-id on Linux
+    MachineId,
+    // An adapter MAC followed by the MachineGuid hash: either changing
+    // breaks the binding, but a cloned VM with a fresh MAC is caught too
+    MacAndMachineGuid,
+}
+
+impl MachineIdSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MachineIdSource::Mac => "mac",
+            MachineIdSource::MachineGuid => "machine_guid",
+            MachineIdSource::MachineId => "machine_id",
+            MachineIdSource::MacAndMachineGuid => "mac_machine_guid",
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "mac" => Ok(MachineIdSource::Mac),
+            "machine_guid" => Ok(MachineIdSource::MachineGuid),
+            "machine_id" => Ok(MachineIdSource::MachineId),
+            "both" | "mac_machine_guid" => Ok(MachineIdSource::MacAndMachineGuid),
+            other => Err(format!("Unknown machine identifier source: {}", other)),
+        }
+    }
+}
+
+// The MAC address picked for binding, with everything that was considered
+#[derive(Debug, Clone)]
+pub struct MacSelection {
+    pub mac: String,
+    pub source: MachineIdSource,
+    // Which rule picked the MAC, for debugging bad selections remotely
+    pub decision: String,
+    pub interfaces: Vec<NetworkInterface>,
+    pub warning: Option<String>,
+    // The MAC is a per-network random Wi-Fi address
+    pub randomized: bool,
+}
+
+// How the machine identity in a MachineInfo was obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectionSource {
+    // A network adapter's MAC and the hostname were read
+    Detected,
+    // No adapter was found and the MAC is derived from the OS machine
+    // identifier, or the hostname couldn't be read
+    Fallback,
+    // Neither was found (e.g. a container without network tools); binding
+    // would use the shared FALLBACK_MAC
+    Unavailable,
+}
+
+// Machine identity as detected, for callers that decide themselves whether
+// an environment without one (headless, containers) is an error
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MachineInfo {
+    pub source: DetectionSource,
+    pub mac: Option<String>,
+    pub hostname: Option<String>,
+}
+
+// Function to get the machine identity without substituting the shared
+// fallback MAC or an "unknown" hostname
+pub fn detect_machine_info() -> MachineInfo {
+    let selection = detect_mac();
+    let hostname = read_hostname().map(|name| normalize_hostname(&name));
+    let (source, mac) = if selection.decision == SHARED_FALLBACK_DECISION {
+        (DetectionSource::Unavailable, None)
+    } else if selection.source != MachineIdSource::Mac || hostname.is_none() {
+        (DetectionSource::Fallback, Some(selection.mac))
+    } else {
+        (DetectionSource::Detected, Some(selection.mac))
+    };
+    MachineInfo {
+        source,
+        mac,
+        hostname,
+    }
+}
+
+// Function to get the MAC to bind to, detecting it on first use. The lock
+// is held during detection so concurrent commands share a single pass.
+pub fn detect_mac() -> MacSelection {
+    cached_detection(&MAC_SELECTION_CACHE, detect_mac_uncached)
+}
+
+// Function behind detect_mac, with the cache and the detection passed in so
+// tests can count the passes
+fn cached_detection(
+    cache: &Mutex<Option<MacSelection>>,
+    detect: impl FnOnce() -> MacSelection,
+) -> MacSelection {
+    let mut cache = cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(selection) = cache.as_ref() {
+        return selection.clone();
+    }
+    let selection = detect();
+    *cache = Some(selection.clone());
+    selection
+}
+
+// Function to get the selected MAC if detection already ran, without
+// running it
+pub fn cached_mac() -> Option<String> {
+    MAC_SELECTION_CACHE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .as_ref()
+        .map(|selection| selection.mac.clone())
+}
+
+// Function to drop the cached selection so the next command detects again
+pub fn clear_mac_cache() {
+    *MAC_SELECTION_CACHE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+}
+
+// Function to detect the network adapters and select the MAC to bind to
+fn detect_mac_uncached() -> MacSelection {
+    let mut interfaces = list_interfaces();
+    use_permanent_macs(&mut interfaces);
+    let filters = read_interface_filters();
+
+    // Debug output of all found interfaces
+    println!("Found {} network interfaces:", interfaces.len());
+    for (i, interface) in interfaces.iter().enumerate() {
+        println!("  [{}] {} -> {}", i, interface.name, interface.mac);
+    }
+
+    // The remembered adapter wins while it is present, so a change in adapter
+    // state between calls can't switch the binding to another NIC
+    let preference = read_interface_preference();
+    let preference_present = preference.as_ref().map(|preferred| {
+        interfaces.iter().any(|interface| {
+            interface.mac == preferred.mac && filters.exclusion_reason(&interface.name).is_none()
+        })
+    });
+    let automatic = match (&preference, preference_present) {
+        (Some(preferred), Some(true)) => {
+            println!(
+                "Using preferred interface: {} with MAC: {}",
+                preferred.name, preferred.mac
+            );
+            Some((preferred.mac.clone(), "remembered"))
+        }
+        _ => select_mac(&interfaces, &filters),
+    };
+
+    let mut warning = None;
+    let mut decision = SHARED_FALLBACK_DECISION;
+    let (selected_mac, source) = match automatic {
+        Some((mac, rule)) => {
+            decision = rule;
+            (mac, MachineIdSource::Mac)
+        }
+        // Without an adapter, use the OS machine identifier so machines that
+        // fail detection don't all share one key
+        None => match platform_machine_id_source()
+            .and_then(|source| get_machine_id_mac(source).ok().map(|mac| (mac, source)))
+        {
+            Some((mac, source)) => {
+                println!(
+                    "No network interface found, using {} identifier",
+                    source.as_str()
+                );
+                decision = source.as_str();
+                (mac, source)
+            }
+            None => {
+                let message = "No network interface or machine identifier found; the shared \
+                               fallback MAC was used - use passphrase mode"
+                    .to_string();
+                println!("WARNING: {}", message);
+                warning = Some(message);
+                (FALLBACK_MAC.to_string(), MachineIdSource::Mac)
+            }
+        },
+    };
+
+    // Every candidate being virtual means the fallback picked a virtual
+    // adapter, whose MAC may change on reboot or VM migration
+    let candidates: Vec<&NetworkInterface> = interfaces
+        .iter()
+        .filter(|interface| filters.exclusion_reason(&interface.name).is_none())
+        .collect();
+    if !candidates.is_empty() && candidates.iter().all(|interface| interface.is_virtual) {
+        let message = if decision == EXTERNAL_VSWITCH_DECISION {
+            "Only virtual network interfaces found; bound to the external virtual switch \
+             adapter, which carries the physical card's MAC - the binding breaks if the switch \
+             is moved to another card"
+                .to_string()
+        } else {
+            format!(
+                "Only virtual network interfaces found; the MAC changes whenever the virtual \
+                 switch is recreated, which makes the config unreadable - use the {} binding \
+                 source or passphrase mode",
+                MachineIdSource::MachineGuid.as_str()
+            )
+        };
+        println!("WARNING: {}", message);
+        warning = Some(message);
+    }
+
+    // A randomized address changes with the Wi-Fi privacy setting or the
+    // network joined, so the binding would break on the next SSID
+    let randomized = source == MachineIdSource::Mac
+        && interfaces
+            .iter()
+            .any(|interface| interface.mac == selected_mac && is_randomized(interface));
+    if randomized {
+        let message = "Only a randomized Wi-Fi MAC address was available; the binding breaks \
+                       when the privacy setting or the network changes - disable random \
+                       hardware addresses or use passphrase mode"
+            .to_string();
+        println!("WARNING: {}", message);
+        warning = Some(message);
+    }
+
+    // A team's MAC is borrowed from one of its members and can change when
+    // the team is rebuilt. A member sharing the MAC means it is stable.
+    let mut owners = interfaces
+        .iter()
+        .filter(|interface| interface.mac == selected_mac)
+        .peekable();
+    let selected_team = owners.peek().is_some() && owners.all(|interface| is_team(&interface.name));
+    if selected_team {
+        let message = "Only a teamed network adapter was found; its MAC can change when the \
+                       team is rebuilt - consider passphrase mode"
+            .to_string();
+        println!("WARNING: {}", message);
+        warning = Some(message);
+    }
+
+    if let (Some(preferred), Some(false)) = (&preference, preference_present) {
+        let message = format!(
+            "Preferred interface {} ({}) is no longer present; bound to {} instead",
+            preferred.name, preferred.mac, selected_mac
+        );
+        println!("WARNING: {}", message);
+        warning = Some(message);
+    }
+
+    println!("MAC selection decision: {}", decision);
+
+    MacSelection {
+        mac: selected_mac,
+        source,
+        decision: decision.to_string(),
+        interfaces,
+        warning,
+        randomized,
+    }
+}
+
+// An adapter as offered to the operator when picking the binding interface
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InterfaceOption {
+    name: String,
+    mac: String,
+    is_virtual: bool,
+    // USB or docking-station adapter
+    removable: bool,
+    // Never considered by the automatic selection, and why
+    excluded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exclusion_reason: Option<String>,
+    // The adapter the automatic selection would bind to
+    selected: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InterfaceList {
+    interfaces: Vec<InterfaceOption>,
+    selected_mac: String,
+    // "mac", or the machine identifier used when no adapter qualified
+    source: String,
+    // Which rule picked selected_mac
+    decision: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+// Command to report how this machine's identity was detected
+#[tauri::command]
+pub async fn get_machine_info() -> Result<MachineInfo, String> {
+    tokio::task::spawn_blocking(detect_machine_info)
+        .await
+        .map_err(|e| format!("Machine detection failed: {}", e))
+}
+
+// Command to detect the adapters again, e.g. after the user plugged in a
+// different one, returning the same list as list_network_interfaces
+#[tauri::command]
+pub async fn refresh_machine_info() -> Result<InterfaceList, String> {
+    clear_mac_cache();
+    list_network_interfaces().await
+}
+
+// Command to list the network adapters and the one automatic selection picks,
+// so the operator can choose the binding interface explicitly
+#[tauri::command]
+pub async fn list_network_interfaces() -> Result<InterfaceList, String> {
+    let selection = tokio::task::spawn_blocking(detect_mac)
+        .await
+        .map_err(|e| format!("MAC detection failed: {}", e))?;
+    let filters = read_interface_filters();
+    let interfaces = selection
+        .interfaces
+        .iter()
+        .map(|interface| {
+            let exclusion_reason = filters.exclusion_reason(&interface.name);
+            InterfaceOption {
+                name: interface.name.clone(),
+                mac: interface.mac.clone(),
+                is_virtual: interface.is_virtual,
+                removable: interface.removable,
+                excluded: exclusion_reason.is_some(),
+                exclusion_reason,
+                selected: selection.source == MachineIdSource::Mac
+                    && interface.mac == selection.mac,
+            }
+        })
+        .collect();
+
+    Ok(InterfaceList {
+        interfaces,
+        selected_mac: selection.mac,
+        source: selection.source.as_str().to_string(),
+        decision: selection.decision,
+        warning: selection.warning,
+    })
+}
+
+// Command to remember the adapter the operator chose, so every later command
+// binds to it while it is present. It replaces any automatic pick remembered
+// by an earlier save.
+#[tauri::command]
+pub async fn choose_network_interface(mac: String) -> Result<InterfaceList, String> {
+    let selection = tokio::task::spawn_blocking(detect_mac)
+        .await
+        .map_err(|e| format!("MAC detection failed: {}", e))?;
+    let selection = choose_interface(selection, &mac)?;
+    remember_interface(&selection.interfaces, &selection.mac)?;
+    clear_mac_cache();
+    list_network_interfaces().await
+}
+
+// Function to bind to the adapter the operator chose instead of the automatic
+// pick, for one command only. The MAC may be written in any form
+// normalize_mac accepts.
+pub fn choose_interface(mut selection: MacSelection, mac: &str) -> Result<MacSelection, String> {
+    let mac = normalize_mac(mac).ok_or_else(|| format!("Invalid MAC address: {}", mac))?;
+    let interface = selection
+        .interfaces
+        .iter()
+        .find(|interface| interface.mac == mac)
+        .ok_or_else(|| format!("No network interface with MAC {} on this machine", mac))?;
+    println!(
+        "Using chosen interface: {} with MAC: {}",
+        interface.name, mac
+    );
+
+    let randomized = is_randomized(interface);
+
+    selection.mac = mac;
+    selection.randomized = randomized;
+    selection.source = MachineIdSource::Mac;
+    selection.decision = "chosen".to_string();
+    Ok(selection)
+}
+
+// Function to find the present adapter a file was bound to when automatic
+// selection now lands on another one, e.g. after a driver update
+pub fn match_present_interface(mac: &str) -> Option<String> {
+    let selection = detect_mac();
+    let filters = read_interface_filters();
+    let name = selection
+        .interfaces
+        .iter()
+        .filter(|interface| filters.exclusion_reason(&interface.name).is_none())
+        .take(MAX_MAC_CANDIDATES)
+        .find(|interface| same_mac(mac, &interface.mac))
+        .map(|interface| interface.name.clone())?;
+
+    println!("File is bound to present adapter {} ({})", name, mac);
+    Some(name)
+}
+
+// Function to list every adapter MAC a file can be bound to at once: the
+// physical adapters selection would consider, randomized Wi-Fi addresses
+// and teams left out since they don't survive a reconnect or rebuild
+pub fn eligible_binding_macs(selection: &MacSelection) -> Vec<String> {
+    let filters = read_interface_filters();
+    let mut macs: Vec<String> = selection
+        .interfaces
+        .iter()
+        .filter(|interface| {
+            filters.exclusion_reason(&interface.name).is_none()
+                && !interface.is_virtual
+                && !is_team(&interface.name)
+                && !is_randomized(interface)
+        })
+        .map(|interface| interface.mac.clone())
+        .collect();
+    macs.sort();
+    macs.dedup();
+    macs.truncate(MAX_MAC_CANDIDATES);
+    macs
+}
+
+// The adapter remembered for binding
+#[derive(Debug, Serialize, Deserialize)]
+struct InterfacePreference {
+    mac: String,
+    name: String,
+}
+
+// Command to forget the remembered adapter so the next command selects one
+// automatically again
+#[tauri::command]
+pub async fn reset_interface_preference() -> Result<bool, String> {
+    let path = get_config_dir().join(INTERFACE_PREFERENCE_FILE);
+    if !path.exists() {
+        return Ok(false);
+    }
+    std::fs::remove_file(&path)
+        .map_err(|e| format!("Failed to remove interface preference: {}", e))?;
+    clear_mac_cache();
+    Ok(true)
+}
+
+fn read_interface_preference() -> Option<InterfacePreference> {
+    let contents =
+        std::fs::read_to_string(get_config_dir().join(INTERFACE_PREFERENCE_FILE)).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+// Function to remember the automatically selected adapter the first time a
+// config is saved bound to it, so later commands consult it first. Read-only
+// commands never save a preference; failures only cost the stickiness.
+pub fn remember_first_selection(selection: &MacSelection) {
+    if selection.source != MachineIdSource::Mac
+        || selection.mac == FALLBACK_MAC
+        || selection.randomized
+        || read_interface_preference().is_some()
+    {
+        return;
+    }
+    match remember_interface(&selection.interfaces, &selection.mac) {
+        Ok(()) => clear_mac_cache(),
+        Err(e) => println!("{}", e),
+    }
+}
+
+// Function to remember the adapter that owns a MAC
+fn remember_interface(interfaces: &[NetworkInterface], mac: &str) -> Result<(), String> {
+    let interface = interfaces
+        .iter()
+        .find(|interface| interface.mac == mac)
+        .ok_or_else(|| format!("No network interface with MAC {} on this machine", mac))?;
+    let preference = InterfacePreference {
+        mac: interface.mac.clone(),
+        name: interface.name.clone(),
+    };
+
+    let path = get_config_dir().join(INTERFACE_PREFERENCE_FILE);
+    std::fs::create_dir_all(get_config_dir())
+        .map_err(|e| e.to_string())
+        .and_then(|_| serde_json::to_string(&preference).map_err(|e| e.to_string()))
+        .and_then(|contents| std::fs::write(&path, contents).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Failed to save interface preference: {}", e))?;
+    println!(
+        "Remembered interface: {} with MAC: {}",
+        preference.name, preference.mac
+    );
+    Ok(())
+}
+
+// Adapter name patterns set by support for adapters the built-in list
+// doesn't know, e.g. a security product's VPN adapter. Patterns are
+// case-insensitive substrings; deny wins over allow, and allow re-admits
+// an adapter the built-in list excludes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InterfaceFilters {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl InterfaceFilters {
+    // Function to tell why an adapter is excluded from selection, if it is
+    fn exclusion_reason(&self, name: &str) -> Option<String> {
+        let name_lower = name.to_lowercase();
+        if let Some(pattern) = self
+            .deny
+            .iter()
+            .find(|pattern| name_lower.contains(&pattern.to_lowercase()))
+        {
+            return Some(format!("denied by pattern '{}'", pattern));
+        }
+        if self
+            .allow
+            .iter()
+            .any(|pattern| name_lower.contains(&pattern.to_lowercase()))
+        {
+            return None;
+        }
+        exclusion_reason(name).map(str::to_string)
+    }
+}
+
+// Command to get the operator's interface allow and deny patterns
+#[tauri::command]
+pub async fn get_interface_filters() -> Result<InterfaceFilters, String> {
+    Ok(read_interface_filters())
+}
+
+// Command to replace the interface allow and deny patterns. Detection runs
+// again on the next command, so list_network_interfaces shows the effect
+// before anything is re-encrypted.
+#[tauri::command]
+pub async fn set_interface_filters(filters: InterfaceFilters) -> Result<InterfaceFilters, String> {
+    let clean = |patterns: Vec<String>| -> Vec<String> {
+        patterns
+            .into_iter()
+            .map(|pattern| pattern.trim().to_string())
+            .filter(|pattern| !pattern.is_empty())
+            .collect()
+    };
+    let filters = InterfaceFilters {
+        allow: clean(filters.allow),
+        deny: clean(filters.deny),
+    };
+
+    let contents = serde_json::to_string_pretty(&filters)
+        .map_err(|e| format!("Failed to serialize interface filters: {}", e))?;
+    std::fs::create_dir_all(get_config_dir())
+        .map_err(|e| format!("Failed to create directory: {}", e))?;
+    std::fs::write(get_config_dir().join(INTERFACE_FILTERS_FILE), contents)
+        .map_err(|e| format!("Failed to save interface filters: {}", e))?;
+    clear_mac_cache();
+    Ok(filters)
+}
+
+// A missing or unreadable file means no operator patterns
+fn read_interface_filters() -> InterfaceFilters {
+    let Ok(contents) = std::fs::read_to_string(get_config_dir().join(INTERFACE_FILTERS_FILE))
+    else {
+        return InterfaceFilters::default();
+    };
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        println!("Ignoring invalid interface filters: {}", e);
+        InterfaceFilters::default()
+    })
+}
+
+// Function to list the adapters that have a MAC address. WMI is used first
+// since it tells physical adapters apart; the OS adapter list covers a
+// disabled WMI service and ipconfig is only run when both come back empty.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn list_interfaces() -> Vec<NetworkInterface> {
+    let interfaces = match list_wmi_interfaces() {
+        Ok(interfaces) if !interfaces.is_empty() => interfaces,
+        Ok(_) => {
+            println!("No interfaces from WMI, falling back to the OS adapter list");
+            list_os_interfaces()
+        }
+        Err(e) => {
+            println!(
+                "WMI unavailable ({}), falling back to the OS adapter list",
+                e
+            );
+            list_os_interfaces()
+        }
+    };
+    if interfaces.is_empty() {
+        println!("No interfaces from the OS adapter list, falling back to ipconfig");
+        return list_ipconfig_interfaces();
+    }
+
+    // Debug builds also run ipconfig and report when the two sources see
+    // different adapters, since a missing adapter can change the binding.
+    // ipconfig can't report the default route, so selections aren't compared.
+    if cfg!(debug_assertions) {
+        let mut library_macs: Vec<String> = interfaces.iter().map(|i| i.mac.clone()).collect();
+        let mut ipconfig_macs: Vec<String> = list_ipconfig_interfaces()
+            .into_iter()
+            .map(|i| i.mac)
+            .collect();
+        library_macs.sort();
+        ipconfig_macs.sort();
+        if library_macs != ipconfig_macs {
+            println!(
+                "WARNING: adapter list reports {:?} but ipconfig reports {:?}",
+                library_macs, ipconfig_macs
+            );
+        }
+    }
+
+    interfaces
+}
+
+// Function to list adapters through the network-interface crate, which reads
+// GetAdaptersAddresses directly instead of parsing command output
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn list_os_interfaces() -> Vec<NetworkInterface> {
+    use network_interface::{Addr, NetworkInterfaceConfig};
+
+    let default_route_ips = default_route_interface_ips();
+    let adapters = match network_interface::NetworkInterface::show() {
+        Ok(adapters) => adapters,
+        Err(e) => {
+            println!("Failed to enumerate network adapters: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut interfaces: Vec<NetworkInterface> = Vec::new();
+    for adapter in adapters {
+        let Some(mac) = adapter.mac_addr.as_deref().and_then(normalize_mac) else {
+            continue;
+        };
+
+        // A self-assigned 169.254 address means DHCP failed, not a live link
+        let ipv4: Vec<String> = adapter
+            .addr
+            .iter()
+            .filter_map(|addr| match addr {
+                Addr::V4(v4) => Some(v4.ip.to_string()),
+                Addr::V6(_) => None,
+            })
+            .collect();
+        let connected = ipv4.iter().any(|ip| !ip.starts_with("169.254."));
+        let default_route = ipv4.iter().any(|ip| default_route_ips.contains(ip));
+
+        // Adapters can be reported once per address family
+        if let Some(existing) = interfaces
+            .iter_mut()
+            .find(|interface| interface.name == adapter.name)
+        {
+            existing.connected |= connected;
+            existing.default_route |= default_route;
+            continue;
+        }
+
+        interfaces.push(NetworkInterface {
+            is_virtual: is_virtual(&adapter.name),
+            removable: is_usb_name(&adapter.name),
+            name: adapter.name,
+            mac,
+            connected,
+            default_route,
+        });
+    }
+
+    interfaces
+}
+
+// Function to get the local addresses of the interfaces carrying the IPv4
+// default route, from the "0.0.0.0 0.0.0.0 <gateway> <interface> <metric>"
+// rows of `route print`. The rows are numeric, so localization doesn't matter.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn default_route_interface_ips() -> Vec<String> {
+    let output = match run_command("route", &["print", "-4", "0.0.0.0"], COMMAND_TIMEOUT) {
+        Ok(output) => output,
+        Err(e) => {
+            println!("{}", e);
+            return Vec::new();
+        }
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["0.0.0.0", "0.0.0.0", _gateway, interface, _metric] => Some(interface.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// Row of Win32_NetworkAdapter. NetConnectionID is the name shown in Network
+// Connections ("Ethernet"); hidden miniports have none and go by Name.
+#[cfg_attr(any(target_os = "linux", target_os = "macos"), allow(dead_code))]
+#[derive(Debug, Deserialize)]
+#[serde(rename = "Win32_NetworkAdapter", rename_all = "PascalCase")]
+struct WmiNetworkAdapter {
+    index: u32,
+    #[serde(rename = "NetConnectionID")]
+    net_connection_id: Option<String>,
+    name: Option<String>,
+    #[serde(rename = "MACAddress")]
+    mac_address: Option<String>,
+    physical_adapter: Option<bool>,
+    net_enabled: Option<bool>,
+    #[serde(rename = "PNPDeviceID")]
+    pnp_device_id: Option<String>,
+}
+
+// Row of Win32_NetworkAdapterConfiguration, joined to the adapter by Index
+#[cfg_attr(any(target_os = "linux", target_os = "macos"), allow(dead_code))]
+#[derive(Debug, Deserialize)]
+#[serde(
+    rename = "Win32_NetworkAdapterConfiguration",
+    rename_all = "PascalCase"
+)]
+struct WmiAdapterConfiguration {
+    index: u32,
+    #[serde(rename = "IPAddress")]
+    ip_address: Option<Vec<String>>,
+}
+
+// Function to list adapters through WMI
+#[cfg(windows)]
+fn list_wmi_interfaces() -> Result<Vec<NetworkInterface>, String> {
+    use wmi::{COMLibrary, WMIConnection};
+
+    let com = COMLibrary::new().map_err(|e| format!("Failed to initialize COM: {}", e))?;
+    let connection =
+        WMIConnection::new(com).map_err(|e| format!("Failed to connect to WMI: {}", e))?;
+    let adapters: Vec<WmiNetworkAdapter> = connection
+        .raw_query(
+            "SELECT Index, NetConnectionID, Name, MACAddress, PhysicalAdapter, NetEnabled, \
+             PNPDeviceID FROM Win32_NetworkAdapter WHERE MACAddress IS NOT NULL",
+        )
+        .map_err(|e| format!("Failed to query network adapters: {}", e))?;
+    let configurations: Vec<WmiAdapterConfiguration> = connection
+        .raw_query(
+            "SELECT Index, IPAddress FROM Win32_NetworkAdapterConfiguration WHERE IPEnabled = TRUE",
+        )
+        .map_err(|e| format!("Failed to query adapter configurations: {}", e))?;
+
+    Ok(map_wmi_adapters(
+        &adapters,
+        &configurations,
+        &default_route_interface_ips(),
+    ))
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+fn list_wmi_interfaces() -> Result<Vec<NetworkInterface>, String> {
+    Err("WMI is only available on Windows".to_string())
+}
+
+// Function to turn WMI rows into interfaces. Software-enumerated devices
+// (PNPDeviceID under ROOT\) are virtual even when the driver claims to be
+// physical, as some VPN clients do.
+#[cfg_attr(any(target_os = "linux", target_os = "macos"), allow(dead_code))]
+fn map_wmi_adapters(
+    adapters: &[WmiNetworkAdapter],
+    configurations: &[WmiAdapterConfiguration],
+    default_route_ips: &[String],
+) -> Vec<NetworkInterface> {
+    adapters
+        .iter()
+        .filter_map(|adapter| {
+            let mac = normalize_mac(adapter.mac_address.as_deref()?)?;
+            let name = adapter
+                .net_connection_id
+                .clone()
+                .or_else(|| adapter.name.clone())?;
+
+            // A self-assigned 169.254 address means DHCP failed, not a live link
+            let ipv4: Vec<&String> = configurations
+                .iter()
+                .filter(|configuration| configuration.index == adapter.index)
+                .flat_map(|configuration| configuration.ip_address.iter().flatten())
+                .filter(|ip| ip.contains('.'))
+                .collect();
+            let connected = adapter.net_enabled.unwrap_or(false)
+                && ipv4.iter().any(|ip| !ip.starts_with("169.254."));
+            let default_route = ipv4.iter().any(|ip| default_route_ips.contains(*ip));
+
+            let software_device = adapter
+                .pnp_device_id
+                .as_deref()
+                .is_some_and(|id| id.to_uppercase().starts_with("ROOT\\"));
+            // Dock and dongle NICs enumerate under the USB bus
+            let usb_device = adapter
+                .pnp_device_id
+                .as_deref()
+                .is_some_and(|id| id.to_uppercase().starts_with("USB\\"));
+            Some(NetworkInterface {
+                is_virtual: adapter.physical_adapter == Some(false)
+                    || software_device
+                    || is_virtual(&name),
+                removable: usb_device
+                    || is_usb_name(&name)
+                    || adapter.name.as_deref().is_some_and(is_usb_name),
+                name,
+                mac,
+                connected,
+                default_route,
+            })
+        })
+        .collect()
+}
+
+// Function to list the adapters reported by ipconfig that have a MAC address
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn list_ipconfig_interfaces() -> Vec<NetworkInterface> {
+    // Use ipconfig to get detailed network interface information on Windows
+    let output = match run_command("ipconfig", &["/all"], COMMAND_TIMEOUT) {
+        Ok(output) => output,
+        Err(e) => {
+            println!("{}", e);
+            return Vec::new();
+        }
+    };
+    let output_str = decode_console_output(&output.stdout, oem_code_page());
+
+    parse_ipconfig_output(&output_str)
+        .into_iter()
+        .map(|(name, mac)| NetworkInterface {
+            is_virtual: is_virtual(&name),
+            removable: is_usb_name(&name),
+            name,
+            mac,
+            connected: false,
+            default_route: false,
+        })
+        .collect()
+}
+
+// Function to decode console program output, which Windows writes in the
+// OEM code page rather than UTF-8. Adapter names on Spanish systems
+// ("Conexión de área local") are not valid UTF-8 there.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn decode_console_output(bytes: &[u8], code_page: u32) -> String {
+    match code_page {
+        CP_UTF8 => String::from_utf8_lossy(bytes).into_owned(),
+        CP_MULTILINGUAL_LATIN1 => bytes
+            .iter()
+            .map(|&b| match b {
+                0x00..=0x7F => b as char,
+                _ => CP850_HIGH[(b - 0x80) as usize],
+            })
+            .collect(),
+        _ => match String::from_utf8(bytes.to_vec()) {
+            Ok(output_str) => output_str,
+            Err(_) => {
+                // MACs and section layout are ASCII, so detection still works;
+                // only non-ASCII adapter names come out garbled
+                println!(
+                    "WARNING: ipconfig output in code page {} is not UTF-8, adapter names may be garbled",
+                    code_page
+                );
+                String::from_utf8_lossy(bytes).into_owned()
+            }
+        },
+    }
+}
+
+// Function to get the console OEM code page
+#[cfg(windows)]
+fn oem_code_page() -> u32 {
+    // SAFETY: GetOEMCP takes no arguments and only reads system settings
+    unsafe { windows_sys::Win32::Globalization::GetOEMCP() }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+fn oem_code_page() -> u32 {
+    CP_UTF8
+}
+
+// Function to list the hardware ports reported by networksetup
+#[cfg(target_os = "macos")]
+fn list_interfaces() -> Vec<NetworkInterface> {
+    let output = match run_command("networksetup", &["-listallhardwareports"], COMMAND_TIMEOUT) {
+        Ok(output) => output,
+        Err(e) => {
+            println!("{}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut interfaces = parse_networksetup_output(&String::from_utf8_lossy(&output.stdout));
+
+    // `route -n get default` names the device carrying the default route
+    let default_device = run_command("route", &["-n", "get", "default"], COMMAND_TIMEOUT)
+        .ok()
+        .and_then(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find_map(|line| {
+                    line.trim()
+                        .strip_prefix("interface:")
+                        .map(|d| d.trim().to_string())
+                })
+        });
+    if let Some(device) = default_device {
+        let suffix = format!("({})", device);
+        for interface in interfaces.iter_mut().filter(|i| i.name.ends_with(&suffix)) {
+            interface.connected = true;
+            interface.default_route = true;
+        }
+    }
+
+    interfaces
+}
+
+// Function to parse `networksetup -listallhardwareports` output. Each port is
+// a "Hardware Port / Device / Ethernet Address" block; ports without an
+// address and bridge, awdl (AirDrop) and utun (VPN tunnel) devices are skipped.
+// Interfaces are named "<port> (<device>)", e.g. "Wi-Fi (en0)".
+#[cfg(target_os = "macos")]
+fn parse_networksetup_output(output_str: &str) -> Vec<NetworkInterface> {
+    let mut interfaces = Vec::new();
+    let mut port = String::new();
+    let mut device = String::new();
+
+    for line in output_str.lines() {
+        let line = line.trim();
+        if let Some(value) = line.strip_prefix("Hardware Port:") {
+            port = value.trim().to_string();
+            device.clear();
+        } else if let Some(value) = line.strip_prefix("Device:") {
+            device = value.trim().to_string();
+        } else if let Some(value) = line.strip_prefix("Ethernet Address:") {
+            let excluded = ["bridge", "awdl", "utun", "llw"]
+                .iter()
+                .any(|prefix| device.starts_with(prefix));
+            let Some(mac) = normalize_mac(value).filter(|_| !excluded) else {
+                continue;
+            };
+
+            let name = format!("{} ({})", port, device);
+            interfaces.push(NetworkInterface {
+                is_virtual: is_virtual(&name),
+                removable: is_usb_name(&name),
+                name,
+                mac,
+                connected: false,
+                default_route: false,
+            });
+        }
+    }
+
+    interfaces
+}
+
+// Function to list the adapters under /sys/class/net that have a MAC address
+#[cfg(target_os = "linux")]
+fn list_interfaces() -> Vec<NetworkInterface> {
+    let mut interfaces = list_sysfs_interfaces(std::path::Path::new("/sys/class/net"));
+
+    // /proc/net/route lists the default route with destination 00000000
+    let route_table = std::fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let default_devices: Vec<&str> = route_table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields.len() > 1 && fields[1] == "00000000").then_some(fields[0])
+        })
+        .collect();
+    for interface in interfaces.iter_mut() {
+        interface.default_route = default_devices.contains(&interface.name.as_str());
+    }
+
+    interfaces
+}
+
+// Function to read interfaces from a sysfs-style directory: one entry per
+// interface holding an `address` file, plus a `device` link for hardware
+// NICs and a `bridge` directory for bridges
+#[cfg(target_os = "linux")]
+fn list_sysfs_interfaces(net_dir: &std::path::Path) -> Vec<NetworkInterface> {
+    let entries = match std::fs::read_dir(net_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut interfaces: Vec<NetworkInterface> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            if name == "lo"
+                || name.starts_with("veth")
+                || name.starts_with("docker")
+                || name.starts_with("br-")
+                || name.starts_with("virbr")
+                || path.join("bridge").exists()
+            {
+                return None;
+            }
+
+            // A bond member reports the bond's MAC as its address; the
+            // permanent hardware MAC is kept under bonding_slave
+            let address = std::fs::read_to_string(path.join("bonding_slave/perm_hwaddr"))
+                .or_else(|_| std::fs::read_to_string(path.join("address")))
+                .ok()?;
+            let mac = normalize_mac(&address)?;
+
+            let operstate = std::fs::read_to_string(path.join("operstate")).unwrap_or_default();
+
+            // The device's subsystem is the bus it sits on
+            let removable = std::fs::read_link(path.join("device/subsystem"))
+                .is_ok_and(|subsystem| subsystem.ends_with("usb"));
+
+            Some(NetworkInterface {
+                is_virtual: !path.join("device").exists(),
+                removable,
+                name,
+                mac,
+                connected: operstate.trim() == "up",
+                default_route: false,
+            })
+        })
+        .collect();
+
+    // read_dir order is unspecified; sort so selection is repeatable
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    interfaces
+}
+
+// One adapter section of `ipconfig /all`: its header and the lines below it
+#[cfg_attr(any(target_os = "linux", target_os = "macos"), allow(dead_code))]
+struct IpconfigBlock<'a> {
+    header: &'a str,
+    lines: Vec<&'a str>,
+}
+
+// Function to parse `ipconfig /all` output into (adapter name, MAC) pairs.
+// Labels are translated on localized Windows ("Adaptador de Ethernet",
+// "Dirección física"), so adapter headers are recognised by being
+// unindented lines ending in ':' and the physical address by its
+// XX-XX-XX-XX-XX-XX value rather than by its label. The output is split
+// into blocks first, so a MAC can only belong to the block it is printed
+// in, wherever it appears there. Disconnected adapters and tunnels without
+// a physical address give blocks with no MAC, which are dropped.
+#[cfg_attr(any(target_os = "linux", target_os = "macos"), allow(dead_code))]
+fn parse_ipconfig_output(output_str: &str) -> Vec<(String, String)> {
+    split_ipconfig_blocks(output_str)
+        .iter()
+        .filter_map(|block| Some((block.header.to_string(), ipconfig_block_mac(block)?)))
+        .collect()
+}
+
+#[cfg_attr(any(target_os = "linux", target_os = "macos"), allow(dead_code))]
+fn split_ipconfig_blocks(output_str: &str) -> Vec<IpconfigBlock<'_>> {
+    let mut blocks: Vec<IpconfigBlock> = Vec::new();
+    for raw_line in output_str.lines() {
+        if is_adapter_header(raw_line) {
+            // French output has a space before the colon
+            let header = raw_line.trim().trim_end_matches(':').trim_end();
+            blocks.push(IpconfigBlock {
+                header,
+                lines: Vec::new(),
+            });
+        } else if let Some(block) = blocks.last_mut() {
+            // Lines above the first header are the global section
+            block.lines.push(raw_line.trim());
+        }
+    }
+    blocks
+}
+
+// Function to find the physical address in a block: the first MAC-shaped
+// token on any line, so the label and the position of its colon don't
+// matter. An all-zero address is skipped for a later one.
+#[cfg_attr(any(target_os = "linux", target_os = "macos"), allow(dead_code))]
+fn ipconfig_block_mac(block: &IpconfigBlock) -> Option<String> {
+    block
+        .lines
+        .iter()
+        .flat_map(|line| line.split_whitespace())
+        .filter(|token| is_ipconfig_mac(token))
+        .find_map(normalize_mac)
+}
+
+// Adapter headers are the only unindented lines ending in ':'; the
+// "Windows IP Configuration" title has no colon
+#[cfg_attr(any(target_os = "linux", target_os = "macos"), allow(dead_code))]
+fn is_adapter_header(raw_line: &str) -> bool {
+    !raw_line.is_empty()
+        && !raw_line.starts_with(char::is_whitespace)
+        && raw_line.trim_end().ends_with(':')
+}
+
+// A MAC as printed by ipconfig: six hex pairs separated by '-' (or ':' by
+// some builds). DUIDs use the same notation but are longer, so the exact
+// shape matters.
+#[cfg_attr(any(target_os = "linux", target_os = "macos"), allow(dead_code))]
+fn is_ipconfig_mac(value: &str) -> bool {
+    ['-', ':'].iter().any(|separator| {
+        let groups: Vec<&str> = value.split(*separator).collect();
+        groups.len() == 6
+            && groups
+                .iter()
+                .all(|group| group.len() == 2 && group.chars().all(|c| c.is_ascii_hexdigit()))
+    })
+}
+
+// Function to pick the MAC, returning it with the rule that chose it. Past
+// the first rule this is the same selection logic as the Go app.
+fn select_mac(
+    interfaces: &[NetworkInterface],
+    filters: &InterfaceFilters,
+) -> Option<(String, &'static str)> {
+    let mut interfaces: Vec<&NetworkInterface> = interfaces
+        .iter()
+        .filter(|interface| {
+            let reason = filters.exclusion_reason(&interface.name);
+            if let Some(reason) = &reason {
+                println!("Excluded interface: {} ({})", interface.name, reason);
+            }
+            reason.is_none()
+        })
+        .collect();
+
+    // Enumeration order isn't stable across boots, so equally eligible
+    // adapters are ranked by MAC; each rule below takes the lowest
+    interfaces.sort_by(|a, b| a.mac.cmp(&b.mac));
+
+    // A dock or dongle NIC is gone once the laptop is undocked, so it only
+    // wins when there is no internal adapter to bind to
+    let has_internal = interfaces.iter().any(|interface| {
+        !interface.is_virtual && !interface.removable && !is_randomized(interface)
+    });
+    let passed_over = |interface: &NetworkInterface| interface.removable && has_internal;
+
+    // The adapter the machine actually talks through: up and carrying the
+    // default route. On laptops this is Wi-Fi while the Ethernet port is idle.
+    // A randomized Wi-Fi address is passed over by the named rules so a
+    // wired adapter wins when there is one.
+    for interface in &interfaces {
+        if interface.connected
+            && interface.default_route
+            && !interface.is_virtual
+            && !is_team(&interface.name)
+            && !is_randomized(interface)
+            && !passed_over(interface)
+        {
+            println!(
+                "Selected connected interface: {} with MAC: {}",
+                interface.name, interface.mac
+            );
+            return Some((interface.mac.clone(), "connected_default_route"));
+        }
+    }
+
+    // Then try with preferred interfaces, passing over teams so a physical
+    // member adapter wins when it is visible
+    for interface in &interfaces {
+        if !interface.is_virtual
+            && !is_team(&interface.name)
+            && !is_randomized(interface)
+            && !passed_over(interface)
+            && is_preferred(&interface.name)
+        {
+            println!(
+                "Selected interface: {} with MAC: {}",
+                interface.name, interface.mac
+            );
+            return Some((interface.mac.clone(), "name_match"));
+        }
+    }
+
+    // A Hyper-V host can have nothing but vEthernet adapters. The one on the
+    // external switch takes over the physical card's MAC, outside the
+    // 00:15:5D range Hyper-V assigns, so it survives the switch being
+    // recreated; the one carrying the default route wins.
+    if interfaces.iter().all(|interface| interface.is_virtual) {
+        let external = interfaces
+            .iter()
+            .filter(|interface| is_external_vswitch(interface))
+            .min_by_key(|interface| (!interface.default_route, !interface.connected));
+        if let Some(interface) = external {
+            println!(
+                "Selected external virtual switch interface: {} with MAC: {}",
+                interface.name, interface.mac
+            );
+            return Some((interface.mac.clone(), EXTERNAL_VSWITCH_DECISION));
+        }
+    }
+
+    // Fallback to less strict criteria, a removable adapter and then a
+    // randomized address last
+    let fallback = interfaces
+        .iter()
+        .find(|interface| !is_randomized(interface) && !passed_over(interface))
+        .or_else(|| {
+            interfaces
+                .iter()
+                .find(|interface| !is_randomized(interface))
+        })
+        .or(interfaces.first());
+    fallback.map(|interface| {
+        println!(
+            "Fallback interface: {} with MAC: {}",
+            interface.name, interface.mac
+        );
+        (interface.mac.clone(), "fallback")
+    })
+}
+
+// A host vNIC whose MAC was copied from a physical card rather than
+// generated by Hyper-V
+fn is_external_vswitch(interface: &NetworkInterface) -> bool {
+    interface.name.to_lowercase().contains("vethernet")
+        && virtual_mac_vendor(&interface.mac).is_none()
+        && !is_locally_administered(&interface.mac)
+}
+
+// Ethernet and Wi-Fi adapters, as named by ipconfig
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn is_preferred(name: &str) -> bool {
+    let name_lower = name.to_lowercase();
+    name_lower.contains("ethernet") || name_lower.contains("wi-fi") || name_lower.contains("wlan")
+}
+
+// Ethernet and Wi-Fi adapters, as named by the kernel (eth0, enp3s0, wlp2s0)
+#[cfg(target_os = "linux")]
+fn is_preferred(name: &str) -> bool {
+    name.starts_with("eth") || name.starts_with("en") || name.starts_with("wl")
+}
+
+// Ethernet and Wi-Fi ports, with the built-in en0 first in line
+#[cfg(target_os = "macos")]
+fn is_preferred(name: &str) -> bool {
+    let name_lower = name.to_lowercase();
+    name_lower.contains("(en0)") || name_lower.contains("wi-fi") || name_lower.contains("ethernet")
+}
+
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+fn is_virtual(name: &str) -> bool {
+    let name_lower = name.to_lowercase();
+    name_lower.contains("virtual") || name_lower.contains("vpn") || name_lower.contains("vethernet")
+}
+
+// Adapters whose name or description says they hang off USB, e.g. "Realtek
+// USB GbE Family Controller" or macOS's "USB 10/100/1000 LAN"
+fn is_usb_name(name: &str) -> bool {
+    name.to_lowercase().contains("usb")
+}
+
+// Wi-Fi adapters, as named by ipconfig ("Wi-Fi", "Wireless"), the kernel
+// (wlan0, wlp2s0) or networksetup ("Wi-Fi (en0)")
+fn is_wifi(name: &str) -> bool {
+    let name_lower = name.to_lowercase();
+    name_lower.contains("wi-fi")
+        || name_lower.contains("wireless")
+        || name_lower.contains("wlan")
+        || name_lower.starts_with("wl")
+}
+
+// Function to check the locally-administered (U/L) bit of a MAC, which
+// randomized and software-assigned addresses set and burned-in ones don't
+fn is_locally_administered(mac: &str) -> bool {
+    mac.get(0..2)
+        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        .map(|byte| byte & 0x02 != 0)
+        .unwrap_or(false)
+}
+
+// A Wi-Fi adapter presenting a randomized address: Windows 10/11 "random
+// hardware addresses" and per-network private addresses
+fn is_randomized(interface: &NetworkInterface) -> bool {
+    is_wifi(&interface.name) && is_locally_administered(&interface.mac)
+}
+
+// Function to swap randomized Wi-Fi addresses for the adapter's permanent
+// hardware address where the OS reports one
+fn use_permanent_macs(interfaces: &mut [NetworkInterface]) {
+    for interface in interfaces.iter_mut() {
+        if !is_randomized(interface) {
+            continue;
+        }
+        match read_permanent_mac(&interface.name) {
+            Some(mac) if !is_locally_administered(&mac) => {
+                println!(
+                    "Using permanent MAC {} of {} instead of randomized {}",
+                    mac, interface.name, interface.mac
+                );
+                interface.mac = mac;
+            }
+            _ => println!(
+                "Randomized MAC on {}: {}, no permanent address found",
+                interface.name, interface.mac
+            ),
+        }
+    }
+}
+
+// Function to read an adapter's burned-in address with Get-NetAdapter, which
+// reports it even while a random address is in use
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn read_permanent_mac(name: &str) -> Option<String> {
+    let command = format!(
+        "(Get-NetAdapter -Name '{}' -ErrorAction Stop).PermanentAddress",
+        name.replace('\'', "''")
+    );
+    let output = run_command(
+        "powershell",
+        &["-NoProfile", "-NonInteractive", "-Command", &command],
+        COMMAND_TIMEOUT,
+    )
+    .ok()?;
+    normalize_mac(&String::from_utf8_lossy(&output.stdout))
+}
+
+// Function to read an adapter's burned-in address from `ethtool -P`
+#[cfg(target_os = "linux")]
+fn read_permanent_mac(name: &str) -> Option<String> {
+    let output = run_command("ethtool", &["-P", name], COMMAND_TIMEOUT).ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| {
+            line.split_once("address:")
+                .and_then(|(_, mac)| normalize_mac(mac))
+        })
+}
+
+// networksetup already reports the hardware address, never the private one
+#[cfg(target_os = "macos")]
+fn read_permanent_mac(_name: &str) -> Option<String> {
+    None
+}
+
+// Function to normalize a MAC to the form used everywhere: 12 upper-case hex
+// digits without separators. Accepts ':' and '-' (Linux, Windows) as well as
+// '.' (Cisco-style 0011.2233.4455) and rejects anything else, including
+// empty and all-zero values.
+pub fn normalize_mac(value: &str) -> Option<String> {
+    let mac = value.trim().replace([':', '-', '.'], "").to_uppercase();
+    let valid = mac.len() == 12
+        && mac.chars().all(|c| c.is_ascii_hexdigit())
+        && !mac.chars().all(|c| c == '0');
+    valid.then_some(mac)
+}
+
+// Function to compare a header MAC field with this machine's value. Keys are
+// derived from the header exactly as stored, so only comparisons normalize:
+// a field written lower-case or with separators by another platform still
+// matches. Values that aren't a single MAC (combined MAC and MachineGuid
+// hash) are compared ignoring case.
+pub fn same_mac(stored: &str, current: &str) -> bool {
+    let (stored, current) = match (normalize_mac(stored), normalize_mac(current)) {
+        (Some(stored), Some(current)) => (stored, current),
+        _ => (stored.to_ascii_uppercase(), current.to_ascii_uppercase()),
+    };
+    constant_time_eq(stored.as_bytes(), current.as_bytes())
+}
+
+// NIC teams and bonds: the Windows LBFO "Microsoft Network Adapter
+// Multiplexor" and Linux bonding masters
+fn is_team(name: &str) -> bool {
+    let name_lower = name.to_lowercase();
+    name_lower.contains("multiplexor")
+        || name_lower.contains("team")
+        || name_lower.starts_with("bond")
+}
+
+// Function to tell why the built-in list excludes an adapter, if it does
+fn exclusion_reason(name: &str) -> Option<&'static str> {
+    let name_lower = name.to_lowercase();
+    EXCLUDED_ADAPTERS
+        .iter()
+        .find(|(fragment, _)| name_lower.contains(fragment))
+        .map(|(_, reason)| *reason)
+}
+
+// Function to turn a machine identifier into a MAC-shaped value: the first
+// six bytes of its hash, so the raw identifier never appears in a file header
+pub fn get_machine_id_mac(source: MachineIdSource) -> Result<String, String> {
+    match source {
+        MachineIdSource::Mac => return Ok(detect_mac().mac),
+        MachineIdSource::MacAndMachineGuid => {
+            let guid_mac = get_machine_id_mac(MachineIdSource::MachineGuid)?;
+            return Ok(format!("{}{}", detect_mac().mac, guid_mac));
+        }
+        _ => {}
+    }
+
+    let machine_id = read_machine_id(source)?;
+    let mut hasher = Sha256::new();
+    hasher.update(MACHINE_ID_LABEL);
+    hasher.update(machine_id.as_bytes());
+    Ok(hex::encode_upper(&hasher.finalize()[..6]))
+}
+
+// The machine identifier this platform provides, if any
+fn platform_machine_id_source() -> Option<MachineIdSource> {
+    if cfg!(windows) {
+        Some(MachineIdSource::MachineGuid)
+    } else if cfg!(target_os = "linux") {
+        Some(MachineIdSource::MachineId)
+    } else {
+        None
+    }
+}
+
+// Function to read a machine identifier from the OS
+fn read_machine_id(source: MachineIdSource) -> Result<String, String> {
+    read_machine_id_with(source, read_registry_string)
+}
+
+// Function behind read_machine_id, with the registry read passed in so it
+// can be tested off Windows
+fn read_machine_id_with(
+    source: MachineIdSource,
+    read_registry: impl Fn(&str, &str) -> Result<String, String>,
+) -> Result<String, String> {
+    let machine_id = match source {
+        MachineIdSource::Mac | MachineIdSource::MacAndMachineGuid => {
+            return Err("A MAC is not a machine identifier".to_string())
+        }
+        MachineIdSource::MachineGuid => {
+            read_registry("HKLM\\SOFTWARE\\Microsoft\\Cryptography", "MachineGuid")?
+        }
+        MachineIdSource::MachineId => std::fs::read_to_string("/etc/machine-id")
+            .or_else(|_| std::fs::read_to_string("/var/lib/dbus/machine-id"))
+            .map_err(|e| format!("Failed to read machine-id: {}", e))?,
+    };
+
+    let machine_id = machine_id.trim().to_string();
+    if machine_id.is_empty() {
+        return Err(format!("The {} identifier is empty", source.as_str()));
+    }
+    Ok(machine_id)
+}
+
+// Function to read a REG_SZ value through reg.exe. All registry access goes
+// through here so other platforms fail with one clear error.
+#[cfg(windows)]
+fn read_registry_string(key: &str, value: &str) -> Result<String, String> {
+    let output = run_command("reg", &["query", key, "/v", value], COMMAND_TIMEOUT)?;
+    if !output.status.success() {
+        return Err(format!("Failed to read {} from the registry", value));
+    }
+    parse_reg_query(&String::from_utf8_lossy(&output.stdout), value)
+        .ok_or_else(|| format!("{} not found in the registry", value))
+}
+
+// Function to get the data of a value from "reg query" output, where the
+// value line reads "    <value>    REG_SZ    <data>"
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_reg_query(output: &str, value: &str) -> Option<String> {
+    output
+        .lines()
+        .find(|line| line.trim_start().starts_with(value))
+        .and_then(|line| line.split_whitespace().nth(2))
+        .map(|data| data.to_string())
+}
+
+#[cfg(not(windows))]
+fn read_registry_string(_key: &str, value: &str) -> Result<String, String> {
+    Err(format!("{} is only available on Windows", value))
+}
+
+// Function to get hostname for metadata, without its DNS suffix
+pub fn get_hostname_for_metadata() -> String {
+    normalize_hostname(&get_raw_hostname())
+}
+
+// Function to get the hostname as the OS reports it. After a domain join
+// this can be the FQDN (SERVER01.empresa.local) instead of the short name.
+pub fn get_raw_hostname() -> String {
+    read_hostname().unwrap_or_else(|| "unknown".to_string())
+}
+
+// Function to read the hostname, None when the OS has none to give (some
+// containers return an error or an empty name)
+fn read_hostname() -> Option<String> {
+    let name = hostname::get().ok()?.to_string_lossy().trim().to_string();
+    (!name.is_empty()).then_some(name)
+}
+
+// Function to strip the DNS suffix from a hostname. Keys are derived from the
+// HOST= value in the header, so files that stored an FQDN still decrypt.
+pub fn normalize_hostname(hostname: &str) -> String {
+    hostname.split('.').next().unwrap_or(hostname).to_string()
+}
+
+// Function to compare a header hostname with this machine's, ignoring DNS
+// suffixes and case (Windows hostnames are case-insensitive), so joining or
+// leaving a domain doesn't look like a different machine
+pub fn same_hostname(stored: &str, current: &str) -> bool {
+    constant_time_eq(
+        normalize_hostname(stored).to_ascii_uppercase().as_bytes(),
+        normalize_hostname(current).to_ascii_uppercase().as_bytes(),
+    )
+}
+
+// Function to read the SMBIOS system UUID, which survives rebuilding a VM from
+// a template while its MAC does not
+pub fn read_bios_uuid() -> Result<String, String> {
+    let uuid = read_raw_bios_uuid()?;
+    validate_bios_uuid(&uuid)
+}
+
+#[cfg(windows)]
+fn read_raw_bios_uuid() -> Result<String, String> {
+    let output = run_command("wmic", &["csproduct", "get", "uuid"], COMMAND_TIMEOUT)?;
+    parse_wmic_uuid(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| "wmic returned no UUID".to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn read_raw_bios_uuid() -> Result<String, String> {
+    // Only readable by root on most distributions
+    std::fs::read_to_string("/sys/class/dmi/id/product_uuid")
+        .map(|uuid| uuid.trim().to_string())
+        .map_err(|e| format!("Failed to read product_uuid: {}", e))
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn read_raw_bios_uuid() -> Result<String, String> {
+    Err("The BIOS UUID is not available on this platform".to_string())
+}
+
+// Function to parse `wmic csproduct get uuid` output: a "UUID" header line
+// followed by the value
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_wmic_uuid(output_str: &str) -> Option<String> {
+    output_str
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.eq_ignore_ascii_case("uuid"))
+        .map(str::to_string)
+}
+
+// Some vendors ship an empty, all-zero or all-F UUID, shared by every
+// machine of the model; those are rejected
+fn validate_bios_uuid(uuid: &str) -> Result<String, String> {
+    let uuid = uuid.trim().to_uppercase();
+    let digits: String = uuid.chars().filter(|c| *c != '-').collect();
+    if digits.len() != 32 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not a UUID", uuid));
+    }
+    if digits.chars().all(|c| c == 'F') || digits.chars().all(|c| c == '0') {
+        return Err(format!("'{}' is a placeholder UUID", uuid));
+    }
+    Ok(uuid)
+}
+
+// OUI prefixes hypervisors assign to virtual NICs, as (MAC prefix, vendor).
+// A VM's adapter gets a new address from these ranges whenever it is
+// recreated or the VM is migrated, taking the binding with it.
+const VIRTUAL_MAC_PREFIXES: &[(&str, &str)] = &[
+    ("000569", "VMware"),
+    ("000C29", "VMware"),
+    ("001C14", "VMware"),
+    ("005056", "VMware"),
+    ("00155D", "Hyper-V"),
+    ("080027", "VirtualBox"),
+    ("525400", "KVM/QEMU"),
+    ("00163E", "Xen"),
+    ("001C42", "Parallels"),
+];
+
+// Fragments of the system model or vendor reported inside a VM
+const VIRTUAL_MACHINE_MODELS: &[&str] = &[
+    "virtual",
+    "vmware",
+    "kvm",
+    "qemu",
+    "xen",
+    "parallels",
+    "bochs",
+];
+
+// Function to get the hypervisor vendor a MAC was assigned by, if any
+pub fn virtual_mac_vendor(mac: &str) -> Option<&'static str> {
+    let mac = normalize_mac(mac)?;
+    VIRTUAL_MAC_PREFIXES
+        .iter()
+        .find(|(prefix, _)| mac.starts_with(prefix))
+        .map(|(_, vendor)| *vendor)
+}
+
+// Function to tell whether this machine is a VM, returning what gave it away.
+// The system model is checked first; the CPUID hypervisor bit is also set
+// on physical Windows hosts running Hyper-V or VBS, so it only decides when
+// the model is unavailable.
+pub fn detect_virtual_machine() -> Option<String> {
+    match read_system_model() {
+        Ok(model) => {
+            let model_lower = model.to_lowercase();
+            VIRTUAL_MACHINE_MODELS
+                .iter()
+                .any(|fragment| model_lower.contains(fragment))
+                .then_some(model)
+        }
+        Err(e) => {
+            println!("Failed to read system model: {}", e);
+            hypervisor_present().then(|| "hypervisor CPUID bit".to_string())
+        }
+    }
+}
+
+// Function to read the system vendor and model from Win32_ComputerSystem
+#[cfg(windows)]
+fn read_system_model() -> Result<String, String> {
+    use wmi::{COMLibrary, WMIConnection};
+
+    #[derive(Deserialize)]
+    #[serde(rename = "Win32_ComputerSystem", rename_all = "PascalCase")]
+    struct WmiComputerSystem {
+        manufacturer: Option<String>,
+        model: Option<String>,
+    }
+
+    let com = COMLibrary::new().map_err(|e| format!("Failed to initialize COM: {}", e))?;
+    let connection =
+        WMIConnection::new(com).map_err(|e| format!("Failed to connect to WMI: {}", e))?;
+    let systems: Vec<WmiComputerSystem> = connection
+        .raw_query("SELECT Manufacturer, Model FROM Win32_ComputerSystem")
+        .map_err(|e| format!("Failed to query computer system: {}", e))?;
+    let system = systems
+        .into_iter()
+        .next()
+        .ok_or_else(|| "WMI returned no computer system".to_string())?;
+    Ok(format!(
+        "{} {}",
+        system.manufacturer.unwrap_or_default(),
+        system.model.unwrap_or_default()
+    )
+    .trim()
+    .to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn read_system_model() -> Result<String, String> {
+    // Unlike product_uuid these are world-readable
+    let vendor = std::fs::read_to_string("/sys/class/dmi/id/sys_vendor").unwrap_or_default();
+    let model = std::fs::read_to_string("/sys/class/dmi/id/product_name")
+        .map_err(|e| format!("Failed to read product_name: {}", e))?;
+    Ok(format!("{} {}", vendor.trim(), model.trim())
+        .trim()
+        .to_string())
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn read_system_model() -> Result<String, String> {
+    Err("The system model is not available on this platform".to_string())
+}
+
+// Function to read the hypervisor-present bit (CPUID leaf 1, ECX bit 31)
+#[cfg(target_arch = "x86_64")]
+fn hypervisor_present() -> bool {
+    // CPUID leaf 1 is available on every x86_64 processor, so the intrinsic
+    // is safe to call
+    let leaf = std::arch::x86_64::__cpuid(1);
+    leaf.ecx & (1 << 31) != 0
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn hypervisor_present() -> bool {
+    false
+}
+
+// Function to read the local machine SID (S-1-5-21-...), which is created at
+// install time and survives NIC swaps and renames
+pub fn read_machine_sid() -> Result<String, String> {
+    let sid = read_raw_machine_sid()?;
+    if !sid.starts_with("S-1-5-21-") {
+        return Err(format!("'{}' is not a machine SID", sid));
+    }
+    Ok(sid)
+}
+
+// The computer name looks up as the account domain of the machine, whose
+// SID is the machine SID
+#[cfg(windows)]
+fn read_raw_machine_sid() -> Result<String, String> {
+    use windows_sys::core::PWSTR;
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Authorization::ConvertSidToStringSidW;
+    use windows_sys::Win32::Security::{LookupAccountNameW, SID_NAME_USE};
+
+    // SECURITY_MAX_SID_SIZE
+    const MAX_SID_LEN: usize = 68;
+
+    let name = std::env::var("COMPUTERNAME").unwrap_or_else(|_| get_hostname_for_metadata());
+    let wide_name: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+    let mut sid = vec![0u8; MAX_SID_LEN];
+    let mut sid_len = sid.len() as u32;
+    let mut domain = vec![0u16; 256];
+    let mut domain_len = domain.len() as u32;
+    let mut sid_type: SID_NAME_USE = 0;
+
+    // SAFETY: every buffer is passed with its real length
+    let ok = unsafe {
+        LookupAccountNameW(
+            std::ptr::null(),
+            wide_name.as_ptr(),
+            sid.as_mut_ptr() as _,
+            &mut sid_len,
+            domain.as_mut_ptr(),
+            &mut domain_len,
+            &mut sid_type,
+        )
+    };
+    if ok == 0 {
+        return Err(format!(
+            "LookupAccountName failed for {}: {}",
+            name,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let mut string_sid: PWSTR = std::ptr::null_mut();
+    // SAFETY: sid holds the SID LookupAccountNameW wrote
+    if unsafe { ConvertSidToStringSidW(sid.as_mut_ptr() as _, &mut string_sid) } == 0 {
+        return Err(format!(
+            "ConvertSidToStringSid failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    // SAFETY: string_sid is a NUL-terminated string allocated with LocalAlloc
+    unsafe {
+        let len = (0..).take_while(|&i| *string_sid.add(i) != 0).count();
+        let value = String::from_utf16_lossy(std::slice::from_raw_parts(string_sid, len));
+        LocalFree(string_sid as _);
+        Ok(value)
+    }
+}
+
+#[cfg(not(windows))]
+fn read_raw_machine_sid() -> Result<String, String> {
+    Err("The machine SID is only available on Windows".to_string())
+}
+
+// Recorded as the domain of a machine that isn't joined to one, so the
+// binding input is never empty. Not a valid DNS name, so no domain matches it.
+pub const WORKGROUP_DOMAIN: &str = "(workgroup)";
+
+// Function to read the DNS domain the machine is joined to, lowercased as
+// DNS names compare case-insensitively; WORKGROUP_DOMAIN when there is none
+pub fn read_machine_domain() -> Result<String, String> {
+    let domain = read_raw_machine_domain()?
+        .trim()
+        .trim_end_matches('.')
+        .to_lowercase();
+    if domain.is_empty() {
+        return Ok(WORKGROUP_DOMAIN.to_string());
+    }
+    if !domain
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    {
+        return Err(format!("'{}' is not a DNS domain name", domain));
+    }
+    Ok(domain)
+}
+
+// The primary DNS suffix, which is the AD domain on a domain member
+#[cfg(windows)]
+fn read_raw_machine_domain() -> Result<String, String> {
+    use windows_sys::Win32::System::SystemInformation::{
+        ComputerNameDnsDomain, GetComputerNameExW,
+    };
+
+    let mut buffer = vec![0u16; 256];
+    let mut len = buffer.len() as u32;
+    // SAFETY: buffer is passed with its real length in characters
+    let ok = unsafe { GetComputerNameExW(ComputerNameDnsDomain, buffer.as_mut_ptr(), &mut len) };
+    if ok == 0 {
+        return Err(format!(
+            "GetComputerNameEx failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(String::from_utf16_lossy(&buffer[..len as usize]))
+}
+
+// Everything after the first label of the fully qualified hostname
+#[cfg(not(windows))]
+fn read_raw_machine_domain() -> Result<String, String> {
+    let output = run_command("hostname", &["-f"], COMMAND_TIMEOUT)?;
+    if !output.status.success() {
+        return Err("hostname -f failed".to_string());
+    }
+    let fqdn = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(fqdn
+        .split_once('.')
+        .map(|(_, domain)| domain.to_string())
+        .unwrap_or_default())
+}
+
+// Function to combine MAC, BIOS UUID and machine SID (empty when not used),
+// hostname and domain (empty when not used) into the key derivation input
+pub fn get_computer_info(
+    mac: &str,
+    bios_uuid: &str,
+    machine_sid: &str,
+    hostname: &str,
+    domain: &str,
+) -> String {
+    format!("{}{}{}{}{}", mac, bios_uuid, machine_sid, hostname, domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const MACHINE_GUID: &str = "4c4c4544-0042-3510-8052-b4c04f4e3432";
+
+    #[test]
+    fn machine_guid_comes_from_the_cryptography_key() {
+        let machine_id = read_machine_id_with(MachineIdSource::MachineGuid, |key, value| {
+            assert_eq!(key, "HKLM\\SOFTWARE\\Microsoft\\Cryptography");
+            assert_eq!(value, "MachineGuid");
+            Ok(format!("  {}\r\n", MACHINE_GUID))
+        });
+        assert_eq!(machine_id.unwrap(), MACHINE_GUID);
+    }
+
+    #[test]
+    fn empty_machine_guid_is_an_error() {
+        let result =
+            read_machine_id_with(MachineIdSource::MachineGuid, |_, _| Ok("  ".to_string()));
+        assert_eq!(result.unwrap_err(), "The machine_guid identifier is empty");
+    }
+
+    #[test]
+    fn registry_errors_are_passed_through() {
+        let result = read_machine_id_with(MachineIdSource::MachineGuid, |_, value| {
+            Err(format!("{} not found in the registry", value))
+        });
+        assert_eq!(result.unwrap_err(), "MachineGuid not found in the registry");
+    }
+
+    #[test]
+    fn mac_sources_never_read_the_registry() {
+        for source in [MachineIdSource::Mac, MachineIdSource::MacAndMachineGuid] {
+            let result = read_machine_id_with(source, |_, _| panic!("registry read"));
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn reg_query_output_is_parsed() {
+        let output = "\r\nHKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Cryptography\r\n    \
+                      MachineGuid    REG_SZ    4c4c4544-0042-3510-8052-b4c04f4e3432\r\n\r\n";
+        assert_eq!(
+            parse_reg_query(output, "MachineGuid").as_deref(),
+            Some(MACHINE_GUID)
+        );
+        assert_eq!(parse_reg_query(output, "ProductId"), None);
+    }
+
+    // `ipconfig /all` of a Spanish Windows Server, decoded from CP850
+    const IPCONFIG_ES: &str = "\r
+Configuración IP de Windows\r
+\r
+   Nombre de host. . . . . . . . . : SERVIDOR-CAFÉ\r
+   Sufijo DNS principal  . . . . . : empresa.local\r
+   Tipo de nodo. . . . . . . . . . : híbrido\r
+\r
+Adaptador de Ethernet Ethernet:\r
+\r
+   Sufijo DNS específico para la conexión. . : empresa.local\r
+   Descripción . . . . . . . . . . . . . . . : Intel(R) Ethernet Connection I219-LM\r
+   Dirección física. . . . . . . . . . . . . : 00-1A-2B-3C-4D-5E\r
+   DHCP habilitado . . . . . . . . . . . . . : sí\r
+   IAID DHCPv6 . . . . . . . . . . . . . . . : 50331648\r
+   DUID de cliente DHCPv6. . . . . . . . . . : 00-01-00-01-2A-3B-4C-5D-00-1A-2B-3C-4D-5E\r
+\r
+Adaptador de LAN inalámbrica Wi-Fi:\r
+\r
+   Estado de los medios. . . . . . . . . . . : medios desconectados\r
+   Descripción . . . . . . . . . . . . . . . : Intel(R) Wi-Fi 6 AX201 160MHz\r
+   Dirección física. . . . . . . . . . . . . : A4-C3-F0-11-22-33\r
+\r
+Adaptador de túnel isatap.empresa.local:\r
+\r
+   Estado de los medios. . . . . . . . . . . : medios desconectados\r
+   Descripción . . . . . . . . . . . . . . . : Microsoft ISATAP Adapter\r
+   Dirección física. . . . . . . . . . . . . : 00-00-00-00-00-00-00-E0\r
+";
+
+    #[test]
+    fn spanish_ipconfig_output_is_parsed() {
+        assert_eq!(
+            parse_ipconfig_output(IPCONFIG_ES),
+            vec![
+                (
+                    "Adaptador de Ethernet Ethernet".to_string(),
+                    "001A2B3C4D5E".to_string()
+                ),
+                (
+                    "Adaptador de LAN inalámbrica Wi-Fi".to_string(),
+                    "A4C3F0112233".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn spanish_ipconfig_blocks_keep_their_own_lines() {
+        let blocks = split_ipconfig_blocks(IPCONFIG_ES);
+        let headers: Vec<&str> = blocks.iter().map(|block| block.header).collect();
+        assert_eq!(
+            headers,
+            [
+                "Adaptador de Ethernet Ethernet",
+                "Adaptador de LAN inalámbrica Wi-Fi",
+                "Adaptador de túnel isatap.empresa.local",
+            ]
+        );
+        // The DHCPv6 DUID has the same notation but isn't a MAC
+        assert!(!is_ipconfig_mac(
+            "00-01-00-01-2A-3B-4C-5D-00-1A-2B-3C-4D-5E"
+        ));
+        // The global section, "Nombre de host" included, is not an adapter
+        assert!(!is_adapter_header(
+            "   Nombre de host. . . . . . . . . : SERVIDOR-CAFÉ"
+        ));
+        assert!(!is_adapter_header("Configuración IP de Windows"));
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    #[test]
+    fn cp850_console_output_is_decoded() {
+        // "Dirección física" and "Conexión de área local" as a Spanish
+        // console writes them: ó = 0xA2, í = 0xA1, á = 0xA0
+        let bytes = b"Direcci\xa2n f\xa1sica\r\nConexi\xa2n de \xa0rea local";
+        assert_eq!(
+            decode_console_output(bytes, CP_MULTILINGUAL_LATIN1),
+            "Dirección física\r\nConexión de área local"
+        );
+        // The same bytes are not UTF-8 and would lose the accents
+        assert!(String::from_utf8(bytes.to_vec()).is_err());
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    #[test]
+    fn utf8_console_output_is_kept() {
+        let text = "Dirección física";
+        assert_eq!(decode_console_output(text.as_bytes(), CP_UTF8), text);
+    }
+
+    fn interface(name: &str, mac: &str) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            mac: mac.to_string(),
+            is_virtual: false,
+            connected: false,
+            default_route: false,
+            removable: false,
+        }
+    }
+
+    fn selected(interfaces: &[NetworkInterface]) -> Option<(String, &'static str)> {
+        select_mac(interfaces, &InterfaceFilters::default())
+    }
+
+    #[test]
+    fn selection_does_not_depend_on_enumeration_order() {
+        let first = interface("ethernet0", "001A2B3C4D5F");
+        let second = interface("ethernet1", "001A2B3C4D5E");
+        let expected = Some(("001A2B3C4D5E".to_string(), "name_match"));
+
+        assert_eq!(selected(&[first.clone(), second.clone()]), expected);
+        assert_eq!(selected(&[second, first]), expected);
+    }
+
+    #[test]
+    fn connected_default_route_wins_in_any_order() {
+        let idle = interface("ethernet0", "001A2B3C4D5E");
+        let mut active = interface("ethernet1", "001A2B3C4D5F");
+        active.connected = true;
+        active.default_route = true;
+        let expected = Some(("001A2B3C4D5F".to_string(), "connected_default_route"));
+
+        assert_eq!(selected(&[idle.clone(), active.clone()]), expected);
+        assert_eq!(selected(&[active, idle]), expected);
+    }
+
+    #[test]
+    fn dock_adapter_only_wins_without_an_internal_one() {
+        let internal = interface("ethernet1", "001A2B3C4D5F");
+        let mut dock = interface("ethernet0", "001A2B3C4D5E");
+        dock.removable = true;
+
+        assert_eq!(
+            selected(&[dock.clone(), internal.clone()]),
+            Some(("001A2B3C4D5F".to_string(), "name_match"))
+        );
+        assert_eq!(
+            selected(&[internal, dock.clone()]),
+            Some(("001A2B3C4D5F".to_string(), "name_match"))
+        );
+        assert_eq!(
+            selected(&[dock]),
+            Some(("001A2B3C4D5E".to_string(), "name_match"))
+        );
+    }
+
+    #[test]
+    fn excluded_adapters_are_never_selected() {
+        let loopback = interface("Npcap Loopback Adapter", "001A2B3C4D5D");
+        let ethernet = interface("ethernet0", "001A2B3C4D5E");
+        assert_eq!(
+            selected(&[loopback.clone(), ethernet]),
+            Some(("001A2B3C4D5E".to_string(), "name_match"))
+        );
+        assert_eq!(selected(&[loopback]), None);
+    }
+
+    #[test]
+    fn randomized_means_a_wifi_adapter_with_the_local_bit_set() {
+        // 0xDA = 1101_1010: the U/L bit (0x02) is set
+        assert!(is_randomized(&interface("Wi-Fi", "DA7B3C4D5E6F")));
+        assert!(is_randomized(&interface("wlp2s0", "0E7B3C4D5E6F")));
+        // 0xA4 = 1010_0100: a burned-in address
+        assert!(!is_randomized(&interface("Wi-Fi", "A4C3F0112233")));
+        // Locally administered, but not Wi-Fi: a VM or software adapter
+        assert!(!is_randomized(&interface("ethernet0", "DA7B3C4D5E6F")));
+        // The multicast bit (0x01) is not the U/L bit
+        assert!(!is_randomized(&interface("Wi-Fi", "017B3C4D5E6F")));
+    }
+
+    #[test]
+    fn locally_administered_bit_is_read_from_the_first_byte() {
+        for first in ["02", "06", "0A", "0E", "F2", "fe"] {
+            assert!(is_locally_administered(&format!("{}1122334455", first)));
+        }
+        for first in ["00", "01", "04", "A4", "FD"] {
+            assert!(!is_locally_administered(&format!("{}1122334455", first)));
+        }
+        assert!(!is_locally_administered(""));
+        assert!(!is_locally_administered("ZZ1122334455"));
+    }
+
+    #[test]
+    fn macs_normalize_from_any_separator_and_case() {
+        for value in [
+            "00:1a:2b:3c:4d:5e",
+            "00-1A-2B-3C-4D-5E",
+            "001a.2b3c.4d5e",
+            "001A2B3C4D5E",
+            "  00-1a-2B-3c-4D-5e\r\n",
+        ] {
+            assert_eq!(
+                normalize_mac(value).as_deref(),
+                Some("001A2B3C4D5E"),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_and_placeholder_macs_are_rejected() {
+        for value in [
+            "",
+            "00:1A:2B:3C:4D",
+            "00:1A:2B:3C:4D:5E:6F",
+            "00:1A:2B:3C:4D:5G",
+            "00 1A 2B 3C 4D 5E",
+            "00:00:00:00:00:00",
+        ] {
+            assert_eq!(normalize_mac(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn same_mac_ignores_case_and_separators() {
+        assert!(same_mac("00:1a:2b:3c:4d:5e", "001A2B3C4D5E"));
+        assert!(same_mac("00-1A-2B-3C-4D-5E", "00:1a:2b:3c:4d:5e"));
+        assert!(!same_mac("001A2B3C4D5E", "001A2B3C4D5F"));
+        // Combined MAC and MachineGuid hash values aren't MACs
+        assert!(same_mac(
+            "001A2B3C4D5EABCDEF012345",
+            "001a2b3c4d5eabcdef012345"
+        ));
+        assert!(!same_mac("001A2B3C4D5EABCDEF012345", "001A2B3C4D5E"));
+    }
+
+    #[test]
+    fn hostnames_compare_without_dns_suffix_or_case() {
+        assert_eq!(normalize_hostname("SERVIDOR01.empresa.local"), "SERVIDOR01");
+        assert_eq!(normalize_hostname("SERVIDOR01"), "SERVIDOR01");
+        assert!(same_hostname("SERVIDOR01.empresa.local", "SERVIDOR01"));
+        assert!(same_hostname("SERVIDOR01", "servidor01.EMPRESA.LOCAL"));
+        assert!(same_hostname("servidor01", "SERVIDOR01"));
+        assert!(!same_hostname("SERVIDOR01.empresa.local", "SERVIDOR02"));
+        // A different short name under the same domain is another machine
+        assert!(!same_hostname(
+            "SERVIDOR01.empresa.local",
+            "SERVIDOR02.empresa.local"
+        ));
+    }
+
+    fn wmi_adapter(index: u32, name: &str, mac: &str, pnp_device_id: &str) -> WmiNetworkAdapter {
+        WmiNetworkAdapter {
+            index,
+            net_connection_id: Some(name.to_string()),
+            name: Some(format!("{} adapter", name)),
+            mac_address: Some(mac.to_string()),
+            physical_adapter: Some(true),
+            net_enabled: Some(true),
+            pnp_device_id: Some(pnp_device_id.to_string()),
+        }
+    }
+
+    fn wmi_configuration(index: u32, ip_address: &[&str]) -> WmiAdapterConfiguration {
+        WmiAdapterConfiguration {
+            index,
+            ip_address: Some(ip_address.iter().map(|ip| ip.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn wmi_rows_are_joined_by_index() {
+        let adapters = [
+            wmi_adapter(1, "Ethernet", "00:1A:2B:3C:4D:5E", "PCI\\VEN_8086&DEV_15BB"),
+            wmi_adapter(2, "Wi-Fi", "A4:C3:F0:11:22:33", "PCI\\VEN_8086&DEV_A0F0"),
+        ];
+        let configurations = [
+            wmi_configuration(1, &["192.168.1.20", "fe80::1"]),
+            // DHCP failed on the Wi-Fi adapter
+            wmi_configuration(2, &["169.254.10.20"]),
+        ];
+        let interfaces =
+            map_wmi_adapters(&adapters, &configurations, &["192.168.1.20".to_string()]);
+
+        assert_eq!(interfaces.len(), 2);
+        assert_eq!(interfaces[0].name, "Ethernet");
+        assert_eq!(interfaces[0].mac, "001A2B3C4D5E");
+        assert!(interfaces[0].connected);
+        assert!(interfaces[0].default_route);
+        assert!(!interfaces[0].is_virtual);
+        assert_eq!(interfaces[1].mac, "A4C3F0112233");
+        assert!(!interfaces[1].connected);
+        assert!(!interfaces[1].default_route);
+    }
+
+    #[test]
+    fn wmi_software_and_usb_devices_are_flagged() {
+        // Some VPN drivers claim to be physical, as wmi_adapter rows do
+        let vpn = wmi_adapter(3, "Ethernet 2", "02:50:41:00:00:01", "ROOT\\NET\\0000");
+        let dock = wmi_adapter(
+            4,
+            "Ethernet 3",
+            "00:E0:4C:68:00:01",
+            "USB\\VID_0BDA&PID_8153",
+        );
+        let hidden = WmiNetworkAdapter {
+            net_connection_id: None,
+            name: Some("Intel(R) Ethernet Connection I219-LM".to_string()),
+            ..wmi_adapter(5, "", "00:1A:2B:3C:4D:60", "PCI\\VEN_8086")
+        };
+        let no_mac = WmiNetworkAdapter {
+            mac_address: None,
+            ..wmi_adapter(6, "Ethernet 4", "", "PCI\\VEN_8086")
+        };
+
+        let interfaces = map_wmi_adapters(&[vpn, dock, hidden, no_mac], &[], &[]);
+
+        assert_eq!(interfaces.len(), 3);
+        assert!(interfaces[0].is_virtual);
+        assert!(!interfaces[0].removable);
+        assert!(!interfaces[1].is_virtual);
+        assert!(interfaces[1].removable);
+        // A hidden miniport goes by its Name
+        assert_eq!(interfaces[2].name, "Intel(R) Ethernet Connection I219-LM");
+        assert!(interfaces.iter().all(|interface| !interface.connected));
+    }
+
+    #[test]
+    fn hypervisor_macs_are_recognised() {
+        assert_eq!(virtual_mac_vendor("00:15:5D:01:02:03"), Some("Hyper-V"));
+        assert_eq!(virtual_mac_vendor("08-00-27-AA-BB-CC"), Some("VirtualBox"));
+        assert_eq!(virtual_mac_vendor("52:54:00:12:34:56"), Some("KVM/QEMU"));
+        assert_eq!(virtual_mac_vendor("005056abcdef"), Some("VMware"));
+        assert_eq!(virtual_mac_vendor("00:0c:29:11:22:33"), Some("VMware"));
+        assert_eq!(virtual_mac_vendor("00:16:3E:11:22:33"), Some("Xen"));
+        assert_eq!(virtual_mac_vendor("00:1C:42:11:22:33"), Some("Parallels"));
+    }
+
+    #[test]
+    fn physical_and_invalid_macs_have_no_hypervisor() {
+        assert_eq!(virtual_mac_vendor("00:1A:2B:3C:4D:5E"), None);
+        // Only the prefix counts, not the OUI bytes further in
+        assert_eq!(virtual_mac_vendor("00:1A:00:15:5D:00"), None);
+        assert_eq!(virtual_mac_vendor("00:15:5D"), None);
+        assert_eq!(virtual_mac_vendor(""), None);
+    }
+
+    #[test]
+    fn wmic_uuid_output_is_parsed() {
+        let output = "UUID                                  \r\r\n\
+                      4c4c4544-0042-3510-8052-b4c04f4e3432  \r\r\n\r\r\n";
+        assert_eq!(
+            parse_wmic_uuid(output).as_deref(),
+            Some("4c4c4544-0042-3510-8052-b4c04f4e3432")
+        );
+        assert_eq!(parse_wmic_uuid("UUID\r\r\n\r\r\n"), None);
+        assert_eq!(parse_wmic_uuid(""), None);
+    }
+
+    #[test]
+    fn bios_uuids_are_upper_cased() {
+        assert_eq!(
+            validate_bios_uuid(" 4c4c4544-0042-3510-8052-b4c04f4e3432\n").unwrap(),
+            "4C4C4544-0042-3510-8052-B4C04F4E3432"
+        );
+    }
+
+    #[test]
+    fn placeholder_bios_uuids_are_rejected() {
+        for uuid in [
+            "00000000-0000-0000-0000-000000000000",
+            "FFFFFFFF-FFFF-FFFF-FFFF-FFFFFFFFFFFF",
+            "ffffffff-ffff-ffff-ffff-ffffffffffff",
+        ] {
+            let error = validate_bios_uuid(uuid).unwrap_err();
+            assert!(error.contains("placeholder"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn malformed_bios_uuids_are_rejected() {
+        for uuid in [
+            "",
+            "To Be Filled By O.E.M.",
+            "4C4C4544-0042-3510-8052-B4C04F4E343",
+            "4C4C4544-0042-3510-8052-B4C04F4E343G",
+        ] {
+            let error = validate_bios_uuid(uuid).unwrap_err();
+            assert!(error.contains("is not a UUID"), "{}", error);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sysfs_interfaces_are_read_from_a_fixture() {
+        use std::fs;
+        use std::os::unix::fs::symlink;
+
+        let net_dir = tempfile::tempdir().unwrap();
+        let add = |name: &str, address: &str, operstate: &str, device: bool| {
+            let path = net_dir.path().join(name);
+            fs::create_dir(&path).unwrap();
+            fs::write(path.join("address"), format!("{}\n", address)).unwrap();
+            fs::write(path.join("operstate"), format!("{}\n", operstate)).unwrap();
+            if device {
+                fs::create_dir(path.join("device")).unwrap();
+            }
+            path
+        };
+
+        add("lo", "00:00:00:00:00:00", "unknown", false);
+        add("enp3s0", "00:1a:2b:3c:4d:5e", "up", true);
+        let dongle = add("enx00e04c680001", "00:e0:4c:68:00:01", "down", true);
+        symlink("../../../../bus/usb", dongle.join("device/subsystem")).unwrap();
+        add("tun0", "02:50:41:00:00:01", "up", false);
+        add("docker0", "02:42:ac:11:00:01", "up", false);
+        add("virbr0", "52:54:00:12:34:56", "up", false);
+        let bridge = add("lan0", "00:1a:2b:3c:4d:70", "up", false);
+        fs::create_dir(bridge.join("bridge")).unwrap();
+        // A bond member reports the bond's MAC; perm_hwaddr is its own
+        let member = add("eno1", "00:1a:2b:3c:4d:5e", "up", true);
+        fs::create_dir(member.join("bonding_slave")).unwrap();
+        fs::write(
+            member.join("bonding_slave/perm_hwaddr"),
+            "00:1a:2b:3c:4d:5f\n",
+        )
+        .unwrap();
+        // No address file at all
+        fs::create_dir(net_dir.path().join("sit0")).unwrap();
+
+        let interfaces = list_sysfs_interfaces(net_dir.path());
+        let summary: Vec<(&str, &str, bool, bool, bool)> = interfaces
+            .iter()
+            .map(|interface| {
+                (
+                    interface.name.as_str(),
+                    interface.mac.as_str(),
+                    interface.is_virtual,
+                    interface.removable,
+                    interface.connected,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("eno1", "001A2B3C4D5F", false, false, true),
+                ("enp3s0", "001A2B3C4D5E", false, false, true),
+                ("enx00e04c680001", "00E04C680001", false, true, false),
+                ("tun0", "025041000001", true, false, true),
+            ]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn missing_sysfs_directory_lists_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(list_sysfs_interfaces(&dir.path().join("net")).is_empty());
+    }
+
+    fn counted_selection(passes: &AtomicU32) -> MacSelection {
+        passes.fetch_add(1, Ordering::SeqCst);
+        // Long enough for the other threads to be waiting on the cache
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        MacSelection {
+            mac: "001A2B3C4D5E".to_string(),
+            source: MachineIdSource::Mac,
+            decision: "name_match".to_string(),
+            interfaces: vec![interface("ethernet0", "001A2B3C4D5E")],
+            warning: None,
+            randomized: false,
+        }
+    }
+
+    #[test]
+    fn detection_runs_once_for_concurrent_callers() {
+        let cache = Mutex::new(None);
+        let passes = AtomicU32::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let selection = cached_detection(&cache, || counted_selection(&passes));
+                    assert_eq!(selection.mac, "001A2B3C4D5E");
+                });
+            }
+        });
+        assert_eq!(passes.load(Ordering::SeqCst), 1);
+
+        cached_detection(&cache, || counted_selection(&passes));
+        assert_eq!(passes.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn cleared_cache_detects_again() {
+        let cache = Mutex::new(None);
+        let passes = AtomicU32::new(0);
+        cached_detection(&cache, || counted_selection(&passes));
+        *cache.lock().unwrap() = None;
+        cached_detection(&cache, || counted_selection(&passes));
+        assert_eq!(passes.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,12 +1,39 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod audit;
 mod auth;
+mod bitrix;
+mod cli;
+mod command;
+mod config;
 mod encryption;
+mod escrow;
+mod lock;
+mod machine;
+mod protection;
+mod sage;
 mod service;
 
+use audit::{get_audit_log, verify_audit_log};
 use auth::{get_user_profile, login_api};
-use encryption::{config_exists, decrypt_json, encrypt_json};
+use bitrix::test_bitrix_connection;
+use config::generate_config_template;
+use encryption::{
+    calibrate_pbkdf2_iterations, check_tamper, config_diff, config_exists, config_status,
+    decrypt_bytes, decrypt_config, decrypt_json, decrypt_json_masked, delete_config,
+    diagnose_binding, encrypt_config, encrypt_json, get_computer_info_summary,
+    get_machine_fingerprint, import_and_encrypt, list_backups, list_configs, list_profiles,
+    migrate_to_random_iv, needs_iv_migration, probe_key_char, purge_deleted, rebind_to_passphrase,
+    recover_with_key, recover_with_share, repair_config, resolve_config_path, restore_backup,
+    rewrap_recovery_key, run_self_test,
+};
+use machine::{
+    choose_network_interface, get_interface_filters, get_machine_info, list_network_interfaces,
+    refresh_machine_info, reset_interface_preference, set_interface_filters,
+};
+use protection::tpm_available;
+use sage::test_sage_connection;
 use service::{check_service_status, start_service, echo_test, open_services_manager}; // Added open_services_manager
 use serde_json::json;
 use std::process;
@@ -20,6 +47,11 @@ fn force_exit() {
 }
 
 fn main() {
+    // `encrypt` and `decrypt` run the command-line interface instead of the app
+    if let Some(code) = cli::run(std::env::args().skip(1).collect()) {
+        process::exit(code);
+    }
+
     println!("Starting application");
 
     tauri::Builder::default()
@@ -27,9 +59,50 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             encrypt_json,
             decrypt_json,
+            encrypt_config,
+            decrypt_config,
+            decrypt_json_masked,
+            decrypt_bytes,
+            probe_key_char,
+            check_tamper,
+            run_self_test,
+            diagnose_binding,
+            get_machine_fingerprint,
+            get_computer_info_summary,
+            recover_with_share,
+            recover_with_key,
+            rewrap_recovery_key,
+            calibrate_pbkdf2_iterations,
+            get_audit_log,
+            verify_audit_log,
             login_api,
             get_user_profile,
+            test_bitrix_connection,
+            test_sage_connection,
             config_exists,
+            config_status,
+            list_configs,
+            list_profiles,
+            resolve_config_path,
+            delete_config,
+            list_backups,
+            restore_backup,
+            purge_deleted,
+            config_diff,
+            repair_config,
+            rebind_to_passphrase,
+            needs_iv_migration,
+            migrate_to_random_iv,
+            import_and_encrypt,
+            tpm_available,
+            list_network_interfaces,
+            choose_network_interface,
+            reset_interface_preference,
+            refresh_machine_info,
+            get_machine_info,
+            get_interface_filters,
+            set_interface_filters,
+            generate_config_template,
             force_exit,
             check_service_status,
             start_service,
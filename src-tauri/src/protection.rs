@@ -0,0 +1,350 @@
+// Platform key protection: the per-file data key is random and sealed by the
+// operating system instead of being derived from network adapter data.
+
+// Extra entropy so other DPAPI users on the machine can't unprotect our blobs
+// by accident
+#[cfg(windows)]
+const DPAPI_ENTROPY: &[u8] = b"btic-config-dpapi-v1";
+
+// Function to protect a data key with DPAPI in machine scope
+#[cfg(windows)]
+pub fn dpapi_protect(data: &[u8]) -> Result<Vec<u8>, String> {
+    use windows_sys::Win32::Security::Cryptography::{
+        CryptProtectData, CRYPTPROTECT_LOCAL_MACHINE, CRYPTPROTECT_UI_FORBIDDEN,
+    };
+
+    let input = blob_from(data);
+    let entropy = blob_from(DPAPI_ENTROPY);
+    let mut output = empty_blob();
+
+    // SAFETY: the input blobs point at live slices for the duration of the
+    // call, and the output blob is freed by take_blob
+    let ok = unsafe {
+        CryptProtectData(
+            &input,
+            std::ptr::null(),
+            &entropy,
+            std::ptr::null(),
+            std::ptr::null(),
+            CRYPTPROTECT_LOCAL_MACHINE | CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+    };
+    if ok == 0 {
+        return Err(format!("CryptProtectData failed: {}", last_error()));
+    }
+
+    Ok(take_blob(output))
+}
+
+// Function to unprotect a DPAPI blob created by dpapi_protect
+#[cfg(windows)]
+pub fn dpapi_unprotect(data: &[u8]) -> Result<Vec<u8>, String> {
+    use windows_sys::Win32::Security::Cryptography::{
+        CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN,
+    };
+
+    let input = blob_from(data);
+    let entropy = blob_from(DPAPI_ENTROPY);
+    let mut output = empty_blob();
+
+    // SAFETY: see dpapi_protect
+    let ok = unsafe {
+        CryptUnprotectData(
+            &input,
+            std::ptr::null_mut(),
+            &entropy,
+            std::ptr::null(),
+            std::ptr::null(),
+            CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+    };
+    if ok == 0 {
+        return Err(format!("CryptUnprotectData failed: {}", last_error()));
+    }
+
+    Ok(take_blob(output))
+}
+
+#[cfg(not(windows))]
+pub fn dpapi_protect(_data: &[u8]) -> Result<Vec<u8>, String> {
+    Err("DPAPI protection is only available on Windows".to_string())
+}
+
+#[cfg(not(windows))]
+pub fn dpapi_unprotect(_data: &[u8]) -> Result<Vec<u8>, String> {
+    Err("DPAPI protection is only available on Windows".to_string())
+}
+
+#[cfg(windows)]
+fn blob_from(data: &[u8]) -> windows_sys::Win32::Security::Cryptography::CRYPT_INTEGER_BLOB {
+    windows_sys::Win32::Security::Cryptography::CRYPT_INTEGER_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut u8,
+    }
+}
+
+#[cfg(windows)]
+fn empty_blob() -> windows_sys::Win32::Security::Cryptography::CRYPT_INTEGER_BLOB {
+    windows_sys::Win32::Security::Cryptography::CRYPT_INTEGER_BLOB {
+        cbData: 0,
+        pbData: std::ptr::null_mut(),
+    }
+}
+
+// Function to copy a DPAPI output blob and free the LocalAlloc'd buffer
+#[cfg(windows)]
+fn take_blob(blob: windows_sys::Win32::Security::Cryptography::CRYPT_INTEGER_BLOB) -> Vec<u8> {
+    // SAFETY: DPAPI filled pbData with cbData bytes allocated by LocalAlloc
+    unsafe {
+        let data = std::slice::from_raw_parts(blob.pbData, blob.cbData as usize).to_vec();
+        windows_sys::Win32::Foundation::LocalFree(blob.pbData as _);
+        data
+    }
+}
+
+#[cfg(windows)]
+fn last_error() -> std::io::Error {
+    std::io::Error::last_os_error()
+}
+
+// Name of the persisted TPM key that wraps the per-file data keys
+pub const TPM_KEY_NAME: &str = "BTicConfigConnectorBitrix";
+
+// Function to wrap a data key with the persisted TPM key, creating it first
+// if needed
+#[cfg(windows)]
+pub fn tpm_wrap(data: &[u8], key_name: &str) -> Result<Vec<u8>, String> {
+    use windows_sys::Win32::Security::Cryptography::{NCryptEncrypt, NCRYPT_PAD_OAEP_FLAG};
+
+    let provider = tpm::Provider::open()?;
+    let key = provider.open_or_create_key(key_name)?;
+    let padding = tpm::oaep_padding();
+
+    let mut needed = 0u32;
+    // SAFETY: the first call only queries the output size
+    tpm::check(
+        unsafe {
+            NCryptEncrypt(
+                key.0,
+                data.as_ptr(),
+                data.len() as u32,
+                &padding as *const _ as *const _,
+                std::ptr::null_mut(),
+                0,
+                &mut needed,
+                NCRYPT_PAD_OAEP_FLAG,
+            )
+        },
+        "NCryptEncrypt",
+    )?;
+
+    let mut output = vec![0u8; needed as usize];
+    // SAFETY: output has room for the size reported above
+    tpm::check(
+        unsafe {
+            NCryptEncrypt(
+                key.0,
+                data.as_ptr(),
+                data.len() as u32,
+                &padding as *const _ as *const _,
+                output.as_mut_ptr(),
+                output.len() as u32,
+                &mut needed,
+                NCRYPT_PAD_OAEP_FLAG,
+            )
+        },
+        "NCryptEncrypt",
+    )?;
+    output.truncate(needed as usize);
+    Ok(output)
+}
+
+// Function to unwrap a data key with the persisted TPM key
+#[cfg(windows)]
+pub fn tpm_unwrap(data: &[u8], key_name: &str) -> Result<Vec<u8>, String> {
+    use windows_sys::Win32::Security::Cryptography::{NCryptDecrypt, NCRYPT_PAD_OAEP_FLAG};
+
+    let provider = tpm::Provider::open()?;
+    let key = provider.open_key(key_name)?;
+    let padding = tpm::oaep_padding();
+
+    // The plaintext is never longer than the wrapped blob
+    let mut output = vec![0u8; data.len()];
+    let mut written = 0u32;
+    // SAFETY: output is at least as large as the ciphertext
+    tpm::check(
+        unsafe {
+            NCryptDecrypt(
+                key.0,
+                data.as_ptr(),
+                data.len() as u32,
+                &padding as *const _ as *const _,
+                output.as_mut_ptr(),
+                output.len() as u32,
+                &mut written,
+                NCRYPT_PAD_OAEP_FLAG,
+            )
+        },
+        "NCryptDecrypt",
+    )?;
+    output.truncate(written as usize);
+    Ok(output)
+}
+
+// Function to check whether the Platform Crypto Provider (TPM) can be opened
+#[cfg(windows)]
+pub fn tpm_is_available() -> bool {
+    tpm::Provider::open().is_ok()
+}
+
+#[cfg(not(windows))]
+pub fn tpm_wrap(_data: &[u8], _key_name: &str) -> Result<Vec<u8>, String> {
+    Err("TPM protection is only available on Windows".to_string())
+}
+
+#[cfg(not(windows))]
+pub fn tpm_unwrap(_data: &[u8], _key_name: &str) -> Result<Vec<u8>, String> {
+    Err("TPM protection is only available on Windows".to_string())
+}
+
+#[cfg(not(windows))]
+pub fn tpm_is_available() -> bool {
+    false
+}
+
+// Command to tell the UI whether TPM protection can be offered
+#[tauri::command]
+pub fn tpm_available() -> bool {
+    tpm_is_available()
+}
+
+// Thin RAII wrappers over the CNG (NCrypt) handles used for TPM wrapping
+#[cfg(windows)]
+mod tpm {
+    use windows_sys::Win32::Security::Cryptography::{
+        NCryptCreatePersistedKey, NCryptFinalizeKey, NCryptFreeObject, NCryptOpenKey,
+        NCryptOpenStorageProvider, BCRYPT_OAEP_PADDING_INFO, BCRYPT_SHA1_ALGORITHM,
+        MS_PLATFORM_CRYPTO_PROVIDER, NCRYPT_KEY_HANDLE, NCRYPT_MACHINE_KEY_FLAG,
+        NCRYPT_PROV_HANDLE, NCRYPT_RSA_ALGORITHM,
+    };
+
+    // NTE_BAD_KEYSET: the named key does not exist yet
+    const NTE_BAD_KEYSET: i32 = 0x8009_0016_u32 as i32;
+
+    pub struct Provider(pub NCRYPT_PROV_HANDLE);
+    pub struct Key(pub NCRYPT_KEY_HANDLE);
+
+    impl Provider {
+        pub fn open() -> Result<Provider, String> {
+            let mut handle: NCRYPT_PROV_HANDLE = 0;
+            // SAFETY: handle is a valid out pointer
+            check(
+                unsafe { NCryptOpenStorageProvider(&mut handle, MS_PLATFORM_CRYPTO_PROVIDER, 0) },
+                "Opening the TPM (Platform Crypto Provider)",
+            )?;
+            Ok(Provider(handle))
+        }
+
+        pub fn open_key(&self, key_name: &str) -> Result<Key, String> {
+            self.try_open_key(key_name).map_err(|status| {
+                format!(
+                    "TPM key '{}' could not be opened (0x{:08X})",
+                    key_name, status
+                )
+            })
+        }
+
+        pub fn open_or_create_key(&self, key_name: &str) -> Result<Key, String> {
+            match self.try_open_key(key_name) {
+                Ok(key) => Ok(key),
+                Err(NTE_BAD_KEYSET) => self.create_key(key_name),
+                Err(status) => Err(format!(
+                    "TPM key '{}' could not be opened (0x{:08X})",
+                    key_name, status
+                )),
+            }
+        }
+
+        fn try_open_key(&self, key_name: &str) -> Result<Key, i32> {
+            let name = wide(key_name);
+            let mut handle: NCRYPT_KEY_HANDLE = 0;
+            // SAFETY: name is NUL-terminated and outlives the call
+            let status = unsafe {
+                NCryptOpenKey(
+                    self.0,
+                    &mut handle,
+                    name.as_ptr(),
+                    0,
+                    NCRYPT_MACHINE_KEY_FLAG,
+                )
+            };
+            if status == 0 {
+                Ok(Key(handle))
+            } else {
+                Err(status)
+            }
+        }
+
+        fn create_key(&self, key_name: &str) -> Result<Key, String> {
+            let name = wide(key_name);
+            let mut handle: NCRYPT_KEY_HANDLE = 0;
+            // SAFETY: name is NUL-terminated and outlives the call
+            check(
+                unsafe {
+                    NCryptCreatePersistedKey(
+                        self.0,
+                        &mut handle,
+                        NCRYPT_RSA_ALGORITHM,
+                        name.as_ptr(),
+                        0,
+                        NCRYPT_MACHINE_KEY_FLAG,
+                    )
+                },
+                "Creating the TPM key",
+            )?;
+            let key = Key(handle);
+            // SAFETY: key holds a valid, not yet finalized key handle
+            check(
+                unsafe { NCryptFinalizeKey(key.0, 0) },
+                "Finalizing the TPM key",
+            )?;
+            Ok(key)
+        }
+    }
+
+    impl Drop for Provider {
+        fn drop(&mut self) {
+            // SAFETY: the handle came from NCryptOpenStorageProvider
+            unsafe { NCryptFreeObject(self.0) };
+        }
+    }
+
+    impl Drop for Key {
+        fn drop(&mut self) {
+            // SAFETY: the handle came from NCryptOpenKey or NCryptCreatePersistedKey
+            unsafe { NCryptFreeObject(self.0) };
+        }
+    }
+
+    pub fn oaep_padding() -> BCRYPT_OAEP_PADDING_INFO {
+        BCRYPT_OAEP_PADDING_INFO {
+            pszAlgId: BCRYPT_SHA1_ALGORITHM,
+            pbLabel: std::ptr::null_mut(),
+            cbLabel: 0,
+        }
+    }
+
+    pub fn check(status: i32, action: &str) -> Result<(), String> {
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(format!("{} failed (0x{:08X})", action, status))
+        }
+    }
+
+    fn wide(value: &str) -> Vec<u16> {
+        value.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+}
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::parse_json;
+
+// Why a connection test failed, so the frontend can point at the right field
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SageFailure {
+    // A required DB_ field is missing or malformed
+    Config,
+    // The host or port can't be reached
+    Network,
+    // SQL Server rejected the user name or password
+    Auth,
+    // Logged in, but the database doesn't exist or the user can't open it
+    DatabaseNotFound,
+    // Any other error reported by SQL Server
+    Server,
+    // The configurator was built without the sage-check feature
+    Unavailable,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SageConnectionResult {
+    success: bool,
+    failure: Option<SageFailure>,
+    error: Option<String>,
+}
+
+// Connection parameters read from the DB section of a config
+#[cfg_attr(not(feature = "sage-check"), allow(dead_code))]
+struct SageDatabase {
+    host: String,
+    // Named instance from "host\instance", resolved through SQL Browser
+    instance: Option<String>,
+    port: Option<u16>,
+    database: String,
+    username: String,
+    password: String,
+}
+
+// Command to check the Sage database settings of a config before it is
+// encrypted: logs in to SQL Server with them and runs a trivial query.
+// Nothing is encrypted or written.
+#[tauri::command]
+pub async fn test_sage_connection(config_json: String) -> Result<SageConnectionResult, String> {
+    let config = parse_json(&config_json)?;
+    let database = match read_database(&config) {
+        Ok(database) => database,
+        Err(e) => return Ok(failure(SageFailure::Config, e)),
+    };
+    println!(
+        "Testing Sage database {} on {}",
+        database.database, database.host
+    );
+    Ok(connect(&database).await)
+}
+
+// Function to read the DB_ fields a connection needs
+fn read_database(config: &Value) -> Result<SageDatabase, String> {
+    let field = |name: &str| {
+        config
+            .pointer(&format!("/DB/{}", name))
+            .and_then(|value| value.as_str())
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    let required = |name: &str| {
+        let value = field(name);
+        if value.is_empty() {
+            Err(format!("Config has no {}", name))
+        } else {
+            Ok(value)
+        }
+    };
+
+    let host_field = required("DB_Host")?;
+    let (host, instance) = match host_field.split_once('\\') {
+        Some((host, instance)) => (host.to_string(), Some(instance.to_string())),
+        None => (host_field, None),
+    };
+    let port = match field("DB_Port") {
+        port if port.is_empty() => None,
+        port => Some(
+            port.parse::<u16>()
+                .map_err(|_| format!("DB_Port '{}' is not a port number", port))?,
+        ),
+    };
+
+    Ok(SageDatabase {
+        host,
+        instance,
+        port,
+        database: required("DB_Database")?,
+        username: required("DB_Username")?,
+        // An empty password is unusual but SQL Server allows it
+        password: field("DB_Password"),
+    })
+}
+
+#[cfg(feature = "sage-check")]
+async fn connect(database: &SageDatabase) -> SageConnectionResult {
+    use tiberius::{AuthMethod, Client, Config};
+    use tokio::net::TcpStream;
+    use tokio_util::compat::TokioAsyncWriteCompatExt;
+
+    // SQL Browser lookups and TLS handshakes to an unreachable host can hang
+    const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+
+    let mut config = Config::new();
+    config.host(&database.host);
+    if let Some(port) = database.port {
+        config.port(port);
+    }
+    if let Some(instance) = &database.instance {
+        config.instance_name(instance);
+    }
+    config.database(&database.database);
+    config.authentication(AuthMethod::sql_server(
+        &database.username,
+        &database.password,
+    ));
+    config.application_name("sage-bitrix-configurador");
+    // Sage installs run SQL Server with its self-signed certificate
+    config.trust_cert();
+
+    let attempt = async {
+        // A named instance without a fixed port is looked up through SQL Browser
+        let tcp = if database.instance.is_some() && database.port.is_none() {
+            use tiberius::SqlBrowser;
+            TcpStream::connect_named(&config).await?
+        } else {
+            TcpStream::connect(config.get_addr()).await?
+        };
+        tcp.set_nodelay(true)?;
+        let mut client = Client::connect(config, tcp.compat_write()).await?;
+        client
+            .simple_query("SELECT 1")
+            .await?
+            .into_results()
+            .await?;
+        Ok::<(), tiberius::error::Error>(())
+    };
+
+    match tokio::time::timeout(CONNECT_TIMEOUT, attempt).await {
+        Ok(Ok(())) => SageConnectionResult {
+            success: true,
+            failure: None,
+            error: None,
+        },
+        Ok(Err(e)) => classify_error(e),
+        Err(_) => failure(
+            SageFailure::Network,
+            format!(
+                "{} didn't answer within {} seconds",
+                database.host,
+                CONNECT_TIMEOUT.as_secs()
+            ),
+        ),
+    }
+}
+
+#[cfg(not(feature = "sage-check"))]
+async fn connect(_database: &SageDatabase) -> SageConnectionResult {
+    failure(
+        SageFailure::Unavailable,
+        "This build can't test database connections (sage-check feature disabled)".to_string(),
+    )
+}
+
+// Function to sort a connection error by the setting that is likely wrong
+#[cfg(feature = "sage-check")]
+fn classify_error(e: tiberius::error::Error) -> SageConnectionResult {
+    use tiberius::error::Error;
+
+    // SQL Server error numbers for a failed login and an unopenable database
+    const LOGIN_FAILED: u32 = 18456;
+    const CANNOT_OPEN_DATABASE: u32 = 4060;
+
+    match e {
+        Error::Server(token) if token.code() == LOGIN_FAILED => failure(
+            SageFailure::Auth,
+            "Login failed, check DB_Username and DB_Password".to_string(),
+        ),
+        Error::Server(token) if token.code() == CANNOT_OPEN_DATABASE => failure(
+            SageFailure::DatabaseNotFound,
+            format!(
+                "Database can't be opened, check DB_Database: {}",
+                token.message()
+            ),
+        ),
+        Error::Server(token) => failure(
+            SageFailure::Server,
+            format!("SQL Server error {}: {}", token.code(), token.message()),
+        ),
+        Error::Io { message, .. } => failure(
+            SageFailure::Network,
+            format!(
+                "Can't reach the server, check DB_Host and DB_Port: {}",
+                message
+            ),
+        ),
+        Error::Tls(message) => failure(
+            SageFailure::Network,
+            format!("TLS handshake with the server failed: {}", message),
+        ),
+        other => failure(SageFailure::Server, other.to_string()),
+    }
+}
+
+fn failure(failure: SageFailure, error: String) -> SageConnectionResult {
+    SageConnectionResult {
+        success: false,
+        failure: Some(failure),
+        error: Some(error),
+    }
+}